@@ -0,0 +1,201 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{LazyLock, Mutex},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{hostlist::normalize, stats::STATS, Args};
+
+const MAX_TRACKED_HOSTS: usize = 4096;
+
+/// Hosts learned to need desync with --auto-hostlist, and failures of those not there yet.
+#[derive(Default)]
+struct Learned {
+    hosts: HashSet<String>,
+    failures: HashMap<String, usize>,
+}
+
+static LEARNED: LazyLock<Mutex<Learned>> = LazyLock::new(Mutex::default);
+
+/// Returns true if `host` has been learned to need desync.
+pub fn contains(host: &str) -> bool {
+    let learned = LEARNED.lock().unwrap();
+    !learned.hosts.is_empty() && learned.hosts.contains(&normalize(host))
+}
+
+/// Counts a connection to `host` relayed as is that failed, adding the host once it has failed
+/// --auto-hostlist-fails times in a row.
+pub fn failure(host: &str, reason: &str, conn: &str, args: &Args) {
+    let Some(path) = &args.auto_hostlist else {
+        return;
+    };
+    let host = normalize(host);
+    let mut learned = LEARNED.lock().unwrap();
+    if learned.hosts.contains(&host) {
+        return;
+    }
+    if learned.failures.len() >= MAX_TRACKED_HOSTS && !learned.failures.contains_key(&host) {
+        learned.failures.clear();
+    }
+    let failures = learned.failures.entry(host.clone()).or_default();
+    *failures += 1;
+    if *failures < args.auto_hostlist_fails {
+        debug!(
+            "{conn}: {reason} with the payload relayed as is, failure {failures}/{} for {host}",
+            args.auto_hostlist_fails
+        );
+        return;
+    }
+    learned.failures.remove(&host);
+    eprintln!(
+        "{conn}: {reason} with the payload relayed as is {} times, \
+         adding {host} to the auto hostlist",
+        args.auto_hostlist_fails
+    );
+    learned.hosts.insert(host);
+    STATS.auto_hostlist_additions.incr();
+    if let Err(err) = save(&learned.hosts, path) {
+        eprintln!("cannot save auto hostlist: {err:#}");
+    }
+}
+
+/// Forgets the failures of `host` once a connection relayed as is got a response.
+pub fn success(host: &str) {
+    let mut learned = LEARNED.lock().unwrap();
+    if !learned.failures.is_empty() {
+        learned.failures.remove(&normalize(host));
+    }
+}
+
+/// Reads the hosts learned before, one per line.
+pub fn load(args: &Args) -> Result<()> {
+    let Some(path) = &args.auto_hostlist else {
+        return Ok(());
+    };
+    let text = match fs::read_to_string(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        result => result.with_context(|| format!("cannot read {}", path.display()))?,
+    };
+    let mut learned = LEARNED.lock().unwrap();
+    learned.hosts.extend(
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(normalize),
+    );
+    println!(
+        "auto hostlist: {} hosts from {}",
+        learned.hosts.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Writes `hosts` to a temporary file renamed over `path`, so that readers never see it half
+/// written.
+fn save(hosts: &HashSet<String>, path: &Path) -> Result<()> {
+    let mut hosts: Vec<_> = hosts.iter().map(String::as_str).collect();
+    hosts.sort_unstable();
+    let mut text = hosts.join("\n");
+    text.push('\n');
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, text).with_context(|| format!("cannot write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("cannot rename to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt;
+
+    use super::*;
+
+    /// Returns an empty directory of its own for `test`.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("adpi-autohostlist-{test}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn args(path: &Path) -> Args {
+        let path = path.to_str().unwrap();
+        crate::tests::args(&["--auto-hostlist", path, "--auto-hostlist-fails", "3"])
+    }
+
+    #[test]
+    fn learns_hosts_failing_in_a_row() {
+        let dir = temp_dir("threshold");
+        let path = dir.join("learned.txt");
+        let args = args(&path);
+        let host = "threshold.example.com";
+
+        failure(host, "reset", "test", &args);
+        failure("Threshold.Example.com", "reset", "test", &args);
+        assert!(!contains(host));
+        // A response starts the count over.
+        success(host);
+        failure(host, "reset", "test", &args);
+        failure(host, "reset", "test", &args);
+        assert!(!contains(host));
+        assert!(!path.exists());
+
+        failure(host, "closed", "test", &args);
+        assert!(contains(host));
+        assert!(contains("THRESHOLD.example.com"));
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.lines().any(|line| line == host), "{saved}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_the_saved_list_whole() {
+        let dir = temp_dir("replace");
+        let path = dir.join("learned.txt");
+        fs::write(&path, "old.example.com\n").unwrap();
+        let old = fs::File::open(&path).unwrap();
+        let inode = old.metadata().unwrap().ino();
+
+        let hosts = HashSet::from(["b.example.com".to_owned(), "a.example.com".to_owned()]);
+        save(&hosts, &path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "a.example.com\nb.example.com\n"
+        );
+        // Renamed over the old file, which readers that opened it still see whole.
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(io::read_to_string(old).unwrap(), "old.example.com\n");
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["learned.txt"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_saved_hosts() {
+        let dir = temp_dir("reload");
+        let path = dir.join("learned.txt");
+        let hosts = HashSet::from(["Reload.Example.com".to_owned()]);
+        save(&hosts, &path).unwrap();
+        fs::write(
+            &path,
+            fs::read_to_string(&path).unwrap() + "\n  other.reload.example \n",
+        )
+        .unwrap();
+
+        load(&args(&path)).unwrap();
+        assert!(contains("reload.example.com"));
+        assert!(contains("other.reload.example"));
+        assert!(!contains("missing.reload.example"));
+        // A list not written yet is no error.
+        load(&args(&dir.join("missing.txt"))).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream as StdTcpStream},
+    sync::{LazyLock, Mutex},
+};
+
+use socket2::Socket;
+use tokio::{net::TcpSocket, task::JoinSet, time};
+
+use crate::{stats::STATS, upstream_socket, Args};
+
+const PROBE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+const MAX_CACHED_PREFIXES: usize = 4096;
+
+/// Hop distance, None if probing failed, and when it was measured.
+type Measurement = (Option<u32>, time::Instant);
+
+static CACHE: LazyLock<Mutex<HashMap<IpAddr, Measurement>>> = LazyLock::new(Default::default);
+
+/// Returns the TTL that should let low-TTL segments to `addr` expire just before the destination,
+/// probing its hop distance unless it is cached.
+pub async fn low_ttl(addr: SocketAddr, args: &Args, conn: &str) -> Option<u32> {
+    let prefix = prefix(addr.ip());
+    let max_age = time::Duration::from_millis(args.autottl_cache_ms);
+    let cached = CACHE
+        .lock()
+        .unwrap()
+        .get(&prefix)
+        .filter(|(_, at)| at.elapsed() < max_age)
+        .map(|(hops, _)| *hops);
+
+    let hops = match cached {
+        Some(hops) => hops,
+        None => {
+            STATS.autottl_probes.incr();
+            let hops = probe(addr, args.autottl_max + args.autottl_delta, args).await;
+            if hops.is_none() {
+                STATS.autottl_failures.incr();
+            }
+            let mut cache = CACHE.lock().unwrap();
+            if cache.len() >= MAX_CACHED_PREFIXES {
+                cache.retain(|_, (_, at)| at.elapsed() < max_age);
+            }
+            cache.insert(prefix, (hops, time::Instant::now()));
+            hops
+        }
+    };
+
+    let Some(hops) = hops else {
+        debug!("{conn}: hop distance unknown, using fixed TTL");
+        return None;
+    };
+    let ttl = hops
+        .saturating_sub(args.autottl_delta)
+        .clamp(args.autottl_min, args.autottl_max);
+    debug!("{conn}: {hops} hops away, low TTL {ttl}");
+    Some(ttl)
+}
+
+/// Groups destinations that are likely to share a path.
+fn prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => Ipv4Addr::from(ip.to_bits() & !0xff).into(),
+        IpAddr::V6(ip) => Ipv6Addr::from(ip.to_bits() & !(u128::MAX >> 64)).into(),
+    }
+}
+
+/// Connects to `addr` with every TTL up to `max_ttl` at once. Connects with a TTL too low to
+/// reach the destination fail as soon as a router reports that it expired.
+async fn probe(addr: SocketAddr, max_ttl: u32, args: &Args) -> Option<u32> {
+    let mut probes = JoinSet::new();
+    for ttl in 1..=max_ttl {
+        let socket = upstream_socket(addr, args).ok()?;
+        let set = if addr.is_ipv6() {
+            socket.set_unicast_hops_v6(ttl)
+        } else {
+            socket.set_ttl(ttl)
+        };
+        set.ok()?;
+        probes.spawn(async move { (ttl, connect(socket, addr).await) });
+    }
+
+    // Probes complete in any order, so the lowest TTL that connected is only known to be the hop
+    // distance once all lower ones have failed.
+    let mut connected = vec![None; max_ttl as usize + 1];
+    let deadline = time::Instant::now() + PROBE_TIMEOUT;
+    while let Ok(Some(Ok((ttl, ok)))) = time::timeout_at(deadline, probes.join_next()).await {
+        connected[ttl as usize] = Some(ok);
+        let first = connected.iter().position(|ok| *ok == Some(true));
+        if let Some(first) = first {
+            if connected[1..first].iter().all(|ok| *ok == Some(false)) {
+                return Some(first as u32);
+            }
+        }
+    }
+    connected
+        .iter()
+        .position(|ok| *ok == Some(true))
+        .map(|ttl| ttl as u32)
+}
+
+async fn connect(socket: Socket, addr: SocketAddr) -> bool {
+    let std_stream: StdTcpStream = socket.into();
+    match TcpSocket::from_std_stream(std_stream).connect(addr).await {
+        Ok(stream) => {
+            crate::reset_on_close(&stream);
+            true
+        }
+        Err(_) => false,
+    }
+}
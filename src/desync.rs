@@ -0,0 +1,543 @@
+use std::{io, os::fd::AsRawFd};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{
+    io::{AsyncWriteExt, Interest},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    time,
+};
+
+#[cfg(feature = "raw")]
+use crate::raw;
+use crate::{
+    fake::{self, SplicedPages},
+    sockopt::{self, TcpInfo},
+    stats::STATS,
+    strategy::{Fake, FakeRecord, Techniques},
+    Args, FlushMode, Segmentation, SplitDelay,
+};
+
+const MD5SIG_KEY: &[u8] = b"adpi";
+/// Total --split-delay added to a ClientHello.
+const MAX_SPLIT_DELAY: time::Duration = time::Duration::from_secs(1);
+const FALLBACK_FLUSH_DELAY: time::Duration = time::Duration::from_millis(50);
+const MIN_FAKE_HOLD: time::Duration = time::Duration::from_millis(1);
+const MAX_FAKE_HOLD: time::Duration = time::Duration::from_millis(100);
+/// Bytes of the next segment replaced by each --fake-interleave decoy.
+const MAX_INTERLEAVED_FAKE_LEN: usize = 16;
+
+/// Per-connection state for sending the first client payload.
+pub struct Desync<'a> {
+    pub writer: &'a mut OwnedWriteHalf,
+    pub args: &'a Args,
+    pub conn: &'a str,
+    /// How to send each segment.
+    pub segments: &'a [Techniques],
+    /// Wait after each split segment has left.
+    pub delay: Option<SplitDelay>,
+    /// Sent before the payload.
+    pub fake_record: Option<FakeRecord>,
+    /// Sent in place of the first segment with --fake.
+    pub decoy: &'a [u8],
+    /// Measured by --autottl, replaces --fake-ttl and --disorder-ttl but not TTLs set by steps.
+    pub low_ttl: Option<u32>,
+    /// Relay the rest as is once this passes.
+    pub deadline: time::Instant,
+}
+
+impl Desync<'_> {
+    fn stream(&self) -> &TcpStream {
+        self.writer.as_ref()
+    }
+
+    fn fd(&self) -> i32 {
+        self.stream().as_raw_fd()
+    }
+
+    /// Sends `data` split at `split_positions`, which must be sorted and within `data`, waiting
+    /// for every segment but the last to leave the host.
+    pub async fn send_split(&mut self, data: &[u8], split_positions: &[usize]) -> Result<()> {
+        let args = self.args;
+        let mut first_segment = 0;
+        let mut wire = Vec::new();
+        let shifted;
+        let (data, split_positions) = match self.fake_record {
+            Some(record) => {
+                let sent = self.send_fake_record(record, data).await?;
+                wire.push("fake record".to_owned());
+                first_segment = split_positions.partition_point(|pos| *pos <= sent);
+                shifted = split_positions[first_segment..]
+                    .iter()
+                    .map(|pos| pos - sent)
+                    .collect::<Vec<_>>();
+                (&data[sent..], &shifted[..])
+            }
+            None => (data, split_positions),
+        };
+        let lowered_mss = args.mss.and_then(|mss| Some((mss, self.lower_mss(mss)?)));
+        let resegmented;
+        let split_positions = match lowered_mss {
+            Some((mss, (_, false))) => {
+                resegmented = cut_segments(split_positions, data.len(), mss as usize);
+                &resegmented[..]
+            }
+            _ => split_positions,
+        };
+        let segments = split_positions.len() + 1;
+        let interleave = args.fake_interleave && segments > 1;
+        let techniques: Vec<_> = (0..segments)
+            .map(|i| {
+                let mut techniques = self
+                    .segments
+                    .get(first_segment + i)
+                    .copied()
+                    .unwrap_or_default();
+                if segments == 1 {
+                    // Nothing would arrive before a lone segment.
+                    techniques.disorder = None;
+                    techniques.oob = None;
+                }
+                techniques
+            })
+            .collect();
+        let original_ttl = if interleave
+            || techniques
+                .iter()
+                .any(|t| t.fake.is_some() || t.disorder.is_some())
+        {
+            Some(sockopt::ttl(self.stream())?)
+        } else {
+            None
+        };
+
+        let mut start_byte = 0usize;
+        let mut delayed = time::Duration::ZERO;
+        for (i, split_at) in split_positions
+            .iter()
+            .copied()
+            .chain([data.len()])
+            .enumerate()
+        {
+            let segment = &data[start_byte..split_at];
+            let techniques = techniques[i];
+            let injected = techniques.fake.filter(Fake::injected);
+            let md5sig = techniques.fake.is_some_and(|fake| fake.md5sig)
+                && injected.is_none()
+                && self.sign_fake();
+            let low_ttl = match (techniques.fake, techniques.disorder) {
+                (Some(_), _) if md5sig || injected.is_some() => None,
+                (Some(fake), _) => Some(fake.ttl.or(self.low_ttl).unwrap_or(args.fake_ttl)),
+                (None, Some(disorder)) => {
+                    Some(disorder.ttl.or(self.low_ttl).unwrap_or(args.disorder_ttl))
+                }
+                (None, None) => None,
+            };
+            let written = async {
+                let mut segment = segment;
+                if interleave
+                    && i > 0
+                    && techniques.fake.is_none()
+                    && segment.len() > 1
+                    && time::Instant::now() < self.deadline
+                {
+                    let len = MAX_INTERLEAVED_FAKE_LEN.min(segment.len() - 1);
+                    let decoy = self.decoy.get(start_byte..).unwrap_or_default();
+                    sockopt::set_ttl(self.stream(), self.low_ttl.unwrap_or(args.fake_ttl))?;
+                    let sent = self.send_fake(&segment[..len], decoy, 1).await;
+                    if let Some(ttl) = original_ttl {
+                        sockopt::set_ttl(self.stream(), ttl)?;
+                    }
+                    wire.push(format!("fake {len}"));
+                    if !sent? {
+                        return Ok(false);
+                    }
+                    segment = &segment[len..];
+                }
+                if let Some(ttl) = low_ttl {
+                    sockopt::set_ttl(self.stream(), ttl)?;
+                }
+                if args.quickack && segments > 1 {
+                    sockopt::set_quickack(self.fd(), true)?;
+                }
+                if let Some(fake) = injected {
+                    wire.push(format!("injected fake x{}", args.fake_repeats));
+                    self.inject_fake(fake, self.decoy, args.fake_repeats)
+                        .await?;
+                } else if techniques.fake.is_some() {
+                    wire.push(format!("fake {} x{}", segment.len(), args.fake_repeats));
+                    return self.send_fake(segment, self.decoy, args.fake_repeats).await;
+                }
+                // The TTL must stay low until the segment has left, and urgent data is sent
+                // by itself anyway.
+                let corked = args.segmentation == Segmentation::Cork
+                    && low_ttl.is_none()
+                    && techniques.oob.is_none();
+                let kind = if low_ttl.is_some() {
+                    "low-TTL real"
+                } else {
+                    "real"
+                };
+                if let Some(byte) = techniques.oob {
+                    wire.push(format!("{kind} {}+oob", segment.len()));
+                    self.send_oob(segment, byte).await?;
+                } else if corked {
+                    wire.push(format!("{kind} {} corked", segment.len()));
+                    self.write_corked(segment).await?;
+                } else {
+                    wire.push(format!("{kind} {}", segment.len()));
+                    self.writer.write_all(segment).await?;
+                }
+                if split_at != data.len() && !corked {
+                    let fd = self.fd();
+                    // Low-TTL segments are only acknowledged once retransmitted.
+                    let mode = match low_ttl {
+                        Some(_) => FlushMode::Notsent,
+                        None => args.flush_mode,
+                    };
+                    let flush = really_flush(self.writer, fd, args, mode);
+                    match time::timeout_at(self.deadline, flush).await {
+                        Ok(flushed) => flushed?,
+                        Err(_) => return anyhow::Ok(false),
+                    }
+                }
+                anyhow::Ok(true)
+            };
+            let written = written.await;
+            if let (Some(_), Some(ttl)) = (low_ttl, original_ttl) {
+                sockopt::set_ttl(self.stream(), ttl)?;
+            }
+            if md5sig {
+                sockopt::set_md5sig(self.stream(), &[]).context("cannot clear TCP_MD5SIG")?;
+            }
+
+            match written {
+                Ok(true) if split_at != data.len() => {
+                    if let Some(delay) = self.delay {
+                        let delay = delay.sample().min(MAX_SPLIT_DELAY - delayed);
+                        delayed += delay;
+                        time::sleep(delay).await;
+                    }
+                }
+                Ok(true) => {}
+                Ok(false) if split_at == data.len() => {
+                    STATS.desync_budget_exceeded.incr();
+                }
+                Ok(false) => {
+                    STATS.desync_budget_exceeded.incr();
+                    debug!(
+                        "{}: desync took longer than {} ms, relaying the rest as is",
+                        self.conn, args.desync_budget_ms
+                    );
+                    self.writer.write_all(&data[split_at..]).await?;
+                    break;
+                }
+                Err(err) => return Err(err.context(format!("segment {}/{segments}", i + 1))),
+            }
+            start_byte = split_at;
+        }
+
+        if args.quickack && segments > 1 {
+            sockopt::set_quickack(self.fd(), false)?;
+        }
+        if interleave {
+            debug!("{}: on the wire: {}", self.conn, wire.join(", "));
+        }
+        if let Some((_, (mss, _))) = lowered_mss {
+            // Segments are cut when they are sent, so wait until the last one has been.
+            let fd = self.fd();
+            let flush = really_flush(self.writer, fd, args, FlushMode::Notsent);
+            if !matches!(time::timeout_at(self.deadline, flush).await, Ok(Ok(()))) {
+                debug!("{}: restoring MSS before the hello has left", self.conn);
+            }
+            if let Err(err) = sockopt::set_mss(fd, mss) {
+                debug!("{}: cannot restore MSS {mss}: {err}", self.conn);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the MSS to `mss`, returning the previous one and whether segments will actually be
+    /// cut to it, or None if the kernel refuses.
+    fn lower_mss(&self, mss: u32) -> Option<(u32, bool)> {
+        let fd = self.fd();
+        let set = sockopt::mss(fd).and_then(|original| {
+            sockopt::set_mss(fd, mss)?;
+            Ok((original, sockopt::mss(fd)? <= mss))
+        });
+        match set {
+            Ok((original, false)) => {
+                debug!(
+                    "{}: kernel keeps MSS {original} once connected, \
+                     cutting segments to {mss} bytes instead",
+                    self.conn
+                );
+                Some((original, false))
+            }
+            Ok(lowered) => Some(lowered),
+            Err(err) => {
+                debug!("{}: cannot set MSS {mss}: {err}", self.conn);
+                None
+            }
+        }
+    }
+
+    /// Writes `segment` to the corked socket and uncorks it, which sends it at once as one
+    /// packet if the congestion window allows, as it does early in a connection.
+    async fn write_corked(&mut self, segment: &[u8]) -> Result<()> {
+        let fd = self.fd();
+        sockopt::set_cork(fd, true).context("cannot set TCP_CORK")?;
+        let written = self.writer.write_all(segment).await;
+        let uncorked = sockopt::set_cork(fd, false).context("cannot clear TCP_CORK");
+        written?;
+        uncorked
+    }
+
+    /// Sends `segment` followed by `byte` as urgent data, which the destination leaves out of the
+    /// stream unless it has SO_OOBINLINE set.
+    async fn send_oob(&mut self, segment: &[u8], byte: u8) -> io::Result<()> {
+        let mut data = segment.to_vec();
+        data.push(byte);
+        let fd = self.fd();
+        let mut sent = 0;
+        while sent < data.len() {
+            self.stream().writable().await?;
+            let rest = &data[sent..];
+            let n = self.stream().try_io(Interest::WRITABLE, || {
+                match unsafe { libc::send(fd, rest.as_ptr().cast(), rest.len(), libc::MSG_OOB) } {
+                    -1 => Err(io::Error::last_os_error()),
+                    n => Ok(n as usize),
+                }
+            });
+            match n {
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends `record` before `data`. A fooled record is a decoy for the first bytes of `data`,
+    /// which the retransmission carries, so returns how many of them have been sent.
+    async fn send_fake_record(&mut self, record: FakeRecord, data: &[u8]) -> Result<usize> {
+        let bytes = fake::record(record.ccs);
+        let Some(fooling) = record.fooling else {
+            self.writer.write_all(&bytes).await?;
+            let fd = self.fd();
+            let flush = really_flush(self.writer, fd, self.args, self.args.flush_mode);
+            let _ = time::timeout_at(self.deadline, flush).await;
+            return Ok(0);
+        };
+        if fooling.injected() {
+            self.inject_fake(fooling, &bytes, 1).await?;
+            return Ok(0);
+        }
+        if data.len() <= bytes.len() {
+            return Ok(0);
+        }
+        let Fake { ttl, md5sig, .. } = fooling;
+
+        let md5sig = md5sig && self.sign_fake();
+        let original_ttl = if md5sig {
+            None
+        } else {
+            let original = sockopt::ttl(self.stream())?;
+            let low = ttl.or(self.low_ttl).unwrap_or(self.args.fake_ttl);
+            sockopt::set_ttl(self.stream(), low)?;
+            Some(original)
+        };
+        let sent = self.send_fake(&data[..bytes.len()], &bytes, 1).await;
+        if let Some(ttl) = original_ttl {
+            sockopt::set_ttl(self.stream(), ttl)?;
+        }
+        if md5sig {
+            sockopt::set_md5sig(self.stream(), &[]).context("cannot clear TCP_MD5SIG")?;
+        }
+        sent.context("fake record")?;
+        Ok(bytes.len())
+    }
+
+    /// Injects `decoy` `repeats` times with the raw sockets, or skips it if they are not open.
+    async fn inject_fake(&self, fake: Fake, decoy: &[u8], repeats: usize) -> io::Result<()> {
+        #[cfg(feature = "raw")]
+        let injected = {
+            let ttl = match fake.ttl {
+                Some(ttl) => ttl,
+                None => sockopt::ttl(self.stream())?,
+            };
+            let mut injected = Ok(());
+            for _ in 0..repeats {
+                injected = raw::send_fake(self.stream(), decoy, fake, ttl).await;
+                if injected.is_err() {
+                    break;
+                }
+                STATS.injected_fakes.incr();
+            }
+            injected
+        };
+        #[cfg(not(feature = "raw"))]
+        let injected = {
+            let _ = (fake, decoy, repeats);
+            Err::<(), _>(anyhow!("adpi was built without the raw feature"))
+        };
+        if let Err(err) = injected {
+            STATS.injection_failures.incr();
+            debug!("{}: cannot inject fake, skipping it: {err:#}", self.conn);
+        }
+        Ok(())
+    }
+
+    /// Starts signing segments for the decoy, returning false if the socket doesn't allow it.
+    fn sign_fake(&self) -> bool {
+        match sockopt::set_md5sig(self.stream(), MD5SIG_KEY) {
+            Ok(()) => true,
+            Err(err) => {
+                STATS.md5sig_fallbacks.incr();
+                debug!(
+                    "{}: cannot set TCP_MD5SIG, lowering TTL instead: {err}",
+                    self.conn
+                );
+                false
+            }
+        }
+    }
+
+    /// Sends `decoy`, cut or padded to the length of `segment`, with zero-copy, waits for it to
+    /// leave, then swaps in the real bytes so that the retransmission carries them. With more
+    /// than one repeat, `segment` is cut into that many packets each carrying the start of the
+    /// decoy. Returns false if the desync budget ran out while waiting.
+    async fn send_fake(&mut self, segment: &[u8], decoy: &[u8], repeats: usize) -> Result<bool> {
+        let part_len = segment
+            .len()
+            .div_ceil(repeats.clamp(1, segment.len().max(1)));
+        let mut pages = Vec::new();
+        for part in segment.chunks(part_len.max(1)) {
+            let mut decoy = decoy.to_vec();
+            decoy.resize(part.len(), 0);
+            pages.push(SplicedPages::new(&decoy)?);
+        }
+
+        let sent = async {
+            for page in &pages {
+                page.send(self.stream()).await?;
+                let fd = self.fd();
+                let flush = really_flush(self.writer, fd, self.args, FlushMode::Notsent);
+                match time::timeout_at(self.deadline, flush).await {
+                    Ok(flushed) => flushed?,
+                    Err(_) => return Ok(false),
+                }
+            }
+            anyhow::Ok(true)
+        }
+        .await;
+        for _ in 1..pages.len() {
+            STATS.fake_repeats.incr();
+        }
+
+        // Leaving the send queue doesn't mean the device has read the pages yet. Until the next
+        // segment is sent, nothing is retransmitted sooner than two round trips, so swap in the
+        // real bytes after half of one.
+        let rtt = sockopt::get_tcp_info(self.fd())
+            .ok()
+            .and_then(|info| info.tcpi_rtt())
+            .unwrap_or(0);
+        let hold = time::Duration::from_micros(rtt as u64 / 2);
+        time::sleep(hold.clamp(MIN_FAKE_HOLD, MAX_FAKE_HOLD)).await;
+        for (page, part) in pages.iter_mut().zip(segment.chunks(part_len.max(1))) {
+            page.overwrite(part);
+        }
+        sent
+    }
+}
+
+/// Returns `split_positions` with more positions cutting the segments between them into at most
+/// `max_len` bytes.
+fn cut_segments(split_positions: &[usize], len: usize, max_len: usize) -> Vec<usize> {
+    let mut cut = Vec::with_capacity(split_positions.len() + len / max_len);
+    let mut start = 0;
+    for end in split_positions.iter().copied().chain([len]) {
+        cut.extend((start + max_len..end).step_by(max_len));
+        cut.push(end);
+        start = end;
+    }
+    cut.pop();
+    cut
+}
+
+/// Waits for what has been written to leave the send queue, and with `FlushMode::Acked` to be
+/// acknowledged as well.
+async fn really_flush(
+    writer: &mut OwnedWriteHalf,
+    fd: i32,
+    args: &Args,
+    mode: FlushMode,
+) -> Result<()> {
+    writer.flush().await?;
+
+    let max_wait = time::Duration::from_millis(args.flush_timeout_ms);
+    let deadline = time::Instant::now() + max_wait;
+    let mut acked = mode == FlushMode::Acked;
+    if acked {
+        let rtt = sockopt::get_tcp_info(fd)?.tcpi_rtt().unwrap_or(0);
+        if rtt as u64 > args.flush_acked_max_rtt_ms * 1000 {
+            STATS.flush_downgrades.incr();
+            acked = false;
+        }
+    }
+    let mut timeout = 1;
+    loop {
+        let Some(flushed) = flushed(&sockopt::get_tcp_info(fd)?, acked) else {
+            STATS.flush_fallbacks.incr();
+            time::sleep(FALLBACK_FLUSH_DELAY).await;
+            break;
+        };
+        if flushed {
+            break;
+        }
+
+        if time::Instant::now() >= deadline {
+            STATS.flush_timeouts.incr();
+            return Err(anyhow!("flush timed out after {max_wait:?}"));
+        }
+        time::sleep(time::Duration::from_millis(timeout)).await;
+        if timeout < 8 {
+            timeout *= 2;
+        }
+    }
+
+    STATS.flushes.incr();
+    Ok(())
+}
+
+/// Returns whether nothing is left in the send queue, nor in flight with `acked`, or None if the
+/// kernel is too old to tell.
+fn flushed(info: &TcpInfo, acked: bool) -> Option<bool> {
+    let notsent_bytes = info.tcpi_notsent_bytes()?;
+    // Segments in flight are counted whole, not in bytes.
+    let unacked = match acked {
+        true => info.tcpi_unacked().unwrap_or(0),
+        false => 0,
+    };
+    Some(notsent_bytes == 0 && unacked == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::offset_of;
+
+    use linux_raw_sys::net::tcp_info;
+
+    use super::*;
+
+    #[test]
+    fn falls_back_without_notsent_bytes() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let info = sockopt::get_tcp_info(stream.as_raw_fd()).unwrap();
+        assert_eq!(flushed(&info, true), Some(true));
+
+        let old_kernel = info.truncated(offset_of!(tcp_info, tcpi_notsent_bytes));
+        assert_eq!(flushed(&old_kernel, false), None);
+        assert_eq!(flushed(&old_kernel, true), None);
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::Args;
+
+/// An address prefix such as `10.0.0.0/8` or `2001:db8::/32`. A bare address is a prefix of its
+/// full length.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Prefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Prefix {
+    /// The prefix of `addr` `len` bits long, with the bits past it cleared.
+    fn new(addr: IpAddr, len: u8) -> Self {
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+                Ipv4Addr::from(addr.to_bits() & mask).into()
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+                Ipv6Addr::from(addr.to_bits() & mask).into()
+            }
+        };
+        Prefix { addr, len }
+    }
+}
+
+impl FromStr for Prefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("bad prefix {s:?}"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len.map(str::parse) {
+            None => max_len,
+            Some(Ok(len)) if len <= max_len => len,
+            Some(_) => return Err(format!("bad prefix length in {s:?}")),
+        };
+        Ok(Prefix::new(addr, len))
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Address prefixes in binary tries, one per family, so that lookups take at most one step per
+/// address bit however many prefixes there are.
+#[derive(Default)]
+pub struct PrefixList {
+    v4: Trie,
+    v6: Trie,
+    len: usize,
+}
+
+#[derive(Default)]
+struct Trie {
+    /// The root first, with children at indexes into this, 0 standing for none.
+    nodes: Vec<Node>,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Node {
+    children: [u32; 2],
+    /// Whether the prefix down to here is listed.
+    listed: bool,
+}
+
+impl Trie {
+    /// Adds the first `len` bits of `key`, counting from the top.
+    fn insert(&mut self, key: u128, len: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::default());
+        }
+        let mut node = 0;
+        for i in 0..len {
+            let bit = (key >> (127 - i)) as usize & 1;
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        self.nodes[node].listed = true;
+    }
+
+    /// Returns the length of the longest listed prefix of `key`.
+    fn lookup(&self, key: u128, max_len: u8) -> Option<u8> {
+        let mut node = self.nodes.first()?;
+        let mut longest = node.listed.then_some(0);
+        for i in 0..max_len {
+            let bit = (key >> (127 - i)) as usize & 1;
+            node = match node.children[bit] {
+                0 => break,
+                child => &self.nodes[child as usize],
+            };
+            if node.listed {
+                longest = Some(i + 1);
+            }
+        }
+        longest
+    }
+}
+
+impl PrefixList {
+    /// Reads prefixes from `spec`, either a comma-separated list of them or a file with one per
+    /// line, skipping blank lines and # comments.
+    fn load(spec: &str) -> Result<Self> {
+        let mut list = PrefixList::default();
+        let inline = spec
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<_>, _>>();
+        let err = match inline {
+            Ok(prefixes) => {
+                prefixes.into_iter().for_each(|prefix| list.insert(prefix));
+                return Ok(list);
+            }
+            Err(err) => err,
+        };
+        let text = match fs::read_to_string(spec) {
+            Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => {
+                return Err(anyhow!("{err}, and there is no such file"));
+            }
+            result => result.with_context(|| format!("cannot read {spec}"))?,
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let prefix = line
+                .parse()
+                .map_err(|err| anyhow!("{spec}:{}: {err}", i + 1))?;
+            list.insert(prefix);
+        }
+        Ok(list)
+    }
+
+    fn insert(&mut self, prefix: Prefix) {
+        match prefix.addr {
+            IpAddr::V4(addr) => self.v4.insert((addr.to_bits() as u128) << 96, prefix.len),
+            IpAddr::V6(addr) => self.v6.insert(addr.to_bits(), prefix.len),
+        }
+        self.len += 1;
+    }
+
+    /// Returns the longest listed prefix containing `addr`.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Prefix> {
+        let len = match addr {
+            IpAddr::V4(v4) => self.v4.lookup((v4.to_bits() as u128) << 96, 32),
+            IpAddr::V6(v6) => self.v6.lookup(v6.to_bits(), 128),
+        }?;
+        Some(Prefix::new(addr, len))
+    }
+}
+
+/// Returns the file `spec` names, unless it lists prefixes itself.
+pub fn path(spec: &str) -> Option<&Path> {
+    let inline = spec
+        .split(',')
+        .all(|prefix| prefix.parse::<Prefix>().is_ok());
+    (!inline).then_some(Path::new(spec))
+}
+
+static LISTS: RwLock<Lists> = RwLock::new(Lists {
+    include: None,
+    exclude: None,
+    allow_from: None,
+});
+
+/// The --dst-include, --dst-exclude and --allow-from lists.
+#[derive(Clone)]
+struct Lists {
+    include: Option<Arc<PrefixList>>,
+    exclude: Option<Arc<PrefixList>>,
+    allow_from: Option<Arc<PrefixList>>,
+}
+
+/// Reads the --dst-include, --dst-exclude and --allow-from prefixes, keeping the current lists if
+/// any fails.
+pub fn reload(args: &Args) -> Result<()> {
+    let load = |spec: &Option<String>, name| -> Result<_> {
+        let Some(spec) = spec else {
+            return Ok(None);
+        };
+        let list = PrefixList::load(spec)?;
+        if path(spec).is_some() {
+            println!("{name}: {} prefixes from {spec}", list.len);
+        }
+        Ok(Some(Arc::new(list)))
+    };
+    let lists = Lists {
+        include: load(&args.dst_include, "dst include")?,
+        exclude: load(&args.dst_exclude, "dst exclude")?,
+        allow_from: load(&args.allow_from, "allow from")?,
+    };
+    *LISTS.write().unwrap() = lists;
+    Ok(())
+}
+
+/// Returns true if connections to `addr` may be desynced: unless excluded, if included or
+/// without --dst-include.
+pub fn targets(addr: IpAddr, conn: &str) -> bool {
+    let Lists {
+        include, exclude, ..
+    } = LISTS.read().unwrap().clone();
+    if let Some(prefix) = exclude.and_then(|exclude| exclude.lookup(addr)) {
+        debug!("{conn}: destination in {prefix} in --dst-exclude, relaying as is");
+        return false;
+    }
+    let Some(include) = include else {
+        return true;
+    };
+    match include.lookup(addr) {
+        Some(prefix) => {
+            debug!("{conn}: destination in {prefix} in --dst-include");
+            true
+        }
+        None => {
+            debug!("{conn}: destination not in --dst-include, relaying as is");
+            false
+        }
+    }
+}
+
+/// Returns true if clients at `addr` are served: those in --allow-from, or all without it.
+pub fn allows(addr: IpAddr) -> bool {
+    let allow_from = LISTS.read().unwrap().allow_from.clone();
+    allow_from.is_none_or(|allow_from| allow_from.lookup(addr).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(list: &PrefixList, addr: &str) -> Option<String> {
+        list.lookup(addr.parse().unwrap())
+            .map(|prefix| prefix.to_string())
+    }
+
+    #[test]
+    fn parses_prefixes() {
+        for (prefix, parsed) in [
+            ("10.1.2.3/8", "10.0.0.0/8"),
+            ("192.168.1.1", "192.168.1.1/32"),
+            ("0.0.0.0/0", "0.0.0.0/0"),
+            ("172.16.255.255/12", "172.16.0.0/12"),
+            ("2001:db8::1/32", "2001:db8::/32"),
+            ("fe80::1", "fe80::1/128"),
+            ("::/0", "::/0"),
+        ] {
+            assert_eq!(prefix.parse::<Prefix>().unwrap().to_string(), parsed);
+        }
+        for prefix in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "/8",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "",
+        ] {
+            assert!(prefix.parse::<Prefix>().is_err(), "{prefix:?}");
+        }
+    }
+
+    #[test]
+    fn finds_longest_matching_prefix() {
+        let list = PrefixList::load("10.0.0.0/8,10.1.0.0/16,192.168.1.1,2001:db8::/32").unwrap();
+        assert_eq!(list.len, 4);
+        assert_eq!(lookup(&list, "10.0.0.0").as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(
+            lookup(&list, "10.255.255.255").as_deref(),
+            Some("10.0.0.0/8")
+        );
+        assert_eq!(lookup(&list, "10.1.2.3").as_deref(), Some("10.1.0.0/16"));
+        assert_eq!(
+            lookup(&list, "192.168.1.1").as_deref(),
+            Some("192.168.1.1/32")
+        );
+        assert_eq!(
+            lookup(&list, "2001:db8:ffff::1").as_deref(),
+            Some("2001:db8::/32")
+        );
+        for addr in [
+            "9.255.255.255",
+            "11.0.0.0",
+            "192.168.1.2",
+            "2001:db9::",
+            "::ffff:10.1.2.3",
+        ] {
+            assert_eq!(lookup(&list, addr), None, "{addr}");
+        }
+        assert_eq!(lookup(&PrefixList::default(), "10.0.0.1"), None);
+    }
+
+    #[test]
+    fn matches_every_address_of_a_zero_length_prefix() {
+        let list = PrefixList::load("0.0.0.0/0").unwrap();
+        assert_eq!(
+            lookup(&list, "255.255.255.255").as_deref(),
+            Some("0.0.0.0/0")
+        );
+        assert_eq!(lookup(&list, "::1"), None);
+        let list = PrefixList::load("::/0").unwrap();
+        assert_eq!(lookup(&list, "::1").as_deref(), Some("::/0"));
+        assert_eq!(lookup(&list, "127.0.0.1"), None);
+    }
+
+    #[test]
+    fn loads_prefix_files() {
+        let file = std::env::temp_dir().join(format!("adpi-dstlist-{}", std::process::id()));
+        let spec = file.to_str().unwrap();
+        fs::write(
+            &file,
+            "# CDN ranges\n\n104.16.0.0/13  # one\n2606:4700::/32\n",
+        )
+        .unwrap();
+        let list = PrefixList::load(spec).unwrap();
+        fs::write(&file, "104.16.0.0/13\n104.16.0.0/33\n").unwrap();
+        let err = PrefixList::load(spec).err().unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(path(spec), Some(file.as_path()));
+        assert_eq!(list.len, 2);
+        assert_eq!(
+            lookup(&list, "104.23.255.1").as_deref(),
+            Some("104.16.0.0/13")
+        );
+        assert_eq!(
+            lookup(&list, "2606:4700::6810:84e5").as_deref(),
+            Some("2606:4700::/32")
+        );
+        assert!(err.to_string().starts_with(&format!("{spec}:2: ")), "{err}");
+
+        let err = PrefixList::load("10.0.0.0/8,nonsense").err().unwrap();
+        assert!(
+            err.to_string().ends_with("and there is no such file"),
+            "{err}"
+        );
+        assert_eq!(path("10.0.0.0/8,::1"), None);
+    }
+}
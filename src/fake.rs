@@ -0,0 +1,366 @@
+use std::{
+    fs, io,
+    os::fd::AsRawFd,
+    path::Path,
+    ptr, slice,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{io::Interest, net::TcpStream};
+
+use crate::{strategy::Strategy, strategy_map, tls, Args};
+
+pub const DEFAULT_SNI: &str = "www.iana.org";
+/// Largest --fake-tls or --fake-http file, which is cut or padded to the segment it replaces
+/// anyway.
+pub const MAX_DECOY_LEN: usize = 16384;
+const ALERT_LEVEL_WARNING: u8 = 1;
+const ALERT_CLOSE_NOTIFY: u8 = 0;
+
+static DECOYS: RwLock<Option<Arc<Decoys>>> = RwLock::new(None);
+
+/// Payloads sent in place of the first segment with --fake.
+pub struct Decoys {
+    pub tls: Vec<u8>,
+    pub http: Vec<u8>,
+}
+
+impl Decoys {
+    fn load(args: &Args) -> Result<Self> {
+        let tls = match &args.fake_tls {
+            Some(path) => read_decoy(path)?,
+            None => client_hello(&args.fake_sni),
+        };
+        let http = match &args.fake_http {
+            Some(path) => read_decoy(path)?,
+            None => format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", args.fake_sni).into_bytes(),
+        };
+        Ok(Decoys { tls, http })
+    }
+}
+
+fn read_decoy(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+    if data.is_empty() || data.len() > MAX_DECOY_LEN {
+        return Err(anyhow!(
+            "{} is {} bytes, expected 1 to {MAX_DECOY_LEN}",
+            path.display(),
+            data.len()
+        ));
+    }
+    Ok(data)
+}
+
+/// Reads the decoy files, keeping the current decoys if that fails.
+pub fn reload(args: &Args) -> Result<()> {
+    let decoys = Decoys::load(args)?;
+    if args.fake_interleave || strategy_map::any(args, Strategy::fakes) {
+        println!(
+            "fake payloads: tls {} bytes, http {} bytes",
+            decoys.tls.len(),
+            decoys.http.len()
+        );
+    }
+    *DECOYS.write().unwrap() = Some(Arc::new(decoys));
+    Ok(())
+}
+
+pub fn decoys() -> Arc<Decoys> {
+    DECOYS
+        .read()
+        .unwrap()
+        .clone()
+        .expect("decoys are loaded at startup")
+}
+
+/// Anonymous memory sent with vmsplice, so that the kernel keeps referencing its pages after the
+/// write and retransmits whatever they contain by then.
+pub struct SplicedPages {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is owned exclusively and only written through `&mut self`.
+unsafe impl Send for SplicedPages {}
+unsafe impl Sync for SplicedPages {}
+
+impl SplicedPages {
+    pub fn new(data: &[u8]) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                data.len().max(1),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pages = SplicedPages {
+            ptr: ptr.cast(),
+            len: data.len(),
+        };
+        pages.overwrite(data);
+        Ok(pages)
+    }
+
+    /// Replaces the contents, including any bytes the kernel hasn't retransmitted yet.
+    pub fn overwrite(&mut self, data: &[u8]) {
+        let len = data.len().min(self.len);
+        unsafe {
+            slice::from_raw_parts_mut(self.ptr, self.len)[..len].copy_from_slice(&data[..len])
+        };
+    }
+
+    /// Writes the pages to `stream` without copying them.
+    pub async fn send(&self, stream: &TcpStream) -> io::Result<()> {
+        let mut pipe = [0; 2];
+        if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let result = self.send_through(stream, pipe).await;
+        unsafe {
+            libc::close(pipe[0]);
+            libc::close(pipe[1]);
+        }
+        result
+    }
+
+    /// Maps as many of the pages from `queued` on into the pipe as it has room for, which is 64
+    /// KiB by default, returning how many.
+    fn queue(&self, pipe_tx: i32, queued: usize) -> io::Result<usize> {
+        let iov = libc::iovec {
+            iov_base: unsafe { self.ptr.add(queued) }.cast(),
+            iov_len: self.len - queued,
+        };
+        let n = unsafe { libc::vmsplice(pipe_tx, &iov, 1, libc::SPLICE_F_NONBLOCK) };
+        if n < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(0),
+                _ => Err(err),
+            };
+        }
+        Ok(n as usize)
+    }
+
+    /// Moves the pages to `stream` through the pipe, refilling it as the socket drains it.
+    async fn send_through(
+        &self,
+        stream: &TcpStream,
+        [pipe_rx, pipe_tx]: [i32; 2],
+    ) -> io::Result<()> {
+        let mut queued = 0;
+        let fd = stream.as_raw_fd();
+        let mut sent = 0;
+        while sent < self.len {
+            if queued < self.len {
+                queued += self.queue(pipe_tx, queued)?;
+            }
+            stream.writable().await?;
+            let n = stream.try_io(Interest::WRITABLE, || {
+                let n = unsafe {
+                    libc::splice(
+                        pipe_rx,
+                        ptr::null_mut(),
+                        fd,
+                        ptr::null_mut(),
+                        queued - sent,
+                        libc::SPLICE_F_NONBLOCK,
+                    )
+                };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            });
+            match n {
+                Ok(n) => sent += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SplicedPages {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len.max(1)) };
+    }
+}
+
+/// Builds a TLS 1.3 ClientHello, compatible with TLS 1.2 parsers, for `sni`.
+pub fn client_hello(sni: &str) -> Vec<u8> {
+    let mut seed = sni.bytes().fold(0x9e37_79b9_7f4a_7c15u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    };
+
+    let mut extensions = Vec::new();
+    let name = sni.as_bytes();
+    let mut server_name = Vec::new();
+    push_u16(&mut server_name, name.len() as u16 + 3);
+    server_name.push(0); // host_name
+    push_u16(&mut server_name, name.len() as u16);
+    server_name.extend_from_slice(name);
+    push_extension(&mut extensions, 0x0000, &server_name);
+    push_extension(&mut extensions, 0x0017, &[]); // extended_master_secret
+    push_extension(&mut extensions, 0xff01, &[0]); // renegotiation_info
+    push_extension(
+        &mut extensions,
+        0x000a,
+        &[0, 6, 0x00, 0x1d, 0x00, 0x17, 0x00, 0x18],
+    ); // supported_groups
+    push_extension(&mut extensions, 0x000b, &[1, 0]); // ec_point_formats
+    push_extension(&mut extensions, 0x0023, &[]); // session_ticket
+    push_extension(&mut extensions, 0x0010, b"\x00\x0c\x02h2\x08http/1.1"); // alpn
+    push_extension(
+        &mut extensions,
+        0x000d, // signature_algorithms
+        &[
+            0, 16, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x03, 0x08, 0x05, 0x05, 0x01, 0x08,
+            0x06, 0x06, 0x01,
+        ],
+    );
+    let mut key_share = vec![0, 36, 0x00, 0x1d, 0, 32];
+    key_share.extend(random(32));
+    push_extension(&mut extensions, 0x0033, &key_share);
+    push_extension(&mut extensions, 0x002d, &[1, 1]); // psk_key_exchange_modes
+    push_extension(&mut extensions, 0x002b, &[4, 0x03, 0x04, 0x03, 0x03]); // supported_versions
+
+    let cipher_suites: [u16; 15] = [
+        0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014,
+        0x009c, 0x009d, 0x002f, 0x0035,
+    ];
+
+    let mut body = vec![0x03, 0x03];
+    body.extend(random(32));
+    body.push(32);
+    body.extend(random(32));
+    push_u16(&mut body, cipher_suites.len() as u16 * 2);
+    for suite in cipher_suites {
+        push_u16(&mut body, suite);
+    }
+    body.extend_from_slice(&[1, 0]); // null compression
+    push_u16(&mut body, extensions.len() as u16);
+    body.extend(extensions);
+
+    let mut hello = vec![0x16, 0x03, 0x01];
+    push_u16(&mut hello, body.len() as u16 + 4);
+    hello.push(0x01);
+    hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    hello.extend(body);
+    hello
+}
+
+/// Returns the record sent before the ClientHello by a fakerec step: a change_cipher_spec, or a
+/// close_notify alert.
+pub fn record(ccs: bool) -> Vec<u8> {
+    if ccs {
+        tls::record(tls::CONTENT_TYPE_CHANGE_CIPHER_SPEC, &[1])
+    } else {
+        tls::record(
+            tls::CONTENT_TYPE_ALERT,
+            &[ALERT_LEVEL_WARNING, ALERT_CLOSE_NOTIFY],
+        )
+    }
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_extension(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    push_u16(buf, kind);
+    push_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use tls_parser::{
+        parse_tls_extensions, parse_tls_plaintext, SNIType, TlsExtension, TlsMessage,
+        TlsMessageHandshake, TlsVersion,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_pages_larger_than_the_pipe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let pages = SplicedPages::new(&data).unwrap();
+        let received = tokio::spawn(async move {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        tokio::time::timeout(std::time::Duration::from_secs(5), pages.send(&client))
+            .await
+            .expect("send doesn't stall on a full pipe")
+            .unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(received.await.unwrap(), data);
+    }
+
+    #[test]
+    fn builds_hello_tls_parser_accepts() {
+        for sni in [
+            DEFAULT_SNI,
+            "a.b",
+            "xn--d1acufc.xn--p1ai",
+            &"long.".repeat(50),
+        ] {
+            let hello = client_hello(sni);
+            let (rest, plaintext) = parse_tls_plaintext(&hello).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(plaintext.hdr.len as usize, hello.len() - tls::HEADER_LEN);
+            let [TlsMessage::Handshake(TlsMessageHandshake::ClientHello(contents))] =
+                &plaintext.msg[..]
+            else {
+                panic!("not a ClientHello");
+            };
+            assert_eq!(contents.version, TlsVersion::Tls12);
+            assert!(contents.ciphers.len() > 10);
+
+            let (rest, extensions) = parse_tls_extensions(contents.ext.unwrap()).unwrap();
+            assert!(rest.is_empty());
+            let names: Vec<_> = extensions
+                .iter()
+                .filter_map(|ext| match ext {
+                    TlsExtension::SNI(names) => Some(names),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+            assert_eq!(names, [&(SNIType::HostName, sni.as_bytes())]);
+            assert!(extensions
+                .iter()
+                .any(|ext| matches!(ext, TlsExtension::SupportedVersions(_))));
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Country lookups in MaxMind DB files, such as GeoLite2-Country.mmdb, for --geoip-db.
+
+use std::{
+    fmt::Write,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use maxminddb::{geoip2, Mmap, Reader};
+
+use crate::Args;
+
+/// Countries move between networks, so a database left this long without an update is ignored.
+const MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+static DB: RwLock<Option<Arc<Reader<Mmap>>>> = RwLock::new(None);
+
+/// Opens --geoip-db, leaving countries unfiltered with a warning if the file is missing, broken
+/// or out of date.
+pub fn reload(args: &Args) {
+    let Some(path) = &args.geoip_db else {
+        return;
+    };
+    // SAFETY: updates such as those of geoipupdate replace the file by renaming a new one over
+    // it, leaving the mapped one as it is.
+    let db = match unsafe { Reader::open_mmap(path) } {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!(
+                "cannot load {}: {err}, not filtering by country",
+                path.display()
+            );
+            *DB.write().unwrap() = None;
+            return;
+        }
+    };
+    let build_time = SystemTime::UNIX_EPOCH + Duration::from_secs(db.metadata().build_epoch);
+    let age = build_time.elapsed().unwrap_or_default();
+    if age > MAX_AGE {
+        eprintln!(
+            "{} was built {} days ago, not filtering by country until it is updated",
+            path.display(),
+            age.as_secs() / 86400
+        );
+        *DB.write().unwrap() = None;
+        return;
+    }
+    println!("geoip: countries from {}", path.display());
+    *DB.write().unwrap() = Some(Arc::new(db));
+}
+
+/// Returns the ISO 3166-1 code of the country of `addr`, or of the one it is registered in
+/// where the database has no other.
+fn country(db: &Reader<Mmap>, addr: IpAddr) -> Result<Option<String>> {
+    let Some(record) = db
+        .lookup(addr.to_canonical())?
+        .decode::<geoip2::Country>()?
+    else {
+        return Ok(None);
+    };
+    let code = record
+        .country
+        .iso_code
+        .or(record.registered_country.iso_code);
+    Ok(code.map(str::to_owned))
+}
+
+/// Returns true if connections to `addr` may be desynced: unless its country is in
+/// --geoip-exclude, if it is in --geoip-include or without one, or without a database. Notes the
+/// country in `log` for the line of the first payload, or logs the connection as relayed as is.
+pub fn targets(addr: IpAddr, log: &mut String, conn: &str, args: &Args) -> bool {
+    let Some(db) = DB.read().unwrap().clone() else {
+        return true;
+    };
+    let country = country(&db, addr).unwrap_or_else(|err| {
+        debug!("{conn}: cannot look up the country of the destination: {err:#}");
+        None
+    });
+    let listed = |codes: &[String]| {
+        country
+            .as_ref()
+            .is_some_and(|country| codes.iter().any(|code| code.eq_ignore_ascii_case(country)))
+    };
+    let name = country.as_deref().unwrap_or("unknown country");
+    let (targeted, why) = if listed(&args.geoip_exclude) {
+        (false, ", in --geoip-exclude")
+    } else if args.geoip_include.is_empty() {
+        (true, "")
+    } else if listed(&args.geoip_include) {
+        (true, ", in --geoip-include")
+    } else {
+        (false, ", not in --geoip-include")
+    };
+    if targeted {
+        _ = write!(log, " (destination in {name}{why})");
+    } else {
+        eprintln!("{conn}: destination in {name}{why}, relaying as is");
+    }
+    targeted
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn leaves_countries_unfiltered_without_a_good_database() {
+        let path = std::env::temp_dir().join(format!("adpi-geoip-{}.mmdb", std::process::id()));
+        fs::write(&path, b"not a MaxMind DB").unwrap();
+        let path_arg = path.to_str().unwrap();
+        let args = crate::tests::args(&["--geoip-db", path_arg, "--geoip-include", "RU"]);
+        reload(&args);
+        fs::remove_file(&path).unwrap();
+        assert!(DB.read().unwrap().is_none());
+
+        let mut log = String::new();
+        assert!(targets(
+            "192.0.2.1".parse().unwrap(),
+            &mut log,
+            "test",
+            &args
+        ));
+        assert_eq!(log, "");
+    }
+}
@@ -0,0 +1,595 @@
+//! Gzip decompression (RFC 1951 and 1952), for hostlists kept compressed.
+
+use std::io::{self, BufRead, Read};
+
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Back-references reach at most this far back into the output.
+const WINDOW_LEN: usize = 32 * 1024;
+const FAST_BITS: u8 = 15;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths come in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt gzip data: {what}"),
+    )
+}
+
+/// Reads the bits of a stream from the least significant of each byte.
+struct Bits<R> {
+    inner: R,
+    buf: u64,
+    len: u8,
+}
+
+impl<R: BufRead> Bits<R> {
+    /// Tops up the buffer from the stream, as far as it goes.
+    fn refill(&mut self) -> io::Result<()> {
+        while self.len <= 56 {
+            let available = self.inner.fill_buf()?;
+            if available.is_empty() {
+                break;
+            }
+            let take = available.len().min(((64 - self.len) / 8) as usize);
+            for &byte in &available[..take] {
+                self.buf |= (byte as u64) << self.len;
+                self.len += 8;
+            }
+            self.inner.consume(take);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: u8) -> io::Result<u32> {
+        if self.len < len {
+            self.refill()?;
+            if self.len < len {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "truncated gzip data",
+                ));
+            }
+        }
+        let bits = (self.buf & ((1 << len) - 1)) as u32;
+        self.buf >>= len;
+        self.len -= len;
+        Ok(bits)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(8)? as u8)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(self.take(16)? as u16)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(self.u16()? as u32 | (self.u16()? as u32) << 16)
+    }
+
+    /// Skips to the next byte boundary.
+    fn align(&mut self) {
+        let skip = self.len % 8;
+        self.buf >>= skip;
+        self.len -= skip;
+    }
+
+    /// Returns the next symbol of `code`.
+    fn decode(&mut self, code: &Huffman) -> io::Result<u16> {
+        if self.len < code.bits {
+            self.refill()?;
+        }
+        let (symbol, len) = code.table[(self.buf & ((1 << code.bits) - 1)) as usize];
+        if len == 0 {
+            return Err(corrupt("bad code"));
+        }
+        if len > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated gzip data",
+            ));
+        }
+        self.buf >>= len;
+        self.len -= len;
+        Ok(symbol)
+    }
+}
+
+/// A canonical Huffman code, looked up by the next `bits` bits of input at once.
+struct Huffman {
+    /// Symbols and code lengths, 0 for bits that start no code.
+    table: Vec<(u16, u8)>,
+    bits: u8,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let bits = lengths.iter().copied().max().unwrap_or(0).min(FAST_BITS);
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        let mut next = [0u32; 16];
+        let mut code = 0;
+        for len in 1..16 {
+            left = (left << 1) - counts[len] as i32;
+            if left < 0 {
+                return Err(corrupt("oversubscribed code"));
+            }
+            code = (code + counts[len - 1] as u32) << 1;
+            next[len] = code;
+        }
+        let mut table = vec![(0, 0); 1 << bits];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            // Codes are sent from their most significant bit, the reverse of other fields.
+            let mut i = (code.reverse_bits() >> (32 - len)) as usize;
+            while i < table.len() {
+                table[i] = (symbol as u16, len);
+                i += 1 << len;
+            }
+        }
+        Ok(Huffman { table, bits })
+    }
+
+    fn fixed() -> (Huffman, Huffman) {
+        let mut lengths = [0u8; 288];
+        lengths[..144].fill(8);
+        lengths[144..256].fill(9);
+        lengths[256..280].fill(7);
+        lengths[280..].fill(8);
+        let literals = Huffman::new(&lengths).expect("valid code");
+        let distances = Huffman::new(&[5; 30]).expect("valid code");
+        (literals, distances)
+    }
+}
+
+enum Block {
+    /// Between blocks, or before the first of a member.
+    Start,
+    Stored(usize),
+    Compressed(Huffman, Huffman),
+    Done,
+}
+
+/// Decompresses gzip data as it is read, including several members one after another.
+pub struct GzDecoder<R> {
+    bits: Bits<R>,
+    block: Block,
+    last_block: bool,
+    /// Output, the last WINDOW_LEN bytes of it kept after reading for back-references.
+    out: Vec<u8>,
+    /// How far `out` has been read.
+    pos: usize,
+    crc: u32,
+    member_len: u32,
+    in_member: bool,
+}
+
+impl<R: BufRead> GzDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        GzDecoder {
+            bits: Bits {
+                inner,
+                buf: 0,
+                len: 0,
+            },
+            block: Block::Start,
+            last_block: false,
+            out: Vec::new(),
+            pos: 0,
+            crc: !0,
+            member_len: 0,
+            in_member: false,
+        }
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        let bits = &mut self.bits;
+        if [bits.byte()?, bits.byte()?] != MAGIC {
+            return Err(corrupt("not gzip"));
+        }
+        if bits.byte()? != 8 {
+            return Err(corrupt("unknown compression method"));
+        }
+        let flags = bits.byte()?;
+        // Modification time, extra flags and OS.
+        bits.take(32)?;
+        bits.u16()?;
+        if flags & 0x04 != 0 {
+            for _ in 0..bits.u16()? {
+                bits.byte()?;
+            }
+        }
+        // The file name, then a comment.
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                while bits.byte()? != 0 {}
+            }
+        }
+        if flags & 0x02 != 0 {
+            bits.u16()?;
+        }
+        self.in_member = true;
+        self.crc = !0;
+        self.member_len = 0;
+        Ok(())
+    }
+
+    fn trailer(&mut self) -> io::Result<()> {
+        self.bits.align();
+        let crc = self.bits.u32()?;
+        let len = self.bits.u32()?;
+        if crc != !self.crc || len != self.member_len {
+            return Err(corrupt("checksum mismatch"));
+        }
+        self.in_member = false;
+        Ok(())
+    }
+
+    fn block_header(&mut self) -> io::Result<Block> {
+        self.last_block = self.bits.take(1)? == 1;
+        match self.bits.take(2)? {
+            0 => {
+                self.bits.align();
+                let len = self.bits.u16()?;
+                if self.bits.u16()? != !len {
+                    return Err(corrupt("bad stored block length"));
+                }
+                Ok(Block::Stored(len as usize))
+            }
+            1 => {
+                let (literals, distances) = Huffman::fixed();
+                Ok(Block::Compressed(literals, distances))
+            }
+            2 => self.dynamic_codes(),
+            _ => Err(corrupt("bad block type")),
+        }
+    }
+
+    fn dynamic_codes(&mut self) -> io::Result<Block> {
+        let literal_count = self.bits.take(5)? as usize + 257;
+        let distance_count = self.bits.take(5)? as usize + 1;
+        let code_length_count = self.bits.take(4)? as usize + 4;
+        let mut code_lengths = [0u8; 19];
+        for &i in &CODE_LENGTH_ORDER[..code_length_count] {
+            code_lengths[i] = self.bits.take(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths)?;
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut i = 0;
+        while i < lengths.len() {
+            let (len, repeat) = match self.bits.decode(&code_lengths)? {
+                len @ 0..=15 => (len as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.bits.take(2)? as usize),
+                16 => return Err(corrupt("repeat without a length")),
+                17 => (0, 3 + self.bits.take(3)? as usize),
+                _ => (0, 11 + self.bits.take(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(corrupt("too many code lengths"));
+            }
+            lengths[i..i + repeat].fill(len);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(corrupt("no end of block code"));
+        }
+        let literals = Huffman::new(&lengths[..literal_count])?;
+        let distances = Huffman::new(&lengths[literal_count..])?;
+        Ok(Block::Compressed(literals, distances))
+    }
+
+    /// Decompresses until there are WINDOW_LEN bytes more to read or the data ends.
+    fn fill(&mut self) -> io::Result<()> {
+        let start = self.out.len();
+        while self.out.len() - start < WINDOW_LEN {
+            match &mut self.block {
+                Block::Start if !self.in_member => {
+                    self.header()?;
+                    self.block = self.block_header()?;
+                }
+                Block::Start if self.last_block => {
+                    self.update_crc(start);
+                    self.trailer()?;
+                    self.block = Block::Done;
+                    // Another member may follow, anything else is ignored as gzip does.
+                    self.bits.refill()?;
+                    if self.bits.len >= 16 && self.bits.buf as u16 == u16::from_le_bytes(MAGIC) {
+                        self.block = Block::Start;
+                    }
+                    return Ok(());
+                }
+                Block::Start => self.block = self.block_header()?,
+                Block::Stored(0) => self.block = Block::Start,
+                Block::Stored(left) => {
+                    *left -= 1;
+                    self.out.push(self.bits.byte()?);
+                }
+                Block::Compressed(literals, distances) => {
+                    let symbol = self.bits.decode(literals)? as usize;
+                    if symbol < 256 {
+                        self.out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        self.block = Block::Start;
+                        continue;
+                    }
+                    let i = symbol - 257;
+                    if i >= LENGTH_BASES.len() {
+                        return Err(corrupt("bad length code"));
+                    }
+                    let len =
+                        LENGTH_BASES[i] as usize + self.bits.take(LENGTH_EXTRA_BITS[i])? as usize;
+                    let i = self.bits.decode(distances)? as usize;
+                    if i >= DISTANCE_BASES.len() {
+                        return Err(corrupt("bad distance code"));
+                    }
+                    let distance = DISTANCE_BASES[i] as usize
+                        + self.bits.take(DISTANCE_EXTRA_BITS[i])? as usize;
+                    if distance > self.out.len() {
+                        return Err(corrupt("distance before the start"));
+                    }
+                    let from = self.out.len() - distance;
+                    for j in from..from + len {
+                        self.out.push(self.out[j]);
+                    }
+                }
+                Block::Done => break,
+            }
+        }
+        self.update_crc(start);
+        Ok(())
+    }
+
+    fn update_crc(&mut self, start: usize) {
+        let new = &self.out[start..];
+        for &byte in new {
+            self.crc = CRC_TABLE[((self.crc ^ byte as u32) & 0xff) as usize] ^ (self.crc >> 8);
+        }
+        self.member_len = self.member_len.wrapping_add(new.len() as u32);
+    }
+}
+
+impl<R: BufRead> Read for GzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if matches!(self.block, Block::Done) {
+                return Ok(0);
+            }
+            if self.out.len() > 2 * WINDOW_LEN {
+                let drop = self.out.len() - WINDOW_LEN;
+                self.out.drain(..drop);
+                self.pos -= drop;
+            }
+            self.fill()?;
+        }
+        let len = buf.len().min(self.out.len() - self.pos);
+        buf[..len].copy_from_slice(&self.out[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Returns true if `reader` is at the start of gzip data, without consuming any of it.
+pub fn is_gzip(reader: &mut impl BufRead) -> io::Result<bool> {
+    Ok(reader.fill_buf()?.starts_with(&MAGIC))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Gzip from zlib, whose deflate streams are a fixed Huffman block with back-references.
+    const ZLIB_FIXED: [&[u8]; 2] = [
+        b"\x1f\x8b\x08\0\0\0\0\0\x02\x03\x4b\x4c\x2a\x4a\x4c\x4e\x4c\x49\x04\x52\x0a\x89\x08\
+          \x36\x17\0\x0a\x7a\xb6\xa3\x18\0\0\0",
+        b"\x1f\x8b\x08\0\0\0\0\0\x02\x03\x4b\xad\x48\xcc\x2d\xc8\x49\xd5\x4b\xce\xcf\xe5\x4a\
+          \x45\x62\x03\0\x96\x73\x72\xdf\x18\0\0\0",
+    ];
+
+    /// Zapret's conventions, compressed by zlib with a dynamic Huffman block.
+    pub const ZAPRET_HOSTLIST: &[u8] = include_bytes!("../testdata/zapret-hostlist.txt.gz");
+
+    fn crc32(data: &[u8]) -> u32 {
+        !data.iter().fold(!0u32, |crc, &byte| {
+            CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    }
+
+    fn member(deflate: Vec<u8>, data: &[u8]) -> Vec<u8> {
+        let mut member = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+        member.extend(deflate);
+        member.extend_from_slice(&crc32(data).to_le_bytes());
+        member.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        member
+    }
+
+    /// Wraps `data` in a gzip member of stored blocks.
+    fn stored(data: &[u8]) -> Vec<u8> {
+        let mut deflate = Vec::new();
+        let mut chunks = data.chunks(u16::MAX as usize).peekable();
+        if chunks.peek().is_none() {
+            deflate.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(chunk) = chunks.next() {
+            deflate.push(chunks.peek().is_none() as u8);
+            deflate.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            deflate.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+            deflate.extend_from_slice(chunk);
+        }
+        member(deflate, data)
+    }
+
+    /// Compresses `data` to a gzip member of one fixed Huffman block of literals only, as big
+    /// as it gets in that block type.
+    pub fn fixed(data: &[u8]) -> Vec<u8> {
+        let mut deflate = Vec::new();
+        let (mut buf, mut len) = (0u64, 0);
+        let mut put = |bits: u32, n: u8, deflate: &mut Vec<u8>| {
+            buf |= (bits as u64) << len;
+            len += n;
+            while len >= 8 {
+                deflate.push(buf as u8);
+                buf >>= 8;
+                len -= 8;
+            }
+        };
+        let code = |code: u32, n: u8| code.reverse_bits() >> (32 - n);
+        // The last block, of type 1.
+        put(0b011, 3, &mut deflate);
+        for &byte in data {
+            match byte {
+                0..=143 => put(code(0x30 + byte as u32, 8), 8, &mut deflate),
+                _ => put(code(0x190 + byte as u32 - 144, 9), 9, &mut deflate),
+            }
+        }
+        put(0, 7, &mut deflate);
+        put(0, 7, &mut deflate);
+        member(deflate, data)
+    }
+
+    fn gunzip(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Bytes that don't repeat much, so that back-references reach across the window.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn inflates_stored_blocks() {
+        for data in [Vec::new(), b"example.com\n".to_vec(), noise(150_000)] {
+            assert_eq!(gunzip(&stored(&data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn inflates_fixed_blocks() {
+        assert_eq!(gunzip(ZLIB_FIXED[0]).unwrap(), b"abracadabra abracadabra\n");
+        assert_eq!(
+            gunzip(ZLIB_FIXED[1]).unwrap(),
+            b"example.com\nexample.com\n"
+        );
+        let every_byte: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+        assert_eq!(gunzip(&fixed(&every_byte)).unwrap(), every_byte);
+    }
+
+    #[test]
+    fn inflates_dynamic_blocks() {
+        assert_eq!((ZAPRET_HOSTLIST[10] >> 1) & 3, 2);
+        let text = String::from_utf8(gunzip(ZAPRET_HOSTLIST).unwrap()).unwrap();
+        assert_eq!(text.len(), 435);
+        assert!(text.starts_with("# list-youtube.txt\r\nyoutube.com\r\n"));
+        assert!(text.ends_with("nnmclub.to\r\nпример.рф\r\n"));
+    }
+
+    #[test]
+    fn inflates_concatenated_members() {
+        let mut data = stored(b"one\n");
+        data.extend(fixed(b"two\n"));
+        data.extend_from_slice(ZLIB_FIXED[1]);
+        assert_eq!(
+            gunzip(&data).unwrap(),
+            b"one\ntwo\nexample.com\nexample.com\n"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let noise = stored(&noise(70_000));
+        for data in [ZLIB_FIXED[0], ZAPRET_HOSTLIST, &noise] {
+            let step = data.len() / 1000 + 1;
+            for len in (1..data.len())
+                .step_by(step)
+                .chain(data.len() - 8..data.len())
+            {
+                assert!(
+                    gunzip(&data[..len]).is_err(),
+                    "{len} of {} bytes",
+                    data.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_corrupted_input() {
+        let stored = stored(b"example.com\n");
+        for data in [ZLIB_FIXED[0], ZAPRET_HOSTLIST, &stored] {
+            // Past the modification time, extra flags and OS, which nothing checks.
+            for i in (0..4).chain(10..data.len()) {
+                let mut corrupted = data.to_vec();
+                corrupted[i] ^= 0xff;
+                assert!(
+                    gunzip(&corrupted).is_err(),
+                    "byte {i} of {} flipped",
+                    data.len()
+                );
+            }
+        }
+        for data in [&b"\x1f\x8b\x07"[..], b"\x1f\x8a\x08\0\0\0\0\0\0\x03\x03\0"] {
+            assert!(gunzip(data).is_err());
+        }
+    }
+}
@@ -0,0 +1,612 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    fs::File,
+    hash::{BuildHasherDefault, Hasher},
+    io::{BufRead, BufReader},
+    iter,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    autohostlist,
+    gzip::{self, GzDecoder},
+    hostlist_url, nftset,
+    regex::RegexSet,
+    stats::STATS,
+    Args,
+};
+
+/// Host name pattern: `example.com` matches it and its subdomains, `.example.com` only its
+/// subdomains and `*.example.com` only those one label down, such as `www.example.com`. Matching
+/// ignores case and a trailing dot.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Pattern {
+    domain: String,
+    kind: Kind,
+}
+
+/// What a pattern matches below its domain, ordered from the narrowest.
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+enum Kind {
+    OneLabel,
+    Subdomains,
+    Domain,
+}
+
+impl Kind {
+    /// Returns true if a host `depth` labels below the domain matches.
+    fn matches(self, depth: usize) -> bool {
+        match self {
+            Kind::OneLabel => depth == 1,
+            Kind::Subdomains => depth > 0,
+            Kind::Domain => true,
+        }
+    }
+}
+
+/// Lowercases `host`, strips a trailing dot and turns international names into their `xn--`
+/// form, as host names are compared.
+pub fn normalize(host: &str) -> String {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    to_ascii(host).unwrap_or_else(|_| host.to_lowercase())
+}
+
+/// Returns `domain` lowercased, with labels holding other than ASCII in their `xn--` form as in
+/// IDNA.
+fn to_ascii(domain: &str) -> Result<String, String> {
+    if domain.is_ascii() {
+        return Ok(domain.to_ascii_lowercase());
+    }
+    idna::domain_to_ascii(domain).map_err(|_| format!("bad international domain name {domain:?}"))
+}
+
+impl Pattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = normalize(host);
+        let Some(prefix) = host.strip_suffix(self.domain.as_str()) else {
+            return false;
+        };
+        let depth = match prefix {
+            "" => 0,
+            prefix => match prefix.strip_suffix('.') {
+                Some(prefix) => prefix.split('.').count(),
+                None => return false,
+            },
+        };
+        self.kind.matches(depth)
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "*" {
+            return Err(
+                "a * pattern would match every host, leave out the list instead".to_owned(),
+            );
+        }
+        let (kind, domain) = if let Some(domain) = s.strip_prefix("*.") {
+            (Kind::OneLabel, domain)
+        } else if let Some(domain) = s.strip_prefix('.') {
+            (Kind::Subdomains, domain)
+        } else {
+            (Kind::Domain, s)
+        };
+        let domain = to_ascii(domain.strip_suffix('.').unwrap_or(domain))?;
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        };
+        if !domain.split('.').all(valid_label) {
+            return Err(format!("bad host pattern {s:?}"));
+        }
+        Ok(Pattern { domain, kind })
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            Kind::OneLabel => write!(f, "*.")?,
+            Kind::Subdomains => write!(f, ".")?,
+            Kind::Domain => {}
+        }
+        write!(f, "{}", self.domain)
+    }
+}
+
+static LOADED: LazyLock<RwLock<Loaded>> = LazyLock::new(RwLock::default);
+
+/// The lists by file, as listeners can have their own with --listener-override.
+#[derive(Default)]
+struct Loaded {
+    /// Merged with the --hostlist-url lists, None standing for those alone.
+    include: HashMap<Option<PathBuf>, Arc<HostList>>,
+    exclude: HashMap<PathBuf, Arc<HostList>>,
+    regexes: Option<Arc<RegexSet>>,
+}
+
+/// The --hostlist, --hostlist-exclude and --host-regex lists of a listener.
+struct Lists {
+    include: Option<Arc<HostList>>,
+    exclude: Option<Arc<HostList>>,
+    regexes: Option<Arc<RegexSet>>,
+}
+
+/// Host patterns read from a file, looked up by walking the labels of a host name from the
+/// top-level domain down, so that lookups take time in the length of the name rather than the
+/// size of the list.
+#[derive(Default)]
+pub struct HostList {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, Node, BuildHasherDefault<LabelHasher>>,
+    /// The pattern for the domain down to here, if listed.
+    pattern: Option<Pattern>,
+}
+
+/// FNV-1a, much quicker than the default hasher on labels as short as most are. The lists come
+/// from the configuration, so there is no one to pick labels that collide.
+struct LabelHasher(u64);
+
+impl Default for LabelHasher {
+    fn default() -> Self {
+        LabelHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for LabelHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl HostList {
+    /// Reads a file of patterns as HostList::read does.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let mut list = HostList::default();
+        list.read(BufReader::new(file), &path.display().to_string())?;
+        Ok(list)
+    }
+
+    /// Adds the patterns read from `reader`, plain or gzip compressed, one per line, naming
+    /// `origin` in errors. Blank lines are skipped, and so are lines starting with ; or / as in
+    /// zapret lists, along with anything after a #.
+    pub fn read(&mut self, mut reader: impl BufRead, origin: &str) -> Result<()> {
+        if gzip::is_gzip(&mut reader).with_context(|| format!("cannot read {origin}"))? {
+            let reader = BufReader::new(GzDecoder::new(reader));
+            return self.read_lines(reader, origin);
+        }
+        self.read_lines(reader, origin)
+    }
+
+    fn read_lines(&mut self, mut reader: impl BufRead, origin: &str) -> Result<()> {
+        let mut buf = String::new();
+        for i in 0.. {
+            buf.clear();
+            let len = reader
+                .read_line(&mut buf)
+                .with_context(|| format!("cannot read {origin}:{}", i + 1))?;
+            if len == 0 {
+                break;
+            }
+            let line = buf.split('#').next().unwrap_or(&buf).trim();
+            if line.is_empty() || line.starts_with([';', '/']) {
+                continue;
+            }
+            let pattern = match line.parse() {
+                Ok(pattern) => pattern,
+                Err(err) if !line.is_ascii() => {
+                    eprintln!("{origin}:{}: {err}, skipping", i + 1);
+                    continue;
+                }
+                Err(err) => return Err(anyhow!("{origin}:{}: {err}", i + 1)),
+            };
+            self.insert(pattern);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, pattern: Pattern) {
+        let mut node = &mut self.root;
+        for label in pattern.domain.rsplit('.') {
+            // Look the label up first, as big lists share most labels and boxing each is slow.
+            if !node.children.contains_key(label) {
+                node.children.insert(label.into(), Node::default());
+            }
+            node = node.children.get_mut(label).expect("inserted");
+        }
+        self.len += 1;
+        // Keep the broadest of the patterns for a domain, as it covers the others.
+        if node
+            .pattern
+            .as_ref()
+            .is_none_or(|listed| pattern.kind > listed.kind)
+        {
+            node.pattern = Some(pattern);
+        }
+    }
+
+    /// Returns the listed pattern matching `host`, the one closest to the top-level domain if
+    /// there are several.
+    pub fn lookup(&self, host: &str) -> Option<&Pattern> {
+        let host = normalize(host);
+        let mut depth = host.split('.').count();
+        let mut node = &self.root;
+        for label in host.rsplit('.') {
+            node = node.children.get(label)?;
+            depth -= 1;
+            if let Some(pattern) = node
+                .pattern
+                .as_ref()
+                .filter(|pattern| pattern.kind.matches(depth))
+            {
+                return Some(pattern);
+            }
+        }
+        None
+    }
+}
+
+/// Reads the --hostlist, --hostlist-exclude and --host-regex files, those of each
+/// --listener-override and the cached --hostlist-url lists, keeping the current lists if any
+/// fails.
+pub fn reload(args: &Args) -> Result<()> {
+    let mut urls = Vec::new();
+    for url in &args.hostlist_url {
+        let path = hostlist_url::cache_path(url, args);
+        if path.exists() {
+            let data =
+                fs::read(&path).with_context(|| format!("cannot read {}", path.display()))?;
+            urls.push((url, path, data));
+        }
+    }
+    let overrides = &args.listener_override;
+    let includes = iter::once(&args.hostlist)
+        .chain(overrides.iter().filter_map(|entry| entry.hostlist.as_ref()));
+    let excludes = iter::once(&args.hostlist_exclude)
+        .chain(
+            overrides
+                .iter()
+                .filter_map(|entry| entry.hostlist_exclude.as_ref()),
+        )
+        .flatten();
+
+    let mut loaded = Loaded::default();
+    for path in includes {
+        if loaded.include.contains_key(path) {
+            continue;
+        }
+        let mut list = match path {
+            Some(path) => {
+                let list = HostList::load(path)?;
+                println!("hostlist: {} patterns from {}", list.len, path.display());
+                list
+            }
+            None if args.hostlist_url.is_empty() => continue,
+            // Until the first download, the list is empty rather than missing, so that nothing
+            // is desynced that the list would have left alone.
+            None => HostList::default(),
+        };
+        for (url, path, data) in &urls {
+            let len = list.len;
+            list.read(&data[..], &path.display().to_string())?;
+            if loaded.include.is_empty() {
+                println!("hostlist: {} patterns from {url}", list.len - len);
+            }
+        }
+        loaded.include.insert(path.clone(), Arc::new(list));
+    }
+    for path in excludes {
+        if loaded.exclude.contains_key(path) {
+            continue;
+        }
+        let list = HostList::load(path)?;
+        println!(
+            "hostlist exclude: {} patterns from {}",
+            list.len,
+            path.display()
+        );
+        loaded.exclude.insert(path.clone(), Arc::new(list));
+    }
+    if let Some(path) = &args.host_regex {
+        let regexes = RegexSet::load(path)?;
+        println!("host regexes: {} from {}", regexes.len(), path.display());
+        loaded.regexes = Some(Arc::new(regexes));
+    }
+    *LOADED.write().unwrap() = loaded;
+    let listeners: Vec<_> = args
+        .listen_address
+        .iter()
+        .map(|addr| args.for_listener(*addr))
+        .collect();
+    nftset::prune(|host| listeners.iter().any(|args| listed(host, args)));
+    Ok(())
+}
+
+/// Returns the lists for connections with `args`.
+fn lists(args: &Args) -> Lists {
+    let loaded = LOADED.read().unwrap();
+    Lists {
+        include: loaded.include.get(&args.hostlist).cloned(),
+        exclude: args
+            .hostlist_exclude
+            .as_ref()
+            .and_then(|path| loaded.exclude.get(path))
+            .cloned(),
+        regexes: loaded.regexes.clone(),
+    }
+}
+
+/// Returns true if connections to `host`, or without one, are to be desynced: unless excluded
+/// without --hostlist, --host-regex and --auto-hostlist.
+pub fn targets(host: Option<&str>, conn: &str, args: &Args) -> bool {
+    let Lists {
+        include,
+        exclude,
+        regexes,
+    } = lists(args);
+    if let (Some(host), Some(exclude)) = (host, &exclude) {
+        if let Some(pattern) = exclude.lookup(host) {
+            debug!("{conn}: {host} matches {pattern} in --hostlist-exclude");
+            return false;
+        }
+    }
+    if include.is_none() && regexes.is_none() && args.auto_hostlist.is_none() {
+        return true;
+    }
+    let Some(host) = host else {
+        return args.hostlist_no_sni;
+    };
+    if let Some(pattern) = include.as_ref().and_then(|include| include.lookup(host)) {
+        debug!("{conn}: {host} matches {pattern} in --hostlist");
+        return true;
+    }
+    if let Some(regex) = regexes.as_ref().and_then(|regexes| regexes.lookup(host)) {
+        debug!("{conn}: {host} matches {regex} in --host-regex");
+        return true;
+    }
+    if autohostlist::contains(host) {
+        STATS.auto_hostlist_hits.incr();
+        debug!("{conn}: {host} is in --auto-hostlist");
+        return true;
+    }
+    debug!("{conn}: {host} matches no hostlist");
+    false
+}
+
+/// Returns true if `host` is in --hostlist, --host-regex or --auto-hostlist, and not excluded.
+pub fn listed(host: &str, args: &Args) -> bool {
+    let Lists {
+        include,
+        exclude,
+        regexes,
+    } = lists(args);
+    exclude.is_none_or(|exclude| exclude.lookup(host).is_none())
+        && (include.is_some_and(|include| include.lookup(host).is_some())
+            || regexes.is_some_and(|regexes| regexes.lookup(host).is_some())
+            || autohostlist::contains(host))
+}
+
+/// Returns true if failures relaying connections to `host` as is count for --auto-hostlist.
+pub fn learns(host: &str, args: &Args) -> bool {
+    let exclude = lists(args).exclude;
+    args.auto_hostlist.is_some() && exclude.is_none_or(|exclude| exclude.lookup(host).is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fmt::Write,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn matches_hosts_like_the_list() {
+        let cases = [
+            ("example.com", "example.com", true),
+            ("example.com", "www.example.com", true),
+            ("example.com", "a.b.example.com", true),
+            ("example.com", "notexample.com", false),
+            ("example.com", "example.com.evil", false),
+            ("example.com", "com", false),
+            (".example.com", "example.com", false),
+            (".example.com", "www.example.com", true),
+            (".example.com", "a.b.example.com", true),
+            ("*.cdn.example.com", "cdn.example.com", false),
+            ("*.cdn.example.com", "edge.cdn.example.com", true),
+            ("*.cdn.example.com", "a.edge.cdn.example.com", false),
+            ("*.cdn.example.com", "example.com", false),
+            ("example.com.", "www.example.com", true),
+            ("example.com", "example.com.", true),
+            ("example.com", "WWW.Example.COM.", true),
+            ("EXAMPLE.com", "www.example.com", true),
+            ("пример.рф", "xn--e1afmkfd.xn--p1ai", true),
+            ("пример.рф", "www.ПРИМЕР.рф", true),
+            ("xn--e1afmkfd.xn--p1ai", "пример.рф", true),
+            ("*.пример.рф", "www.xn--e1afmkfd.xn--p1ai", true),
+        ];
+        for (pattern, host, matches) in cases {
+            let parsed: Pattern = pattern.parse().unwrap();
+            assert_eq!(parsed.matches(host), matches, "{pattern} against {host}");
+            let mut list = HostList::default();
+            list.insert(parsed);
+            assert_eq!(
+                list.lookup(host).is_some(),
+                matches,
+                "{pattern} list against {host}"
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_rfc_3492_samples() {
+        // Section 7.1, with the uppercase letters of the mixed case samples lowered.
+        for (label, encoded) in [
+            (
+                "\u{644}\u{64a}\u{647}\u{645}\u{627}\u{628}\u{62a}\u{643}\u{644}\u{645}\u{648}\u{634}\u{639}\u{631}\u{628}\u{64a}\u{61f}",
+                "egbpdaj6bu4bxfgehfvwxn",
+            ),
+            ("他们为什么不说中文", "ihqwcrb4cv8a8dqg056pqjye"),
+            ("他們爲什麽不說中文", "ihqwctvzc91f659drss3x8bo0yb"),
+            ("למההםפשוטלאמדבריםעברית", "4dbcagdahymbxekheh6e0a7fei0b"),
+            ("なぜみんな日本語を話してくれないのか", "n8jok5ay5dzabd5bym9f0cm5685rrjetr6pdxa"),
+            ("почемужеонинеговорятпорусски", "b1abfaaepdrnnbgefbadotcwatmq2g4l"),
+            (
+                "porquénopuedensimplementehablarenespañol",
+                "porqunopuedensimplementehablarenespaol-fmd56a",
+            ),
+            ("3年b組金八先生", "3b-ww4c5e180e575a65lsy2b"),
+            ("安室奈美恵-with-super-monkeys", "-with-super-monkeys-pc58ag80a8qai00g7n9n"),
+            ("ひとつ屋根の下2", "2-u9tlzr9756bt3uc0v"),
+            ("パフィーdeルンバ", "de-jg4avhby1noc0d"),
+            ("そのスピードで", "d9juau41awczczp"),
+        ] {
+            assert_eq!(to_ascii(label).unwrap(), format!("xn--{encoded}"), "{label}");
+        }
+    }
+
+    #[test]
+    fn reads_lists_mixing_unicode_and_punycode() {
+        let text = "пример.рф\n.xn--e1aabhcpqfgk.com\n*.Bücher.example\n\
+                    xn--mnchen-3ya.de\nмойдомен .рф\nexample.org\n";
+        let mut list = HostList::default();
+        list.read(text.as_bytes(), "mixed").unwrap();
+        for (host, matches) in [
+            ("xn--e1afmkfd.xn--p1ai", true),
+            ("www.пример.рф", true),
+            ("www.xn--e1aabhcpqfgk.com", true),
+            ("www.приложение.com", true),
+            ("приложение.com", false),
+            ("shop.xn--bcher-kva.example", true),
+            ("shop.bücher.example", true),
+            ("münchen.de", true),
+            ("MÜNCHEN.de", true),
+            ("example.org", true),
+            // The bad line is skipped, not the list.
+            ("xn--d1acklchcc.xn--p1ai", false),
+        ] {
+            assert_eq!(list.lookup(host).is_some(), matches, "{host}");
+        }
+    }
+
+    #[test]
+    fn prints_normalized_patterns() {
+        for (pattern, printed) in [
+            ("Example.COM.", "example.com"),
+            (".example.com", ".example.com"),
+            ("*.пример.рф", "*.xn--e1afmkfd.xn--p1ai"),
+        ] {
+            assert_eq!(pattern.parse::<Pattern>().unwrap().to_string(), printed);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_patterns() {
+        for pattern in [
+            "*",
+            "",
+            ".",
+            "*.",
+            "a..b",
+            "exa mple.com",
+            "*example.com",
+            "**.a",
+        ] {
+            assert!(pattern.parse::<Pattern>().is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn finds_the_broadest_of_overlapping_patterns() {
+        let mut list = HostList::default();
+        let lines = "*.example.com\n.example.com\nexample.com\n; comment\nwww.example.com\n";
+        list.read(lines.as_bytes(), "test").unwrap();
+        assert_eq!(list.len, 4);
+        assert_eq!(
+            list.lookup("example.com").unwrap().to_string(),
+            "example.com"
+        );
+        assert_eq!(
+            list.lookup("a.www.example.com").unwrap().to_string(),
+            "example.com"
+        );
+
+        let mut list = HostList::default();
+        list.read("*.example.com\nwww.example.com\n".as_bytes(), "test")
+            .unwrap();
+        assert!(list.lookup("example.com").is_none());
+        assert_eq!(
+            list.lookup("www.example.com").unwrap().to_string(),
+            "*.example.com"
+        );
+        assert_eq!(
+            list.lookup("a.www.example.com").unwrap().to_string(),
+            "www.example.com"
+        );
+    }
+
+    #[test]
+    fn reads_compressed_zapret_lists() {
+        let mut list = HostList::default();
+        list.read(gzip::tests::ZAPRET_HOSTLIST, "list-youtube.txt.gz")
+            .unwrap();
+        assert_eq!(list.len, 26);
+        for host in [
+            "rr1.googlevideo.com",
+            "t.co",
+            "www.facebook.com",
+            "fbcdn.net",
+            "пример.рф",
+        ] {
+            assert!(list.lookup(host).is_some(), "{host}");
+        }
+        assert_eq!(
+            list.lookup("cdn.instagram.com").unwrap().to_string(),
+            "instagram.com"
+        );
+        assert!(list.lookup("youtube.org").is_none());
+    }
+
+    /// A benchmark, to run with `cargo test --release -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn reads_big_compressed_lists_quickly() {
+        let mut text = String::new();
+        for i in 0..500_000 {
+            writeln!(text, "host{i}.example{}.com", i % 5000).unwrap();
+        }
+        let data = gzip::tests::fixed(text.as_bytes());
+        let start = Instant::now();
+        let mut list = HostList::default();
+        list.read(&data[..], "big.txt.gz").unwrap();
+        let elapsed = start.elapsed();
+        eprintln!(
+            "read 500000 lines, {} bytes compressed, in {elapsed:?}",
+            data.len()
+        );
+        assert_eq!(list.len, 500_000);
+        assert!(list.lookup("www.host499999.example4999.com").is_some());
+        assert!(elapsed < Duration::from_secs(1));
+    }
+}
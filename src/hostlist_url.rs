@@ -0,0 +1,531 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Socket, Type};
+use tokio::time;
+
+use crate::{hostlist, hostlist::HostList, Args};
+
+const TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 5;
+const MAX_RESPONSE_LEN: u64 = 64 << 20;
+
+/// An http or https URL to download a hostlist from.
+#[derive(Clone, Debug)]
+pub struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+    /// The path and query, starting with /.
+    path: String,
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (https, rest) = if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!("bad URL {s:?}, expected http:// or https://"));
+        };
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                let port = port.parse().map_err(|_| format!("bad port in URL {s:?}"))?;
+                (host, port)
+            }
+            _ => (authority, if https { 443 } else { 80 }),
+        };
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() || host.contains(['@', '[', ']', ' ']) {
+            return Err(format!("bad host in URL {s:?}"));
+        }
+        let path = match path.starts_with('?') {
+            true => format!("/{path}"),
+            false => path.to_owned(),
+        };
+        Ok(Url {
+            https,
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+impl Url {
+    /// The host and port as in the Host header, leaving out the default port.
+    fn authority(&self) -> String {
+        let host = match self.host.contains(':') {
+            true => format!("[{}]", self.host),
+            false => self.host.clone(),
+        };
+        match (self.https, self.port) {
+            (true, 443) | (false, 80) => host,
+            (_, port) => format!("{host}:{port}"),
+        }
+    }
+
+    /// Resolves a redirect `location` against this URL.
+    fn join(&self, location: &str) -> Result<Url, String> {
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return location.parse();
+        }
+        if let Some(rest) = location.strip_prefix("//") {
+            let scheme = if self.https { "https" } else { "http" };
+            return format!("{scheme}://{rest}").parse();
+        }
+        let path = if location.starts_with('/') {
+            location.to_owned()
+        } else {
+            let dir = self.path.split('?').next().unwrap_or(&self.path);
+            let dir = &dir[..dir.rfind('/').map_or(0, |i| i + 1)];
+            format!("{dir}{location}")
+        };
+        Ok(Url {
+            path,
+            ..self.clone()
+        })
+    }
+}
+
+/// Returns the file in --hostlist-cache-dir holding the last good copy of the list at `url`.
+pub fn cache_path(url: &Url, args: &Args) -> PathBuf {
+    let digest = Sha256::digest(url.to_string().as_bytes());
+    let name: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+    args.hostlist_cache_dir.join(format!("hostlist-{name}.txt"))
+}
+
+/// Downloads the --hostlist-url lists now and every --hostlist-refresh-secs, reloading the
+/// hostlist when any changed.
+pub fn start(args: &Arc<Args>) {
+    if args.hostlist_url.is_empty() {
+        return;
+    }
+    let args = Arc::clone(args);
+    tokio::spawn(async move {
+        let mut interval =
+            time::interval(time::Duration::from_secs(args.hostlist_refresh_secs.max(1)));
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let refresh_args = Arc::clone(&args);
+            let changed = tokio::task::spawn_blocking(move || refresh_all(&refresh_args))
+                .await
+                .unwrap_or(false);
+            if changed {
+                if let Err(err) = hostlist::reload(&args) {
+                    eprintln!("cannot reload hostlist: {err:#}");
+                }
+            }
+        }
+    });
+}
+
+/// Returns true if any of the lists changed.
+fn refresh_all(args: &Args) -> bool {
+    let mut changed = false;
+    for url in &args.hostlist_url {
+        match refresh(url, args) {
+            Ok(true) => changed = true,
+            Ok(false) => debug!("hostlist: {url} not modified"),
+            Err(err) => eprintln!("cannot download {url}, keeping the last copy: {err:#}"),
+        }
+    }
+    changed
+}
+
+/// Downloads the list at `url` into its cache file unless it hasn't changed since the cached
+/// copy, returning whether it did.
+fn refresh(url: &Url, args: &Args) -> Result<bool> {
+    let path = cache_path(url, args);
+    // The ETag and Last-Modified headers of the cached copy, one per line.
+    let validators_path = path.with_extension("validators");
+    let mut headers = String::new();
+    if path.exists() {
+        let validators = fs::read_to_string(&validators_path).unwrap_or_default();
+        for line in validators.lines() {
+            if let Some(etag) = line.strip_prefix("etag: ") {
+                headers += &format!("If-None-Match: {etag}\r\n");
+            } else if let Some(date) = line.strip_prefix("last-modified: ") {
+                headers += &format!("If-Modified-Since: {date}\r\n");
+            }
+        }
+    }
+    let Some(response) = get(url, &headers, args)? else {
+        return Ok(false);
+    };
+    let validators: String = ["etag", "last-modified"]
+        .into_iter()
+        .filter_map(|name| Some(format!("{name}: {}\n", response.header(name)?)))
+        .collect();
+    let mut list = HostList::default();
+    list.read(&response.body[..], &url.to_string())?;
+
+    fs::create_dir_all(&args.hostlist_cache_dir)
+        .with_context(|| format!("cannot create {}", args.hostlist_cache_dir.display()))?;
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, &response.body)
+        .and_then(|()| fs::rename(&temp_path, &path))
+        .with_context(|| format!("cannot save {}", path.display()))?;
+    fs::write(&validators_path, validators)
+        .with_context(|| format!("cannot save {}", validators_path.display()))?;
+    println!("hostlist: downloaded {url}");
+    Ok(true)
+}
+
+struct Response {
+    status: u16,
+    /// With lowercased names.
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Fetches `url` with the extra request `headers`, following redirects, or returns None if it
+/// was not modified.
+fn get(url: &Url, headers: &str, args: &Args) -> Result<Option<Response>> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        let response = request(&url, headers, args)?;
+        match response.status {
+            200 => return Ok(Some(response)),
+            304 => return Ok(None),
+            301 | 302 | 303 | 307 | 308 => {
+                let location = response
+                    .header("location")
+                    .with_context(|| format!("HTTP {} without a location", response.status))?;
+                url = url.join(location).map_err(|err| anyhow!(err))?;
+                debug!("hostlist: redirected to {url}");
+            }
+            status => bail!("HTTP {status}"),
+        }
+    }
+    bail!("more than {MAX_REDIRECTS} redirects")
+}
+
+fn request(url: &Url, headers: &str, args: &Args) -> Result<Response> {
+    let addrs: Vec<_> = (url.host.as_str(), url.port)
+        .to_socket_addrs()
+        .with_context(|| format!("cannot resolve {}", url.host))?
+        .collect();
+    let mut stream = Err(anyhow!("no addresses for {}", url.host));
+    for addr in addrs {
+        stream = connect(addr, args).with_context(|| format!("cannot connect to {addr}"));
+        if stream.is_ok() {
+            break;
+        }
+    }
+    let stream = stream?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: adpi/{}\r\n\
+         Accept-Encoding: identity\r\nConnection: close\r\n{headers}\r\n",
+        url.path,
+        url.authority(),
+        env!("CARGO_PKG_VERSION"),
+    );
+    let mut raw = Vec::new();
+    if url.https {
+        let name = ServerName::try_from(url.host.clone())
+            .map_err(|_| anyhow!("bad server name {:?}", url.host))?;
+        let connection = ClientConnection::new(tls_config()?, name)?;
+        read_response(StreamOwned::new(connection, stream), &request, &mut raw)?;
+    } else {
+        read_response(stream, &request, &mut raw)?;
+    }
+    parse_response(&raw)
+}
+
+/// Sends `request` and reads the response until the server closes the connection.
+fn read_response(mut stream: impl Read + Write, request: &str, raw: &mut Vec<u8>) -> Result<()> {
+    stream.write_all(request.as_bytes())?;
+    match stream.take(MAX_RESPONSE_LEN + 1).read_to_end(raw) {
+        // Servers often close without a TLS close_notify, which parse_response makes up for
+        // by checking the length of the body.
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => {}
+        result => {
+            result?;
+        }
+    }
+    if raw.len() as u64 > MAX_RESPONSE_LEN {
+        bail!("response over {MAX_RESPONSE_LEN} bytes");
+    }
+    Ok(())
+}
+
+/// Connects to `addr` with --fwmark, so that the download isn't intercepted itself.
+fn connect(addr: SocketAddr, args: &Args) -> io::Result<TcpStream> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::STREAM, None)?;
+    socket.set_cloexec(true)?;
+    if args.fwmark != 0 {
+        socket.set_mark(args.fwmark)?;
+    }
+    socket.connect_timeout(&addr.into(), TIMEOUT)?;
+    Ok(socket.into())
+}
+
+fn tls_config() -> Result<Arc<ClientConfig>> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(Arc::clone(config));
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    if roots.is_empty() {
+        bail!("no CA certificates found on the system");
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::clone(CONFIG.get_or_init(|| Arc::new(config))))
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response> {
+    let end = raw
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .context("truncated response headers")?;
+    let head = std::str::from_utf8(&raw[..end]).context("bad response headers")?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.strip_prefix("HTTP/1."))
+        .and_then(|line| line.get(2..5))
+        .and_then(|status| status.parse().ok())
+        .context("bad status line")?;
+    let headers: Vec<_> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+    let body = &raw[end + 4..];
+    let chunked = response
+        .header("transfer-encoding")
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+    response.body = if chunked {
+        dechunk(body)?
+    } else if let Some(len) = response.header("content-length") {
+        let len: usize = len.parse().context("bad content-length")?;
+        if body.len() < len {
+            bail!("truncated response, {} of {len} bytes", body.len());
+        }
+        body[..len].to_vec()
+    } else {
+        body.to_vec()
+    };
+    Ok(response)
+}
+
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .context("truncated chunked response")?;
+        let size = std::str::from_utf8(&body[..line_end])
+            .ok()
+            .and_then(|line| usize::from_str_radix(line.split(';').next()?.trim(), 16).ok())
+            .context("bad chunk size")?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        if body.len() < size + 2 {
+            bail!("truncated chunked response");
+        }
+        out.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, os::unix::fs::MetadataExt, path::Path, thread};
+
+    use super::*;
+
+    /// Answers a connection with each of `responses` in turn on a local port, returning the URL
+    /// of the list and the requests made.
+    fn serve(responses: &[&str]) -> (Url, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let responses: Vec<_> = responses.iter().map(|r| r.to_string()).collect();
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut byte = [0];
+                while !request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut byte).unwrap();
+                    request.push(byte[0]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.push(String::from_utf8(request).unwrap());
+            }
+            requests
+        });
+        (url.parse().unwrap(), server)
+    }
+
+    fn ok(etag: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nETag: {etag}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    /// Returns the arguments for downloading `url` into an empty cache directory of `test`.
+    fn args(test: &str, url: &Url) -> Args {
+        let dir =
+            std::env::temp_dir().join(format!("adpi-hostlist-url-{test}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let (url, dir) = (url.to_string(), dir.to_str().unwrap().to_owned());
+        crate::tests::args(&[
+            "--fwmark",
+            "0",
+            "--hostlist-url",
+            &url,
+            "--hostlist-cache-dir",
+            &dir,
+        ])
+    }
+
+    fn cached_hosts(path: &Path) -> HostList {
+        HostList::load(path).unwrap()
+    }
+
+    #[test]
+    fn revalidates_the_cached_copy_with_its_etag() {
+        let (url, server) = serve(&[
+            &ok("\"v1\"", "example.com\n"),
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n",
+            &ok("\"v2\"", "example.org\n"),
+        ]);
+        let args = args("etag", &url);
+        let path = cache_path(&url, &args);
+
+        assert!(refresh(&url, &args).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "example.com\n");
+        assert!(!refresh(&url, &args).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "example.com\n");
+        assert!(refresh(&url, &args).unwrap());
+        assert!(cached_hosts(&path).lookup("www.example.org").is_some());
+        assert!(cached_hosts(&path).lookup("example.com").is_none());
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("If-None-Match"), "{}", requests[0]);
+        for request in &requests[1..] {
+            assert!(
+                request.contains("\r\nIf-None-Match: \"v1\"\r\n"),
+                "{request}"
+            );
+        }
+        fs::remove_dir_all(&args.hostlist_cache_dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_cached_copy_when_the_download_fails() {
+        let (url, server) = serve(&[
+            &ok("\"v1\"", "example.com\n"),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n",
+            // Cut short.
+            "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nexample.org\n",
+            &ok("\"v2\"", "example.org\nexa mple.net\n"),
+        ]);
+        let args = args("failure", &url);
+        let path = cache_path(&url, &args);
+        assert!(refresh(&url, &args).unwrap());
+
+        for expected in ["HTTP 500", "truncated response", "bad host pattern"] {
+            let err = format!("{:#}", refresh(&url, &args).unwrap_err());
+            assert!(err.contains(expected), "{err}");
+            // The first download stays, with nothing left half written.
+            assert_eq!(fs::read_to_string(&path).unwrap(), "example.com\n");
+            assert!(!path.with_extension("tmp").exists());
+        }
+        server.join().unwrap();
+
+        // Nobody listens there any more.
+        let err = format!("{:#}", refresh(&url, &args).unwrap_err());
+        assert!(err.contains("cannot connect"), "{err}");
+        assert!(cached_hosts(&path).lookup("example.com").is_some());
+        fs::remove_dir_all(&args.hostlist_cache_dir).unwrap();
+    }
+
+    #[test]
+    fn replaces_the_cached_copy_whole() {
+        let (url, server) = serve(&[
+            &ok("\"v1\"", "example.com\n"),
+            &ok("\"v2\"", "example.org\n"),
+        ]);
+        let args = args("replace", &url);
+        let path = cache_path(&url, &args);
+        assert!(refresh(&url, &args).unwrap());
+        let old = fs::File::open(&path).unwrap();
+        let inode = old.metadata().unwrap().ino();
+
+        assert!(refresh(&url, &args).unwrap());
+        server.join().unwrap();
+        // Renamed over the old copy, which readers that opened it still see whole.
+        assert_ne!(fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(io::read_to_string(old).unwrap(), "example.com\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "example.org\n");
+        let validators = fs::read_to_string(path.with_extension("validators")).unwrap();
+        assert_eq!(validators, "etag: \"v2\"\n");
+        let mut names: Vec<_> = fs::read_dir(&args.hostlist_cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        names.sort_unstable();
+        assert_eq!(names, [path.clone(), path.with_extension("validators")]);
+        fs::remove_dir_all(&args.hostlist_cache_dir).unwrap();
+    }
+}
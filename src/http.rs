@@ -0,0 +1,283 @@
+use std::ops::Range;
+
+use crate::{rng, tls::Host};
+
+const METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"CONNECT ",
+    b"PATCH ",
+    b"TRACE ",
+];
+
+/// Returns true if `data` starts like an HTTP/1 request.
+pub fn is_request(data: &[u8]) -> bool {
+    METHODS.iter().any(|method| data.starts_with(method))
+}
+
+/// Starts the connection preface of cleartext HTTP/2 with prior knowledge, which is followed by
+/// a SETTINGS frame.
+const H2C_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const H2_FRAME_HEADER_LEN: usize = 9;
+const H2_SETTINGS: u8 = 4;
+/// Largest frame a peer must accept before SETTINGS raise the limit.
+const H2_MAX_FRAME_LEN: usize = 16384;
+
+/// Returns true if `data` starts like the connection preface of cleartext HTTP/2.
+pub fn is_h2c_preface(data: &[u8]) -> bool {
+    data.starts_with(&H2C_PREFACE[..14])
+}
+
+/// Returns the stream length to wait for while the h2c preface at the start of `data` or the
+/// SETTINGS frame following it are incomplete, or None once both are there or it isn't one.
+pub fn pending_h2c_preface(data: &[u8]) -> Option<usize> {
+    let header_end = H2C_PREFACE.len() + H2_FRAME_HEADER_LEN;
+    if !H2C_PREFACE.starts_with(&data[..data.len().min(H2C_PREFACE.len())]) {
+        return None;
+    }
+    let Some(header) = data.get(H2C_PREFACE.len()..header_end) else {
+        return Some(header_end);
+    };
+    let frame_len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    let needed = header_end + frame_len;
+    (header[3] == H2_SETTINGS && frame_len <= H2_MAX_FRAME_LEN && data.len() < needed)
+        .then_some(needed)
+}
+
+/// Headers beyond this are relayed without waiting for the rest.
+pub const MAX_HEADER_LEN: usize = 16384;
+
+pub struct Request {
+    /// Length of the request line and headers, including the empty line ending them.
+    pub header_len: usize,
+    /// Value of the Host header, without the port.
+    pub host: Option<Host>,
+    /// Stream offset of the Host header name.
+    pub host_header: Option<usize>,
+    pub http10: bool,
+    /// Stream ranges of the header lines with their line endings.
+    lines: Vec<Range<usize>>,
+    /// Set if a header is folded, Host is repeated or a line isn't a header, which rules out
+    /// moving them around.
+    unusual: bool,
+    /// Content-Length, 0 without one, or None if a Transfer-Encoding or odd Content-Length
+    /// leaves it unknown.
+    pub body_len: Option<usize>,
+}
+
+/// Returns the stream length to wait for while the headers of the request at the start of
+/// `data` are incomplete, or None once they are complete or it isn't a request.
+pub fn pending_request(data: &[u8]) -> Option<usize> {
+    (is_request(data) && data.len() < MAX_HEADER_LEN && header_len(data).is_none())
+        .then_some(MAX_HEADER_LEN)
+}
+
+/// Parses the header section of an HTTP/1.x request. The body, if any, is left alone.
+pub fn parse_request(data: &[u8]) -> Option<Request> {
+    if !is_request(data) {
+        return None;
+    }
+    let header_len = header_len(data)?;
+    let mut lines = Lines {
+        data: &data[..header_len],
+        pos: 0,
+    };
+    let (_, request_line) = lines.next()?;
+    let [.., b' ' | b'\t', b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor] = request_line else {
+        return None;
+    };
+    if !matches!(minor, b'0' | b'1') {
+        return None;
+    }
+
+    let mut request = Request {
+        header_len,
+        host: None,
+        host_header: None,
+        http10: *minor == b'0',
+        lines: Vec::new(),
+        unusual: false,
+        body_len: Some(0),
+    };
+    let mut content_length = None;
+    while let Some((start, line)) = lines.next() {
+        if line.is_empty() {
+            break;
+        }
+        request.lines.push(start..lines.pos);
+
+        let colon = line.iter().position(|b| *b == b':');
+        let (Some(colon), false) = (colon, line.first().is_some_and(is_space)) else {
+            request.unusual = true;
+            continue;
+        };
+        let (name, value) = (&line[..colon], line[colon + 1..].trim_ascii());
+        if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            request.body_len = None;
+        } else if name.eq_ignore_ascii_case(b"content-length") {
+            let len = std::str::from_utf8(value)
+                .ok()
+                .and_then(|len| len.parse().ok());
+            if len.is_none() || content_length.is_some_and(|other| Some(other) != len) {
+                request.body_len = None;
+            }
+            content_length = len;
+        } else if name.eq_ignore_ascii_case(b"host") {
+            if request.host_header.is_some() {
+                request.unusual = true;
+                continue;
+            }
+            request.host_header = Some(start);
+            let value_start =
+                start + colon + 1 + line[colon + 1..].iter().take_while(|b| is_space(b)).count();
+            let (offset, host) = match value.strip_prefix(b"[") {
+                Some(rest) => (1, rest.split(|b| *b == b']').next().unwrap_or(rest)),
+                None => (0, value.split(|b| *b == b':').next().unwrap_or(value)),
+            };
+            let host_start = value_start + offset;
+            request.host = std::str::from_utf8(host).ok().map(|name| Host {
+                name: name.to_owned(),
+                range: host_start..host_start + host.len(),
+            });
+        }
+    }
+    if request.body_len.is_some() {
+        request.body_len = Some(content_length.unwrap_or(0));
+    }
+    Some(request)
+}
+
+/// Returns the request in `data` with the Host header moved after the others if `host_last` is
+/// set, and preceded by an X-Padding header with `padding` bytes of value if it isn't 0, or None
+/// if its headers are unusual. The body is copied unchanged.
+pub fn reorder_headers(
+    data: &[u8],
+    request: &Request,
+    host_last: bool,
+    padding: usize,
+) -> Option<Vec<u8>> {
+    let host_line = request
+        .lines
+        .iter()
+        .find(|line| Some(line.start) == request.host_header)?;
+    if request.unusual {
+        return None;
+    }
+    let eol: &[u8] = if data[..host_line.end].ends_with(b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    let mut padded_host = Vec::new();
+    if padding > 0 {
+        padded_host.extend_from_slice(b"X-Padding: ");
+        padded_host.resize(padded_host.len() + padding, b'x');
+        padded_host.extend_from_slice(eol);
+    }
+    padded_host.extend_from_slice(&data[host_line.clone()]);
+
+    let first = request.lines.first()?;
+    let mut rewritten = Vec::with_capacity(data.len() + padded_host.len());
+    rewritten.extend_from_slice(&data[..first.start]);
+    for line in &request.lines {
+        if line != host_line {
+            rewritten.extend_from_slice(&data[line.clone()]);
+        } else if !host_last {
+            rewritten.extend_from_slice(&padded_host);
+        }
+    }
+    if host_last {
+        rewritten.extend_from_slice(&padded_host);
+    }
+    rewritten.extend_from_slice(&data[request.lines.last()?.end..]);
+    Some(rewritten)
+}
+
+/// Returns the request in `data` with an extra space after the method, a tab instead of the
+/// space before the version, and line endings in the headers changed from CRLF to LF, as
+/// selected. The body is copied unchanged.
+pub fn mangle_request_line(
+    data: &[u8],
+    request: &Request,
+    method_space: bool,
+    version_tab: bool,
+    unixeol: bool,
+) -> Vec<u8> {
+    let mut headers = data[..request.header_len].to_vec();
+    if version_tab {
+        let line_len = headers.iter().position(|b| *b == b'\n').unwrap_or(0);
+        let line = headers[..line_len]
+            .strip_suffix(b"\r")
+            .unwrap_or(&headers[..line_len]);
+        let space = line.len() - b"HTTP/1.1".len() - 1;
+        headers[space] = b'\t';
+    }
+    if method_space {
+        let space = headers.iter().position(|b| *b == b' ').unwrap_or(0);
+        headers.insert(space, b' ');
+    }
+    if unixeol {
+        headers = headers
+            .iter()
+            .enumerate()
+            .filter(|(i, b)| !(**b == b'\r' && headers.get(i + 1) == Some(&b'\n')))
+            .map(|(_, b)| *b)
+            .collect();
+    }
+    headers.extend_from_slice(&data[request.header_len..]);
+    headers
+}
+
+/// Rewrites the Host header name of `request` in `data` to `name`, which must be "host" in any
+/// case, and randomizes the case of its value if `domcase` is set. The length doesn't change.
+pub fn set_host_case(data: &mut [u8], request: &Request, name: Option<&str>, domcase: bool) {
+    if let (Some(name), Some(at)) = (name, request.host_header) {
+        data[at..at + name.len()].copy_from_slice(name.as_bytes());
+    }
+    if let (true, Some(host)) = (domcase, &request.host) {
+        for b in &mut data[host.range.clone()] {
+            *b = if rng::next_u64() & 1 == 0 {
+                b.to_ascii_lowercase()
+            } else {
+                b.to_ascii_uppercase()
+            };
+        }
+    }
+}
+
+/// Returns the length of the header section, ending with an empty line.
+fn header_len(data: &[u8]) -> Option<usize> {
+    let mut lines = Lines { data, pos: 0 };
+    while let Some((_, line)) = lines.next() {
+        if line.is_empty() {
+            return Some(lines.pos);
+        }
+    }
+    None
+}
+
+fn is_space(b: &u8) -> bool {
+    *b == b' ' || *b == b'\t'
+}
+
+/// Complete lines with their stream offsets, ending with LF or CRLF.
+struct Lines<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.pos;
+        let len = self.data[start..].iter().position(|b| *b == b'\n')?;
+        self.pos = start + len + 1;
+        let line = &self.data[start..start + len];
+        Some((start, line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+}
@@ -1,31 +1,220 @@
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::VERBOSE.load(::std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod autohostlist;
+mod autottl;
+mod desync;
+mod dstlist;
+mod fake;
+mod geoip;
+mod gzip;
+mod hostlist;
+mod hostlist_url;
+mod http;
+mod nftset;
+mod position;
+mod quic;
+#[cfg(feature = "raw")]
+mod raw;
+mod regex;
+mod retry;
+mod rng;
+mod sockopt;
+mod starttls;
+mod stats;
+mod strategy;
+mod strategy_map;
+mod tls;
+mod udp;
+mod watch;
+
 use std::{
-    mem::{size_of, MaybeUninit},
-    net::{SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+    ffi::CString,
+    fmt::{self, Write},
+    fs,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, TcpStream as StdTcpStream},
     os::fd::AsRawFd,
-    sync::Arc,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
-use linux_raw_sys::net::tcp_info;
-use socket2::{Domain, Socket, Type};
-use tls_parser::{
-    parse_tls_client_hello_extensions, parse_tls_plaintext, SNIType, TlsExtension, TlsMessage,
-    TlsMessageHandshake,
-};
+use clap::{ArgAction, Parser, ValueEnum};
+use desync::Desync;
+use position::{Landmarks, Position};
+use retry::{Attempt, WatchedReader};
+use socket2::{Domain, SockRef, Socket, Type};
+use starttls::StartTls;
+use stats::{ActiveGuard, STATS};
+use strategy::{NoSniPolicy, Plan, PortStrategy, SplitAt, Step, Strategy};
+use strategy_map::StrategyMap;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream,
+        TcpListener, TcpSocket, TcpStream,
     },
+    signal::unix::{signal, SignalKind},
     time,
 };
 
-#[derive(Parser, Debug)]
-#[command(version, about)]
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+const DRAIN_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+const ACCEPT_BACKOFF_MIN: time::Duration = time::Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: time::Duration = time::Duration::from_secs(1);
+const MAX_SPLIT_EVERY_SEGMENTS: usize = 64;
+/// Label splits kept for long chains of subdomains, from the first label.
+const MAX_LABEL_SPLITS: usize = 16;
+/// Window clamp lifting --wssize, the largest window with the maximum window scale.
+const UNCLAMPED_WINDOW: u32 = 65535 << 14;
+/// Settings a --listener-override can replace.
+const LISTENER_SETTINGS: &str = "hostlist, hostlist-exclude, desync-ports or no-sni-policy";
+/// Printed after the options, instead of in each that names a file.
+const RELOAD_HELP: &str = "The --strategy-map, host name and address list files are reloaded \
+    when they change and on SIGHUP, and --geoip-db and the decoy files on SIGHUP. One that fails \
+    to load leaves the previous one in use.";
+/// Bounds the time --desync-repeats can spend, as every repeat may take --desync-budget-ms.
+const MAX_DESYNC_REPEATS: usize = 16;
+const MAX_FAKE_REPEATS: usize = 16;
+const ACCEPT_WARNING_INTERVAL: time::Duration = time::Duration::from_secs(5);
+/// How long the interface addresses are trusted before being listed again.
+const INTERFACES_TTL: time::Duration = time::Duration::from_secs(5);
+const DENIED_WARNING_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// When a client denied by --allow-from was last logged, and how many were not logged since.
+static DENIED_WARNING: Mutex<(Option<time::Instant>, u64)> = Mutex::new((None, 0));
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Transparent {
+    /// Set if permitted
+    Auto,
+    /// Fail if it can't be set
+    Always,
+    /// Don't set
+    Never,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum FlushMode {
+    /// Until the segment has left the send queue
+    Notsent,
+    /// Until the destination has acknowledged it, or as notsent if the path is slower than
+    /// --flush-acked-max-rtt-ms
+    Acked,
+}
+
+/// A --desync-ports entry.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DesyncPort {
+    Any,
+    Port(u16),
+}
+
+impl fmt::Display for DesyncPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DesyncPort::Any => write!(f, "any"),
+            DesyncPort::Port(port) => write!(f, "{port}"),
+        }
+    }
+}
+
+/// A --listener-override entry, None leaving the global setting.
+#[derive(Clone, Debug)]
+struct ListenerOverride {
+    addr: SocketAddr,
+    hostlist: Option<Option<PathBuf>>,
+    hostlist_exclude: Option<Option<PathBuf>>,
+    desync_ports: Option<Vec<DesyncPort>>,
+    no_sni_policy: Option<NoSniPolicy>,
+}
+
+impl FromStr for ListenerOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let addr = words.next().ok_or("expected ADDRESS SETTING=VALUE...")?;
+        let mut entry = ListenerOverride {
+            addr: addr
+                .parse()
+                .map_err(|err| format!("bad listen address {addr:?}: {err}"))?,
+            hostlist: None,
+            hostlist_exclude: None,
+            desync_ports: None,
+            no_sni_policy: None,
+        };
+        for word in words {
+            let (setting, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("expected SETTING=VALUE, got {word:?}"))?;
+            let path = (!value.is_empty()).then(|| PathBuf::from(value));
+            match setting {
+                "hostlist" => entry.hostlist = Some(path),
+                "hostlist-exclude" => entry.hostlist_exclude = Some(path),
+                "desync-ports" => {
+                    let ports = value.split(',').map(parse_desync_port);
+                    let ports = ports.collect::<Result<_, _>>();
+                    entry.desync_ports = Some(ports.map_err(|err| format!("{setting}: {err}"))?);
+                }
+                "no-sni-policy" => entry.no_sni_policy = Some(value.parse()?),
+                _ => {
+                    return Err(format!(
+                        "unknown setting {setting:?}, expected {LISTENER_SETTINGS}"
+                    ))
+                }
+            }
+        }
+        Ok(entry)
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum PortFallback {
+    /// Use the default strategy
+    Default,
+    /// Relay connections as is
+    Passthrough,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Segmentation {
+    /// Write each segment and wait as --flush-mode says before writing the next
+    Flush,
+    /// Write each segment corked and uncork it to send it at once, without waiting. Segments
+    /// that are lowered TTL or carry urgent data still wait
+    Cork,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Fooling {
+    /// Sign the decoy with a TCP MD5 option instead of lowering its TTL
+    Md5sig,
+    /// Inject the decoy as a separate raw packet with a wrong sequence number, leaving the
+    /// connection alone. Needs CAP_NET_RAW and adpi built with the raw feature
+    Badseq,
+    /// Inject the decoy as a separate raw packet with a wrong TCP checksum, if that isn't fixed
+    /// on the way, as checked with the first one. Needs the same as badseq, with which it can be
+    /// combined
+    Badsum,
+}
+
+#[derive(Parser, Clone, Debug)]
+#[command(version, about, after_help = RELOAD_HELP)]
 struct Args {
-    /// Number of worker threads
+    /// Number of worker threads. Use all cores if 0.
     #[arg(short = 't', long, default_value_t = 4)]
     threads: usize,
 
@@ -33,239 +222,3005 @@ struct Args {
     #[arg(short = 'l', long, default_values = ["127.0.0.1:1280", "[::1]:1280"])]
     listen_address: Vec<SocketAddr>,
 
-    /// Split positions in TLS ClientHello message
-    #[arg(short = 'c', long)]
-    split_positions: Vec<usize>,
+    /// Settings for connections accepted on one listen address, replacing the global ones, such
+    /// as '127.0.0.1:1280 hostlist=own.txt hostlist-exclude= desync-ports=443,853
+    /// no-sni-policy=passthrough'. hostlist, hostlist-exclude, desync-ports and no-sni-policy can
+    /// be set, and an empty file leaves the list out
+    #[arg(long)]
+    listener_override: Vec<ListenerOverride>,
+
+    /// Exit if any listen address can't be bound
+    #[arg(long, default_value_t = false)]
+    bind_strict: bool,
+
+    /// Set IP_TRANSPARENT on listeners, which requires CAP_NET_ADMIN
+    #[arg(long, value_enum, default_value_t = Transparent::Auto)]
+    ip_transparent: Transparent,
+
+    /// Socket addresses to relay QUIC on, such as [::]:443 for udp/443 redirected with TPROXY,
+    /// splitting the ClientHello across Initial packets
+    #[arg(long)]
+    quic_listen_address: Vec<SocketAddr>,
+
+    /// Split positions in the ClientHello of QUIC connections, as with --split-positions. None by
+    /// default with --quic-fake
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_relative_split_position,
+        default_value = "sni+1",
+        default_value_if("quic_fake", "true", None)
+    )]
+    quic_split_positions: Vec<Position>,
+
+    /// Send a decoy QUIC Initial for --fake-sni with --fake-ttl, --fake-repeats times, before the
+    /// first Initial of every QUIC connection
+    #[arg(long, default_value_t = false)]
+    quic_fake: bool,
+
+    /// Send client Initials to IPv4 destinations as two IP fragments, the first carrying this many
+    /// bytes of the UDP payload, a multiple of 8, for DPI that doesn't reassemble them. Needs
+    /// CAP_NET_RAW and adpi built with the raw feature. Initials are sent whole if that fails or
+    /// the destination doesn't answer fragments
+    #[arg(long, value_parser = parse_ipfrag_offset)]
+    quic_ipfrag: Option<usize>,
+
+    /// Desync steps to take in order, such as fake:ttl=6,tlsrec,split:sni+1,delay:20, instead of
+    /// the individual flags. Steps are split:POSITION, split:host, split:midsld, split:labels,
+    /// split:random=MIN-MAX, split:every=N, tlsrec[:POSITION], fake[:ttl=N][:FOOLING]...,
+    /// fakerec[:ccs][:ttl=N][:FOOLING|:unfooled]..., disorder[:ttl=N], oob[:byte=B] and
+    /// delay:MS, where FOOLING is md5sig, badseq or badsum. Fake, disorder and oob apply to the
+    /// segment ended by the next split. Fakerec sends an alert or change_cipher_spec record
+    /// before the ClientHello, which the destination also gets if unfooled.
+    #[arg(long, conflicts_with_all = [
+        "split_positions", "split_random", "split_every", "split_host", "split_midsld",
+        "split_labels", "split_record_header", "disorder", "tlsrec", "tlsrec_pos", "fake",
+        "fooling", "oob", "disoob", "split_delay",
+    ])]
+    strategy: Option<Strategy>,
+
+    /// Named strategy to use unless --strategy or individual desync flags are given: split-sni,
+    /// split-midsld, oob-sni, fake-tls, disorder-md5 or tlsrec-aggressive. The steps it expands
+    /// to are printed at startup. --split-delay adds a delay to its splits instead
+    #[arg(long, value_parser = strategy::parse_preset)]
+    preset: Option<String>,
+
+    /// Pick the strategy by host name from this file, with lines of a host pattern and a strategy,
+    /// such as .example.com tlsrec,split:sni+1 or example.org none. A pattern matches the domain
+    /// and its subdomains, only subdomains with a leading dot, or only those one label down with
+    /// *., and the first matching line wins. Other hosts get the default strategy
+    #[arg(long)]
+    strategy_map: Option<PathBuf>,
+
+    /// Only desync connections to hosts listed in this file, one pattern per line as in
+    /// --strategy-map, and relay others as is
+    #[arg(long)]
+    hostlist: Option<PathBuf>,
+
+    /// Like --hostlist, with the list downloaded from this http or https URL at startup and every
+    /// --hostlist-refresh-secs, with --fwmark. Can be given more than once, and the lists are
+    /// merged with --hostlist. The last good copy is kept in --hostlist-cache-dir and used when a
+    /// download fails
+    #[arg(long)]
+    hostlist_url: Vec<hostlist_url::Url>,
+
+    /// Where to keep the lists downloaded for --hostlist-url
+    #[arg(long, default_value = "/var/cache/adpi")]
+    hostlist_cache_dir: PathBuf,
+
+    /// How often to check the --hostlist-url lists for changes
+    #[arg(long, default_value_t = 21600)]
+    hostlist_refresh_secs: u64,
+
+    /// Never desync connections to hosts listed in this file, even if --hostlist or --strategy-map
+    /// lists them
+    #[arg(long)]
+    hostlist_exclude: Option<PathBuf>,
+
+    /// Like --hostlist, with a regular expression per line that has to match all of the lowercased
+    /// host name, such as r[0-9]+---sn-.*\.googlevideo\.com. Checked after --hostlist, and both can
+    /// be given
+    #[arg(long)]
+    host_regex: Option<PathBuf>,
+
+    /// Relay connections to hosts not learned yet as is, and learn those whose destination
+    /// closes, resets or doesn't answer the connection within --fallback-timeout-ms while it is
+    /// retransmitting, --auto-hostlist-fails times in a row. Learned hosts are desynced from then
+    /// on and saved to this file, along with those in --hostlist
+    #[arg(long)]
+    auto_hostlist: Option<PathBuf>,
+
+    /// Failures relaying a host as is for --auto-hostlist to learn it
+    #[arg(long, default_value_t = 3, value_parser = parse_auto_hostlist_fails)]
+    auto_hostlist_fails: usize,
+
+    /// Add the IPv4 destinations of connections to hosts in --hostlist, --host-regex or
+    /// --auto-hostlist to this nftables set, such as inet adpi blocked, so that redirect rules can
+    /// be limited to them. Addresses are removed once their hosts leave the lists
+    #[arg(long, num_args = 3, value_names = ["FAMILY", "TABLE", "SET"])]
+    nft_set: Vec<String>,
+
+    /// Like --nft-set, for IPv6 destinations
+    #[arg(long, num_args = 3, value_names = ["FAMILY", "TABLE", "SET"])]
+    nft_set6: Vec<String>,
+
+    /// Only serve clients in these address prefixes, given as for --dst-include, and close
+    /// connections from others right away
+    #[arg(long)]
+    allow_from: Option<String>,
+
+    /// Only desync connections to destinations in these address prefixes, given as a
+    /// comma-separated list such as 192.0.2.0/24,2001:db8::/32 or a file with one per line, and
+    /// relay others as is without looking at their payload. Host name lists still apply to those
+    /// desynced
+    #[arg(long)]
+    dst_include: Option<String>,
+
+    /// Relay connections to destinations in these address prefixes as is, given as for
+    /// --dst-include, which they win over
+    #[arg(long)]
+    dst_exclude: Option<String>,
+
+    /// MaxMind DB file to look up the countries of destinations in for --geoip-include and
+    /// --geoip-exclude, such as GeoLite2-Country.mmdb. A file missing, broken or built over 90 days
+    /// ago leaves countries unfiltered, with a warning
+    #[arg(long)]
+    geoip_db: Option<PathBuf>,
+
+    /// Only desync connections to destinations in these countries, given as ISO 3166-1 codes such
+    /// as RU,BY, and relay others as is, along with those the --geoip-db doesn't know. Checked
+    /// after --dst-include and --dst-exclude
+    #[arg(long, value_delimiter = ',', value_parser = parse_country, requires = "geoip_db")]
+    geoip_include: Vec<String>,
+
+    /// Relay connections to destinations in these countries as is, given as for --geoip-include,
+    /// which they win over
+    #[arg(long, value_delimiter = ',', value_parser = parse_country, requires = "geoip_db")]
+    geoip_exclude: Vec<String>,
+
+    /// What to do with TLS connections without a host name, such as those without SNI or with
+    /// ECH, or with a ClientHello that can't be parsed: split them with the steps of their
+    /// strategy that don't need one, such as split:2, passthrough to relay them as is, or
+    /// strategy:STRATEGY to use that instead. With --hostlist, they are relayed as is unless
+    /// --hostlist-no-sni is given
+    #[arg(long, default_value = "split")]
+    no_sni_policy: NoSniPolicy,
+
+    /// Desync connections whose ClientHello has the encrypted_client_hello extension too, splitting
+    /// at the outer SNI, which are relayed as is otherwise
+    #[arg(long, default_value_t = false)]
+    desync_ech: bool,
 
-    /// Split TLS ClientHello at host
+    /// Only desync TLS connections whose ClientHello offers one of these ALPN protocols, such as
+    /// h2,http/1.1 for browsers, and relay others as is
+    #[arg(long, value_delimiter = ',')]
+    alpn_filter: Vec<String>,
+
+    /// Desync connections whose ClientHello offers no ALPN protocols with --alpn-filter
+    #[arg(long, default_value_t = false)]
+    alpn_filter_no_alpn: bool,
+
+    /// Desync connections without a host name with --hostlist, such as TLS without SNI
+    #[arg(long, default_value_t = false)]
+    hostlist_no_sni: bool,
+
+    /// Default strategies by original destination port, such as
+    /// 443=tlsrec:sni;80=split:host;853=split:sni+1, replacing the default strategy for
+    /// connections to that port. --strategy-map entries still win for the hosts they match
+    #[arg(long, value_delimiter = ';')]
+    port_strategy: Vec<PortStrategy>,
+
+    /// Desync connections to private, loopback and link-local addresses too, which are relayed as
+    /// is otherwise
+    #[arg(long, default_value_t = false)]
+    desync_private: bool,
+
+    /// Destination ports to desync connections to, or any, relaying others as is without looking
+    /// at their payload. Ports in --port-strategy and --starttls-ports are desynced too
+    #[arg(long, value_delimiter = ',', default_value = "443", value_parser = parse_desync_port)]
+    desync_ports: Vec<DesyncPort>,
+
+    /// What connections to ports missing from --port-strategy get
+    #[arg(long, value_enum, default_value_t = PortFallback::Default)]
+    port_strategy_fallback: PortFallback,
+
+    /// Strategies to try in order for destinations where the previous one failed, because the
+    /// destination closed, reset or didn't answer the connection within --fallback-timeout-ms of
+    /// the first payload. Every failure moves the host, or the address without one, to the next
+    /// strategy for future connections, starting over after the last one
+    #[arg(long)]
+    strategy_fallback: Vec<Strategy>,
+
+    /// Time for the destination to answer the first payload with --strategy-fallback or
+    /// --auto-hostlist
+    #[arg(long, default_value_t = 3000)]
+    fallback_timeout_ms: u64,
+
+    /// How long to keep using the fallback reached for a destination since its last connection
+    #[arg(long, default_value_t = 3_600_000)]
+    strategy_cache_ms: u64,
+
+    /// Save the fallbacks found to work here on shutdown, and start with them
+    #[arg(long)]
+    strategy_cache_file: Option<PathBuf>,
+
+    /// Split positions in TLS ClientHello message or HTTP request headers, as byte offsets or
+    /// relative to the TLS record header, the host name, the server_name or ALPN extension or the
+    /// end of the message, such as rechdr+2, sni+1, ext_sni, alpn+4 or end-10
+    #[arg(short = 'c', long, value_parser = parse_relative_split_position)]
+    split_positions: Vec<Position>,
+
+    /// Split the connection preface of cleartext HTTP/2 and the SETTINGS frame following it at
+    /// these byte offsets, such as 1,20. The preface is relayed as is without them
+    #[arg(long, value_delimiter = ',', value_parser = parse_split_position)]
+    h2c_split_positions: Vec<usize>,
+
+    /// Split TLS ClientHello or HTTP request headers at a random position in MIN:MAX for every
+    /// connection
+    #[arg(long, value_parser = parse_split_range)]
+    split_random: Option<(usize, usize)>,
+
+    /// Seed for --split-random, for reproducible runs
+    #[arg(long)]
+    split_random_seed: Option<u64>,
+
+    /// Split TLS ClientHello or HTTP request headers every this many bytes, into at most 64
+    /// segments
+    #[arg(long, value_parser = parse_split_position)]
+    split_every: Option<usize>,
+
+    /// Split TLS ClientHello or HTTP request headers at host
     #[arg(short = 's', long, default_value_t = false)]
     split_host: bool,
 
+    /// Split TLS ClientHello or HTTP request headers in the middle of the registrable domain label
+    #[arg(long, default_value_t = false)]
+    split_midsld: bool,
+
+    /// Split TLS ClientHello or HTTP request headers after every dot of the host name, at most
+    /// 16 times
+    #[arg(long, default_value_t = false)]
+    split_labels: bool,
+
+    /// Split TLS ClientHello after the first byte of its record header, for DPI that needs the
+    /// whole header in one segment
+    #[arg(long, default_value_t = false)]
+    split_record_header: bool,
+
+    /// Don't split at all when SNI or HTTP host is an IP address
+    #[arg(long, default_value_t = false)]
+    no_split_ip_sni: bool,
+
+    /// Print debug messages
+    #[arg(short = 'v', long, default_value_t = false)]
+    verbose: bool,
+
+    /// Destination ports where TLS starts after a STARTTLS command
+    #[arg(long, value_delimiter = ',')]
+    starttls_ports: Vec<u16>,
+
     /// Set fwmark for outgoing sockets. Disabled if 0.
     #[arg(short = 'm', long, default_value_t = 1280)]
     fwmark: u32,
-}
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+    /// Relay connections accepted with one of these fwmarks as is, for daemons that mark their
+    /// sockets to opt out. Accepted connections carry the mark of the client's SYN with TPROXY
+    /// or net.ipv4.tcp_fwmark_accept=1, and those with --fwmark are refused as loops
+    #[arg(long, value_delimiter = ',')]
+    skip_mark: Vec<u32>,
 
-    if args.threads > 1 {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?
-            .block_on(_main(args))
-    } else {
-        tokio::runtime::Builder::new_multi_thread()
-            .worker_threads(args.threads)
-            .enable_all()
-            .build()?
-            .block_on(_main(args))
-    }
-}
+    /// Close connections with a --skip-mark fwmark instead of relaying them
+    #[arg(long, default_value_t = false)]
+    skip_mark_refuse: bool,
 
-async fn _main(args: Args) -> Result<()> {
-    let args = Arc::new(args);
+    /// Forward connections without an original destination here instead of closing them
+    #[arg(long)]
+    default_destination: Option<SocketAddr>,
 
-    for addr in &args.listen_address {
-        let listener = make_listener(*addr)?;
-        println!("listening on {addr}");
+    /// Connect to IPv6 link-local destinations through this interface
+    #[arg(long, value_parser = parse_interface)]
+    link_local_interface: Option<u32>,
 
-        let args = Arc::clone(&args);
-        tokio::spawn(async move {
-            loop {
-                if let Ok((client_stream, client_addr)) = listener.accept().await {
-                    tokio::spawn(handle_client(client_stream, client_addr, Arc::clone(&args)));
-                }
-            }
-        });
-    }
+    /// Close client connections with RST instead of FIN when the upstream fails
+    #[arg(long, default_value_t = false)]
+    reset_client: bool,
 
-    tokio::signal::ctrl_c().await?;
-    Ok(())
-}
+    /// Upstream connect timeout
+    #[arg(long, default_value_t = 10000)]
+    connect_timeout_ms: u64,
 
-fn make_listener(addr: SocketAddr) -> Result<TcpListener> {
-    let domain = if addr.is_ipv4() {
-        Domain::IPV4
-    } else {
-        Domain::IPV6
-    };
+    /// Number of times to retry upstream connects failing with a transient routing error
+    #[arg(long, default_value_t = 0)]
+    connect_retries: u32,
 
-    let listen_socket = Socket::new(domain, Type::STREAM, None)?;
-    listen_socket.set_nonblocking(true)?;
-    listen_socket.set_cloexec(true)?;
-    listen_socket.set_reuse_address(true)?;
-    listen_socket.set_nodelay(true)?;
-    listen_socket.set_ip_transparent(true)?;
-    listen_socket.bind(&addr.into())?;
-    listen_socket.listen(1024)?;
+    /// Delay before the first connect retry, doubled for every next one
+    #[arg(long, default_value_t = 200)]
+    connect_retry_backoff_ms: u64,
 
-    let std_listener: StdTcpListener = listen_socket.into();
-    let listener = TcpListener::from_std(std_listener)?;
-    Ok(listener)
+    /// Don't start connect retries after this much time since the first attempt
+    #[arg(long, default_value_t = 3000)]
+    connect_retry_budget_ms: u64,
+
+    /// Maximum time to wait for connections to finish on shutdown
+    #[arg(long, default_value_t = 10000)]
+    drain_timeout_ms: u64,
+
+    /// Send the first split segment with a low TTL so that it is retransmitted after the rest
+    #[arg(long, default_value_t = false)]
+    disorder: bool,
+
+    /// TTL of the first segment with --disorder
+    #[arg(long, default_value_t = 1)]
+    disorder_ttl: u32,
+
+    /// Cut the TLS record carrying the ClientHello in two at the host name
+    #[arg(long, default_value_t = false)]
+    tlsrec: bool,
+
+    /// More positions to cut ClientHello records at, such as 50 or sni+2. TCP split positions
+    /// keep pointing at the same bytes.
+    #[arg(long, value_delimiter = ',')]
+    tlsrec_pos: Vec<Position>,
+
+    /// Rewrite the HTTP Host header name to this case, hoSt if not given
+    #[arg(long, num_args = 0..=1, default_missing_value = "hoSt", value_parser = parse_hostcase)]
+    hostcase: Option<String>,
+
+    /// Randomize the case of the HTTP Host header value
+    #[arg(long, default_value_t = false)]
+    domcase: bool,
+
+    /// Move the HTTP Host header after all others
+    #[arg(long, default_value_t = false)]
+    host_last: bool,
+
+    /// Add an X-Padding header with this many bytes of value before the HTTP Host header
+    #[arg(long, default_value_t = 0, value_parser = parse_host_padding)]
+    host_padding: usize,
+
+    /// Add a space after the HTTP request method, as in "GET  /"
+    #[arg(long, default_value_t = false)]
+    method_space: bool,
+
+    /// Separate the HTTP version from the request target with a tab
+    #[arg(long, default_value_t = false)]
+    version_tab: bool,
+
+    /// End HTTP request line and headers with LF instead of CRLF
+    #[arg(long, default_value_t = false)]
+    unixeol: bool,
+
+    /// Also apply --method-space, --version-tab and --unixeol to HTTP/1.0 requests, which old
+    /// servers may reject
+    #[arg(long, default_value_t = false)]
+    tamper_http10: bool,
+
+    /// Split and tamper with every HTTP request on a connection, not just the first one, as long
+    /// as the body lengths allow finding where each starts
+    #[arg(long, default_value_t = false)]
+    http_all_requests: bool,
+
+    /// Send a decoy ClientHello with a low TTL in place of the first segment, then the real one
+    #[arg(long, default_value_t = false)]
+    fake: bool,
+
+    /// TTL of the decoy with --fake
+    #[arg(long, default_value_t = 8)]
+    fake_ttl: u32,
+
+    /// Send the decoy this many times, cutting the segment it replaces into as many packets that
+    /// each start with it
+    #[arg(long, default_value_t = 1, value_parser = parse_fake_repeats)]
+    fake_repeats: usize,
+
+    /// Also send a few bytes of the decoy with a low TTL in place of the start of every split
+    /// segment after the first one, for DPI that reassembles across the first gap only
+    #[arg(long, default_value_t = false)]
+    fake_interleave: bool,
+
+    /// Other ways to get the decoy discarded before or by the destination
+    #[arg(long, value_enum, value_delimiter = ',')]
+    fooling: Vec<Fooling>,
+
+    /// Host name in the built-in decoys
+    #[arg(long, default_value = fake::DEFAULT_SNI, value_parser = parse_fake_sni)]
+    fake_sni: String,
+
+    /// Decoy for TLS flows with --fake instead of the built-in ClientHello
+    #[arg(long)]
+    fake_tls: Option<PathBuf>,
+
+    /// Decoy for HTTP flows with --fake instead of the built-in request
+    #[arg(long)]
+    fake_http: Option<PathBuf>,
+
+    /// Append an out-of-band byte to the first split segment, which the destination drops
+    #[arg(long, default_value_t = false)]
+    oob: bool,
+
+    /// Byte sent with --oob, as a character or a number
+    #[arg(long, default_value = "a", value_parser = parse_byte)]
+    oob_byte: u8,
+
+    /// Same as --disorder with --oob: the first segment carries the out-of-band byte and is sent
+    /// with a low TTL, so it only reaches the destination when retransmitted after the rest
+    #[arg(long, default_value_t = false)]
+    disoob: bool,
+
+    /// Derive the TTL for --fake and --disorder from the hop distance to each destination
+    #[arg(long, default_value_t = false)]
+    autottl: bool,
+
+    /// Hops before the destination where low-TTL segments should expire with --autottl
+    #[arg(long, default_value_t = 1)]
+    autottl_delta: u32,
+
+    /// Lowest TTL used with --autottl
+    #[arg(long, default_value_t = 3)]
+    autottl_min: u32,
+
+    /// Highest TTL used with --autottl
+    #[arg(long, default_value_t = 20)]
+    autottl_max: u32,
+
+    /// How long to reuse the hop distance measured for a /24 or /64
+    #[arg(long, default_value_t = 600000)]
+    autottl_cache_ms: u64,
+
+    /// Set TCP_QUICKACK on the upstream socket while sending a split ClientHello
+    #[arg(long, default_value_t = false)]
+    quickack: bool,
+
+    /// Keep TCP_NODELAY on the upstream socket after the ClientHello
+    #[arg(long, action = ArgAction::Set, default_value_t = true)]
+    nodelay_after_hello: bool,
+
+    /// Wait this long after each split segment has left, with optional jitter as in 20±10
+    #[arg(long, value_parser = parse_split_delay)]
+    split_delay: Option<SplitDelay>,
+
+    /// Clamp the receive window advertised to the destination to this many bytes, so that it
+    /// sends its first flight in small segments. Also keeps the window from scaling beyond 64 KiB
+    /// for the rest of the connection.
+    #[arg(long)]
+    wssize: Option<u32>,
+
+    /// Lift --wssize after receiving this many bytes from the destination
+    #[arg(long, default_value_t = 8192)]
+    wssize_bytes: usize,
+
+    /// Lift --wssize after this many reads from the destination, if sooner than --wssize-bytes
+    #[arg(long)]
+    wssize_reads: Option<usize>,
+
+    /// Lower the upstream MSS to this many bytes while sending the ClientHello, so that it leaves
+    /// in small segments. Kernels that only honor it when connecting get the segments cut to this
+    /// size instead.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(88..=65535))]
+    mss: Option<u32>,
+
+    /// Split every ClientHello or HTTP request among this many reads from the client, such as
+    /// when the ClientHello isn't the first thing sent, before relaying the rest as is
+    #[arg(long, default_value_t = 1, value_parser = parse_desync_repeats)]
+    desync_repeats: usize,
+
+    /// Desync ClientHellos that TLS 1.2 clients send in the clear after application data to
+    /// renegotiate, found by following the record framing of the connection. Renegotiations
+    /// within an encrypted session are relayed as is
+    #[arg(long, default_value_t = false)]
+    desync_renegotiation: bool,
+
+    /// Relay the ClientHello as is if buffering and splitting it takes longer than this
+    #[arg(long, default_value_t = 500)]
+    desync_budget_ms: u64,
+
+    /// Maximum time to wait for a split segment to leave the send queue
+    #[arg(long, default_value_t = 3000)]
+    flush_timeout_ms: u64,
+
+    /// What to wait for before writing the next split segment
+    #[arg(long, value_enum, default_value_t = FlushMode::Notsent)]
+    flush_mode: FlushMode,
+
+    /// How to make every split segment leave as its own packet
+    #[arg(long, value_enum, default_value_t = Segmentation::Flush)]
+    segmentation: Segmentation,
+
+    /// Round trip time above which --flush-mode acked waits as notsent, since every split adds
+    /// one
+    #[arg(long, default_value_t = 150)]
+    flush_acked_max_rtt_ms: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct SplitDelay {
+    ms: u64,
+    jitter_ms: u64,
 }
 
-fn get_tcp_info(fd: i32) -> Result<tcp_info> {
-    unsafe {
-        let mut payload: MaybeUninit<tcp_info> = MaybeUninit::uninit();
-        let mut len = size_of::<tcp_info>() as libc::socklen_t;
+impl SplitDelay {
+    fn sample(self) -> time::Duration {
+        let min = self.ms.saturating_sub(self.jitter_ms) as usize;
+        let max = (self.ms + self.jitter_ms) as usize;
+        time::Duration::from_millis(rng::in_range(min..=max) as u64)
+    }
+}
 
-        if libc::getsockopt(
-            fd,
-            libc::IPPROTO_TCP,
-            libc::TCP_INFO,
-            payload.as_mut_ptr().cast(),
-            &mut len,
-        ) == 0
-        {
-            let payload = payload.assume_init();
-            Ok(payload)
-        } else {
-            Err(anyhow!("getsockopt failed"))
+impl fmt::Display for SplitDelay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.ms)?;
+        if self.jitter_ms > 0 {
+            write!(f, "±{}", self.jitter_ms)?;
         }
+        Ok(())
     }
 }
 
-async fn really_flush(writer: &mut OwnedWriteHalf, fd: i32) -> Result<()> {
-    writer.flush().await?;
+impl Args {
+    fn strategy(&self) -> &Strategy {
+        self.strategy.as_ref().expect("filled in at startup")
+    }
+
+    /// Fills in the strategy from --preset or the individual desync flags unless --strategy was
+    /// given. --split-delay alone delays the splits of the preset rather than overriding it.
+    fn fill_strategy(&mut self) {
+        if self.strategy.is_some() {
+            if let Some(name) = &self.preset {
+                eprintln!("preset {name} is overridden by --strategy");
+            }
+            return;
+        }
+        let strategy = Strategy::from_args(self);
+        let delays_only = strategy
+            .steps
+            .iter()
+            .all(|step| matches!(step, Step::Delay(_)));
+        self.strategy = Some(match &self.preset {
+            Some(name) if delays_only => {
+                println!("preset: {name}");
+                let mut preset = Strategy::preset(name);
+                preset.steps.extend(strategy.steps);
+                preset
+            }
+            Some(name) => {
+                eprintln!("preset {name} is overridden by explicit desync flags");
+                strategy
+            }
+            None => strategy,
+        });
+    }
 
-    let mut timeout = 1;
-    while get_tcp_info(fd)?.tcpi_notsent_bytes > 0 {
-        time::sleep(time::Duration::from_millis(timeout)).await;
-        if timeout < 8 {
-            timeout *= 2;
+    /// Returns the settings for connections accepted on `addr`, with its --listener-override.
+    fn for_listener(&self, addr: SocketAddr) -> Args {
+        let mut args = self.clone();
+        let Some(entry) = self
+            .listener_override
+            .iter()
+            .find(|entry| entry.addr == addr)
+        else {
+            return args;
+        };
+        if let Some(hostlist) = &entry.hostlist {
+            args.hostlist.clone_from(hostlist);
+        }
+        if let Some(hostlist_exclude) = &entry.hostlist_exclude {
+            args.hostlist_exclude.clone_from(hostlist_exclude);
+        }
+        if let Some(desync_ports) = &entry.desync_ports {
+            args.desync_ports.clone_from(desync_ports);
         }
+        if let Some(no_sni_policy) = &entry.no_sni_policy {
+            args.no_sni_policy = no_sni_policy.clone();
+        }
+        args
     }
 
-    Ok(())
+    /// Describes the settings --listener-override can replace.
+    fn listener_settings(&self) -> String {
+        let path = |path: &Option<PathBuf>| match path {
+            Some(path) => path.display().to_string(),
+            None => "none".to_owned(),
+        };
+        let desync_ports: Vec<_> = self
+            .desync_ports
+            .iter()
+            .map(DesyncPort::to_string)
+            .collect();
+        format!(
+            "hostlist {}, hostlist exclude {}, desync ports {}, no-sni policy {}",
+            path(&self.hostlist),
+            path(&self.hostlist_exclude),
+            desync_ports.join(","),
+            self.no_sni_policy,
+        )
+    }
+
+    /// Returns the --port-strategy rule for connections to `port`.
+    fn port_strategy(&self, port: u16) -> Option<&PortStrategy> {
+        self.port_strategy.iter().find(|rule| rule.port == port)
+    }
+
+    /// Returns true if connections to `port` may be desynced, for listed in --desync-ports,
+    /// --port-strategy or --starttls-ports.
+    fn desyncs_port(&self, port: u16) -> bool {
+        self.desync_ports
+            .iter()
+            .any(|entry| matches!(entry, DesyncPort::Any) || *entry == DesyncPort::Port(port))
+            || self.port_strategy(port).is_some()
+            || self.starttls_ports.contains(&port)
+    }
+
+    /// Returns true if connections to `port` are relayed as is, for missing from
+    /// --port-strategy.
+    fn passes_through(&self, port: u16) -> bool {
+        self.port_strategy_fallback == PortFallback::Passthrough
+            && !self.port_strategy.is_empty()
+            && self.port_strategy(port).is_none()
+    }
 }
 
-async fn client_to_server(
-    mut reader: OwnedReadHalf,
-    mut writer: OwnedWriteHalf,
-    fd: i32,
-    args: Arc<Args>,
-) -> Result<()> {
-    let mut buf = vec![0u8; 8192];
-    let mut split_positions = Vec::with_capacity(8);
+fn parse_split_position(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("split position must be greater than 0".to_owned()),
+        Ok(pos) => Ok(pos),
+        Err(err) => Err(err.to_string()),
+    }
+}
 
-    loop {
-        split_positions.clear();
+fn parse_relative_split_position(s: &str) -> Result<Position, String> {
+    let pos: Position = s.parse()?;
+    if pos.absolute() == Some(0) {
+        return Err("split position must be greater than 0".to_owned());
+    }
+    Ok(pos)
+}
 
-        let Ok(read_bytes) = reader.read(&mut buf).await else {
-            break;
-        };
-        if read_bytes == 0 {
-            break;
-        }
+fn parse_split_range(s: &str) -> Result<(usize, usize), String> {
+    let (min, max) = s.split_once(':').ok_or("expected MIN:MAX")?;
+    let (min, max) = (parse_split_position(min)?, parse_split_position(max)?);
+    if min > max {
+        return Err(format!("{min} is greater than {max}"));
+    }
+    Ok((min, max))
+}
 
-        if let Ok((_, record)) = parse_tls_plaintext(&buf) {
-            for msg in record.msg {
-                if let TlsMessage::Handshake(TlsMessageHandshake::ClientHello(ch)) = msg {
-                    if let Some(exts) = ch.ext {
-                        if let Ok((_, exts)) = parse_tls_client_hello_extensions(exts) {
-                            for ext in exts {
-                                if let TlsExtension::SNI(snis) = ext {
-                                    for (sni_type, sni_data) in snis {
-                                        if sni_type == SNIType::HostName {
-                                            for pos in &args.split_positions {
-                                                if *pos < read_bytes {
-                                                    split_positions.push(*pos);
-                                                }
-                                            }
-
-                                            if args.split_host {
-                                                let start_of_hostname = sni_data.as_ptr() as usize
-                                                    - buf.as_ptr() as usize;
-                                                if sni_data.len() >= 2 {
-                                                    split_positions.push(start_of_hostname + 1);
-                                                } else {
-                                                    split_positions.push(start_of_hostname);
-                                                };
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+fn parse_split_delay(s: &str) -> Result<SplitDelay, String> {
+    let (ms, jitter_ms) = s
+        .split_once('±')
+        .or_else(|| s.split_once("+-"))
+        .unwrap_or((s, "0"));
+    Ok(SplitDelay {
+        ms: ms
+            .parse()
+            .map_err(|err| format!("bad delay {ms:?}: {err}"))?,
+        jitter_ms: jitter_ms
+            .parse()
+            .map_err(|err| format!("bad jitter {jitter_ms:?}: {err}"))?,
+    })
+}
 
-        split_positions.push(read_bytes);
-        split_positions.sort_unstable();
-        split_positions.dedup();
+fn parse_byte(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [byte] if !byte.is_ascii_digit() => Ok(*byte),
+        _ => s
+            .parse()
+            .map_err(|err| format!("expected a character or 0-255: {err}")),
+    }
+}
 
-        let mut start_byte = 0usize;
-        for split_at in &split_positions {
-            writer.write_all(&buf[start_byte..*split_at]).await?;
-            if split_positions.len() > 1 && *split_at != read_bytes {
-                really_flush(&mut writer, fd).await?;
-            }
-            start_byte = *split_at;
-        }
+fn parse_fake_sni(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > 253 || !s.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err("expected a host name of up to 253 printable characters".to_owned());
     }
+    Ok(s.to_owned())
+}
 
-    writer.shutdown().await?;
-    Ok(())
+fn parse_hostcase(s: &str) -> Result<String, String> {
+    if !s.eq_ignore_ascii_case("host") {
+        return Err("expected \"host\" in any case, like hoSt".to_owned());
+    }
+    Ok(s.to_owned())
 }
 
-async fn server_to_client(mut reader: OwnedReadHalf, mut writer: OwnedWriteHalf) -> Result<()> {
-    tokio::io::copy(&mut reader, &mut writer).await?;
-    Ok(())
+fn parse_country(s: &str) -> Result<String, String> {
+    if s.len() != 2 || !s.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err("expected a two-letter country code, like RU".to_owned());
+    }
+    Ok(s.to_ascii_uppercase())
 }
 
-async fn handle_client(
-    client_stream: TcpStream,
-    client_addr: SocketAddr,
-    args: Arc<Args>,
-) -> Result<()> {
-    client_stream.set_nodelay(true)?;
-    let (client_stream, original_dst) = get_original_dst(client_stream)?;
-    eprintln!("{client_addr} -> {original_dst}");
+fn parse_desync_repeats(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(repeats) if (1..=MAX_DESYNC_REPEATS).contains(&repeats) => Ok(repeats),
+        Ok(_) => Err(format!("expected 1 to {MAX_DESYNC_REPEATS}")),
+        Err(err) => Err(err.to_string()),
+    }
+}
 
-    let dst_domain = if original_dst.is_ipv4() {
-        Domain::IPV4
-    } else {
-        Domain::IPV6
-    };
-    let dst_socket = Socket::new(dst_domain, Type::STREAM, None)?;
-    dst_socket.set_nonblocking(true)?;
-    dst_socket.set_cloexec(true)?;
-    dst_socket.set_reuse_address(true)?;
-    dst_socket.set_nodelay(true)?;
-    if args.fwmark != 0 {
-        dst_socket.set_mark(args.fwmark)?;
+fn parse_desync_port(s: &str) -> Result<DesyncPort, String> {
+    match s {
+        "any" => Ok(DesyncPort::Any),
+        s => match s.parse() {
+            Ok(port) => Ok(DesyncPort::Port(port)),
+            Err(err) => Err(err.to_string()),
+        },
     }
-    dst_socket.connect(&original_dst.into()).ok();
-    let std_stream: StdTcpStream = dst_socket.into();
-    let server_stream = TcpStream::from_std(std_stream)?;
+}
 
-    let server_fd = server_stream.as_raw_fd();
+fn parse_auto_hostlist_fails(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(0) => Err("expected at least 1".to_owned()),
+        Ok(fails) => Ok(fails),
+        Err(err) => Err(err.to_string()),
+    }
+}
 
-    let (client_reader, client_writer) = client_stream.into_split();
-    let (server_reader, server_writer) = server_stream.into_split();
+fn parse_ipfrag_offset(s: &str) -> Result<usize, String> {
+    // Client Initial datagrams are at least 1200 bytes.
+    match s.parse() {
+        Ok(offset) if offset > 0 && offset < 1200 && offset % 8 == 0 => Ok(offset),
+        Ok(_) => Err("expected a multiple of 8 below 1200".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
 
-    tokio::spawn(client_to_server(
-        client_reader,
-        server_writer,
-        server_fd,
-        Arc::clone(&args),
-    ));
-    tokio::spawn(server_to_client(server_reader, client_writer));
+fn parse_fake_repeats(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(repeats) if (1..=MAX_FAKE_REPEATS).contains(&repeats) => Ok(repeats),
+        Ok(_) => Err(format!("expected 1 to {MAX_FAKE_REPEATS}")),
+        Err(err) => Err(err.to_string()),
+    }
+}
 
-    Ok(())
+fn parse_host_padding(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(len) if len > http::MAX_HEADER_LEN => Err(format!(
+            "padding must be at most {} bytes",
+            http::MAX_HEADER_LEN
+        )),
+        Ok(len) => Ok(len),
+        Err(err) => Err(err.to_string()),
+    }
 }
 
-fn get_original_dst(stream: TcpStream) -> Result<(TcpStream, SocketAddr)> {
-    let std_stream = stream.into_std()?;
-    let socket2_socket = Socket::from(std_stream);
-    let original_dst = socket2_socket
-        .original_dst()?
-        .as_socket()
-        .context("socket is not inet")?;
-    let std_stream: StdTcpStream = socket2_socket.into();
-    let stream = TcpStream::from_std(std_stream)?;
-    Ok((stream, original_dst))
+fn parse_interface(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|err| err.to_string())?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(format!("no interface named {name}")),
+        index => Ok(index),
+    }
+}
+
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    args.fill_strategy();
+    VERBOSE.store(args.verbose, Ordering::Relaxed);
+    rng::seed(args.split_random_seed);
+
+    let runtime = if args.threads == 1 {
+        println!("runtime: current-thread");
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?
+    } else {
+        let workers = if args.threads == 0 {
+            std::thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            args.threads
+        };
+        println!("runtime: multi-thread, {workers} workers");
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()?
+    };
+
+    runtime.block_on(_main(args))
+}
+
+/// Opens the raw sockets if some strategy injects decoys with them, or for --quic-ipfrag.
+fn start_raw(args: &Args) {
+    if strategy_map::any(args, Strategy::injects) {
+        #[cfg(feature = "raw")]
+        let started = raw::start(args);
+        #[cfg(not(feature = "raw"))]
+        let started = Err::<(), _>("adpi was built without the raw feature");
+        if let Err(err) = started {
+            eprintln!("cannot inject fakes, skipping injected decoys: {err}");
+        }
+    }
+    if args.quic_ipfrag.is_some() {
+        #[cfg(feature = "raw")]
+        let started = raw::start_fragmenter(args);
+        #[cfg(not(feature = "raw"))]
+        let started = Err::<(), _>("adpi was built without the raw feature");
+        if let Err(err) = started {
+            eprintln!("cannot fragment QUIC Initials, sending them whole: {err}");
+        }
+    }
+}
+
+async fn _main(args: Args) -> Result<()> {
+    let args = Arc::new(args);
+    self_check(&args);
+    for (i, entry) in args.listener_override.iter().enumerate() {
+        if !args.listen_address.contains(&entry.addr) {
+            return Err(anyhow!(
+                "--listener-override for {}, which is not a --listen-address",
+                entry.addr
+            ));
+        }
+        if args.listener_override[..i]
+            .iter()
+            .any(|other| other.addr == entry.addr)
+        {
+            return Err(anyhow!(
+                "more than one --listener-override for {}",
+                entry.addr
+            ));
+        }
+    }
+
+    if !args.strategy().steps.is_empty() {
+        println!("strategy: {}", args.strategy());
+    }
+    for rule in &args.port_strategy {
+        println!("strategy for port {}: {}", rule.port, rule.strategy);
+    }
+    strategy_map::reload(&args)?;
+    hostlist::reload(&args)?;
+    dstlist::reload(&args)?;
+    geoip::reload(&args);
+    autohostlist::load(&args)?;
+    watch::start(&args)?;
+    nftset::start(&args)?;
+    hostlist_url::start(&args);
+    start_raw(&args);
+    fake::reload(&args)?;
+    retry::load(&args)?;
+
+    let mut listeners = Vec::new();
+    for addr in &args.listen_address {
+        let listener = match make_listener(*addr, args.ip_transparent) {
+            Ok(listener) => listener,
+            Err(err) if !args.bind_strict => {
+                eprintln!("cannot listen on {addr}, skipping: {err:#}");
+                continue;
+            }
+            Err(err) => return Err(err.context(format!("cannot listen on {addr}"))),
+        };
+        let listener_args = Arc::new(args.for_listener(*addr));
+        if args.listener_override.is_empty() {
+            println!("listening on {addr}");
+        } else {
+            println!("listening on {addr}: {}", listener_args.listener_settings());
+        }
+
+        let args = listener_args;
+        let addr = *addr;
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = accept_loop(listener, args).await {
+                eprintln!("listener {addr} stopped: {err}");
+            }
+        }));
+    }
+
+    for addr in &args.quic_listen_address {
+        let socket = match udp::make_relay_socket(*addr, args.ip_transparent) {
+            Ok(socket) => socket,
+            Err(err) if !args.bind_strict => {
+                eprintln!("cannot relay QUIC on {addr}, skipping: {err:#}");
+                continue;
+            }
+            Err(err) => return Err(err.context(format!("cannot relay QUIC on {addr}"))),
+        };
+        println!("relaying QUIC on {addr}");
+
+        let args = Arc::clone(&args);
+        let addr = *addr;
+        listeners.push(tokio::spawn(async move {
+            if let Err(err) = udp::relay_loop(socket, args).await {
+                eprintln!("QUIC relay {addr} stopped: {err}");
+            }
+        }));
+    }
+
+    if listeners.is_empty() {
+        return Err(anyhow!("no listeners could be created"));
+    }
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let usr1_args = Arc::clone(&args);
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            eprintln!("stats: {}", STATS.report());
+            if !usr1_args.strategy_fallback.is_empty() {
+                eprintln!("{}", retry::report(&usr1_args));
+            }
+        }
+    });
+
+    let mut hup = signal(SignalKind::hangup())?;
+    let hup_args = Arc::clone(&args);
+    tokio::spawn(async move {
+        while hup.recv().await.is_some() {
+            if let Err(err) = strategy_map::reload(&hup_args) {
+                eprintln!("cannot reload strategy map: {err:#}");
+            }
+            if let Err(err) = hostlist::reload(&hup_args) {
+                eprintln!("cannot reload hostlist: {err:#}");
+            }
+            if let Err(err) = dstlist::reload(&hup_args) {
+                eprintln!("cannot reload destination prefixes: {err:#}");
+            }
+            geoip::reload(&hup_args);
+            start_raw(&hup_args);
+            if let Err(err) = fake::reload(&hup_args) {
+                eprintln!("cannot reload fake payloads: {err:#}");
+            }
+        }
+    });
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
+    }
+
+    for listener in &listeners {
+        listener.abort();
+    }
+    let in_flight = STATS.active_connections.get();
+    println!("shutting down, draining {in_flight} connections");
+
+    let drain = async {
+        while STATS.active_connections.get() > 0 {
+            time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    };
+    tokio::select! {
+        _ = time::timeout(time::Duration::from_millis(args.drain_timeout_ms), drain) => {}
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
+    }
+
+    let aborted = STATS.active_connections.get();
+    println!(
+        "drained {} connections, aborted {aborted}",
+        in_flight.saturating_sub(aborted)
+    );
+    if let Err(err) = retry::save(&args) {
+        eprintln!("cannot save strategy cache: {err:#}");
+    }
+    Ok(())
+}
+
+/// Reports features degraded by missing privileges.
+fn self_check(args: &Args) {
+    let Ok(probe) = Socket::new(Domain::IPV4, Type::STREAM, None) else {
+        return;
+    };
+    if args.ip_transparent != Transparent::Never {
+        if let Err(err) = probe.set_ip_transparent(true) {
+            eprintln!(
+                "self-check: IP_TRANSPARENT unavailable ({err}), TPROXY interception won't work"
+            );
+        }
+    }
+    if args.fwmark != 0 {
+        if let Err(err) = probe.set_mark(args.fwmark) {
+            eprintln!(
+                "self-check: SO_MARK unavailable ({err}), upstream connects will fail; \
+                 use --fwmark 0"
+            );
+        }
+    }
+}
+
+async fn accept_loop(listener: TcpListener, args: Arc<Args>) -> io::Result<()> {
+    // Accepted connections only carry the mark of the client's SYN under TPROXY or with
+    // net.ipv4.tcp_fwmark_accept=1.
+    let marked = SockRef::from(&listener).ip_transparent().unwrap_or(true)
+        || fs::read_to_string("/proc/sys/net/ipv4/tcp_fwmark_accept")
+            .map_or(true, |value| value.trim() != "0");
+    let handle = |client_stream, client_addr| {
+        STATS.connections.incr();
+        let args = Arc::clone(&args);
+        let active = ActiveGuard::new();
+        tokio::spawn(async move {
+            let _active = active;
+            let handled = handle_client(client_stream, client_addr, marked, args).await;
+            if let Err(err) = handled {
+                STATS.setup_errors.incr();
+                eprintln!("{client_addr}: {err:#}");
+            }
+        });
+    };
+    accept_connections(|| listener.accept(), handle).await
+}
+
+/// Hands the connections `accept` returns to `handle`, backing off after transient errors such
+/// as running out of file descriptors, until another error.
+async fn accept_connections<S, F>(
+    mut accept: impl FnMut() -> F,
+    mut handle: impl FnMut(S, SocketAddr),
+) -> io::Result<()>
+where
+    F: Future<Output = io::Result<(S, SocketAddr)>>,
+{
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+    let mut last_warning: Option<time::Instant> = None;
+    let mut suppressed = 0u64;
+
+    loop {
+        match accept().await {
+            Ok((client_stream, client_addr)) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                handle(client_stream, client_addr);
+            }
+            Err(err) if is_transient_accept_error(&err) => {
+                STATS.accept_errors.incr();
+                if last_warning.is_none_or(|at| at.elapsed() >= ACCEPT_WARNING_INTERVAL) {
+                    eprintln!("accept failed: {err} ({suppressed} similar errors suppressed)");
+                    last_warning = Some(time::Instant::now());
+                    suppressed = 0;
+                } else {
+                    suppressed += 1;
+                }
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn is_transient_accept_error(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(
+            libc::EMFILE
+                | libc::ENFILE
+                | libc::ENOBUFS
+                | libc::ENOMEM
+                | libc::ECONNABORTED
+                | libc::ECONNRESET
+                | libc::EPROTO
+                | libc::EINTR
+        )
+    )
+}
+
+fn make_listener(addr: SocketAddr, transparent: Transparent) -> Result<TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+
+    let listen_socket = Socket::new(domain, Type::STREAM, None)?;
+    listen_socket.set_nonblocking(true)?;
+    listen_socket.set_cloexec(true)?;
+    listen_socket.set_reuse_address(true)?;
+    listen_socket.set_nodelay(true)?;
+    match transparent {
+        Transparent::Auto => {
+            if let Err(err) = listen_socket.set_ip_transparent(true) {
+                eprintln!("listener {addr}: cannot set IP_TRANSPARENT, continuing without: {err}");
+            }
+        }
+        Transparent::Always => listen_socket
+            .set_ip_transparent(true)
+            .context("cannot set IP_TRANSPARENT")?,
+        Transparent::Never => {}
+    }
+    listen_socket.bind(&addr.into())?;
+    listen_socket.listen(1024)?;
+
+    let std_listener: StdTcpListener = listen_socket.into();
+    let listener = TcpListener::from_std(std_listener)?;
+    Ok(listener)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    /// Waiting for the server to accept a STARTTLS command.
+    Plaintext,
+    /// Waiting for the first client payload, which may be a ClientHello.
+    Hello,
+    /// Relaying client data unchanged.
+    Relay,
+    /// Relaying this many bytes of an HTTP request body before the next request.
+    Body(usize),
+}
+
+/// Watches a first payload to `host` relayed as is for --auto-hostlist.
+fn learn(untouched: bool, host: Option<&str>, attempt: Option<&Arc<Attempt>>, args: &Args) {
+    if let (true, Some(host), Some(attempt)) = (untouched, host, attempt) {
+        if hostlist::learns(host, args) {
+            attempt.learn(host);
+        }
+    }
+}
+
+/// Adds `dst` to --nft-set if `host` is desynced for being listed.
+fn export(untouched: bool, host: Option<&str>, dst: SocketAddr, args: &Args) {
+    if let (false, Some(host)) = (untouched, host) {
+        if hostlist::listed(host, args) {
+            nftset::add(dst.ip(), host, args);
+        }
+    }
+}
+
+/// Returns the strategy for connections to `port`, noting the --port-strategy rule in `log`.
+fn port_default_strategy<'a>(port: u16, log: &mut String, args: &'a Args) -> Result<&'a Strategy> {
+    match args.port_strategy(port) {
+        Some(rule) => {
+            write!(log, " (strategy {} for port {port})", rule.strategy)?;
+            Ok(&rule.strategy)
+        }
+        None => Ok(args.strategy()),
+    }
+}
+
+/// Returns the strategy for `host` from `map`, or the default one. The choice is added to `log`
+/// if there is a map.
+fn pick_strategy<'a>(
+    map: Option<&'a StrategyMap>,
+    host: Option<&tls::Host>,
+    port: u16,
+    log: &mut String,
+    args: &'a Args,
+) -> Result<&'a Strategy> {
+    let Some(map) = map else {
+        return port_default_strategy(port, log, args);
+    };
+    match host.and_then(|host| map.lookup(&host.name)) {
+        Some((pattern, strategy)) => {
+            write!(log, " (strategy {strategy} for {pattern})")?;
+            Ok(strategy)
+        }
+        None if args.port_strategy(port).is_some() => port_default_strategy(port, log, args),
+        None => {
+            write!(log, " (default strategy {})", args.strategy())?;
+            Ok(args.strategy())
+        }
+    }
+}
+
+/// Resolves the steps of `strategy` into `plan`, against `parsed` if the payload was parsed.
+/// Steps that need a host name are skipped without one, and only the techniques are kept without
+/// a parsed payload.
+fn plan_splits<P: Landmarks>(
+    strategy: &Strategy,
+    parsed: Option<&P>,
+    plan: &mut Plan,
+    log: &mut String,
+    conn: &str,
+    args: &Args,
+) -> Result<()> {
+    let ip_literal = |host: &tls::Host| host.name.parse::<IpAddr>().is_ok();
+    let parsed = match parsed {
+        Some(parsed) if args.no_split_ip_sni && parsed.host().is_some_and(ip_literal) => {
+            log.push_str(" (IP address, not splitting)");
+            None
+        }
+        parsed => parsed,
+    };
+
+    for step in &strategy.steps {
+        let parsed = match (step, parsed) {
+            (Step::Split(_) | Step::Tlsrec(_), Some(parsed)) => parsed,
+            (Step::Split(_) | Step::Tlsrec(_) | Step::Delay(_), _) => continue,
+            (Step::FakeRecord(record), _) => {
+                if P::RECORDS {
+                    let kind = if record.ccs {
+                        "change_cipher_spec"
+                    } else {
+                        "alert"
+                    };
+                    write!(log, " (fake {kind} record first)")?;
+                    plan.fake_record = Some(*record);
+                }
+                continue;
+            }
+            (technique, _) => {
+                plan.techniques
+                    .push((plan.split_positions.len(), *technique));
+                continue;
+            }
+        };
+        let len = parsed.end();
+        let host = match (step, parsed.host()) {
+            (Step::Split(SplitAt::Host | SplitAt::Midsld | SplitAt::Labels), None)
+            | (Step::Tlsrec(None), None) => {
+                debug!("{conn}: no host name for {step}");
+                continue;
+            }
+            (_, host) => host,
+        };
+        let host_pos = host.map(|host| {
+            if host.range.len() >= 2 {
+                host.range.start + 1
+            } else {
+                host.range.start
+            }
+        });
+
+        match (step, host, host_pos) {
+            (Step::Split(SplitAt::Position(pos)), _, _) => match pos.resolve(parsed) {
+                Some(at) if at < len => plan.split_positions.push(at),
+                Some(_) => debug!(
+                    "{conn}: split position {pos} is beyond the {} ({len} bytes)",
+                    P::NAME
+                ),
+                None => debug!("{conn}: cannot resolve split position {pos}"),
+            },
+            (Step::Split(SplitAt::Random(min, max)), _, _) => {
+                if *min < len {
+                    let pos = rng::in_range(*min..=(*max).min(len - 1));
+                    write!(log, " (random split at {pos})")?;
+                    plan.split_positions.push(pos);
+                } else {
+                    debug!(
+                        "{conn}: random split range starts beyond the {} ({len} bytes)",
+                        P::NAME
+                    );
+                }
+            }
+            (Step::Split(SplitAt::Every(every)), _, _) => {
+                let every = (*every).max(len.div_ceil(MAX_SPLIT_EVERY_SEGMENTS));
+                plan.split_positions.extend((every..len).step_by(every));
+            }
+            (Step::Split(SplitAt::Host), Some(host), _) if ip_literal(host) => {
+                log.push_str(" (IP address, not splitting at host)");
+            }
+            (Step::Split(SplitAt::Host), _, Some(host_pos)) if host_pos < len => {
+                plan.split_positions.push(host_pos)
+            }
+            (Step::Split(SplitAt::Midsld), Some(host), Some(host_pos)) => {
+                let midsld = position::midsld(&host.name).filter(|_| !ip_literal(host));
+                let pos = midsld.map_or(host_pos, |offset| host.range.start + offset);
+                if pos < len {
+                    plan.split_positions.push(pos);
+                }
+            }
+            (Step::Split(SplitAt::Labels), Some(host), _) if ip_literal(host) => {
+                log.push_str(" (IP address, not splitting at labels)");
+            }
+            (Step::Split(SplitAt::Labels), Some(host), _) => {
+                let name = host.name.strip_suffix('.').unwrap_or(&host.name);
+                let dots = name.match_indices('.').take(MAX_LABEL_SPLITS);
+                let labels = dots.map(|(i, _)| host.range.start + i + 1);
+                plan.split_positions.extend(labels.filter(|pos| *pos < len));
+            }
+            (Step::Tlsrec(_), _, _) if !P::RECORDS => {}
+            (Step::Tlsrec(None), Some(host), Some(host_pos)) if !ip_literal(host) => {
+                plan.record_cuts.push(host_pos)
+            }
+            (Step::Tlsrec(Some(pos)), _, _) => match pos.resolve(parsed) {
+                Some(cut) if cut < len => plan.record_cuts.push(cut),
+                _ => debug!("{conn}: record cut {pos} is not within the ClientHello"),
+            },
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Applies the HTTP tampering options to `request`, parsed from `data`. Returns the request as
+/// reparsed after rewriting it, and the rewritten bytes if its length changed.
+fn tamper_http(
+    data: &mut [u8],
+    mut request: http::Request,
+    log: &mut String,
+    conn: &str,
+    args: &Args,
+) -> Result<(http::Request, Option<Vec<u8>>)> {
+    http::set_host_case(data, &request, args.hostcase.as_deref(), args.domcase);
+    if let (Some(name), Some(_)) = (&args.hostcase, request.host_header) {
+        write!(log, " (header {name})")?;
+    }
+    if args.domcase && request.host.is_some() {
+        log.push_str(" (random case)");
+    }
+
+    let mut rewritten = None;
+    if args.host_last || args.host_padding > 0 {
+        let reordered = http::reorder_headers(data, &request, args.host_last, args.host_padding);
+        match reordered.as_deref().and_then(http::parse_request) {
+            Some(reordered_request) => {
+                request = reordered_request;
+                rewritten = reordered;
+                if args.host_last {
+                    log.push_str(" (host last)");
+                }
+                if args.host_padding > 0 {
+                    write!(log, " (padded by {} bytes)", args.host_padding)?;
+                }
+            }
+            None => debug!("{conn}: unusual headers, not reordering them"),
+        }
+    }
+
+    if args.method_space || args.version_tab || args.unixeol {
+        if request.http10 && !args.tamper_http10 {
+            debug!("{conn}: HTTP/1.0 request, not mangling it");
+            return Ok((request, rewritten));
+        }
+        let mangled = http::mangle_request_line(
+            rewritten.as_deref().unwrap_or(data),
+            &request,
+            args.method_space,
+            args.version_tab,
+            args.unixeol,
+        );
+        if let Some(mangled_request) = http::parse_request(&mangled) {
+            request = mangled_request;
+            rewritten = Some(mangled);
+            log.push_str(" (request line mangled)");
+        }
+    }
+    Ok((request, rewritten))
+}
+
+/// Returns true if a ClientHello offering the `alpn` protocols is to be desynced with
+/// --alpn-filter.
+fn alpn_targets(alpn: &[String], conn: &str, args: &Args) -> bool {
+    if args.alpn_filter.is_empty() {
+        return true;
+    }
+    if alpn.is_empty() {
+        debug!("{conn}: no alpn offered");
+        return args.alpn_filter_no_alpn;
+    }
+    let targets = alpn
+        .iter()
+        .any(|protocol| args.alpn_filter.contains(protocol));
+    if !targets {
+        debug!("{conn}: alpn {} not in --alpn-filter", alpn.join(","));
+    }
+    targets
+}
+
+/// Returns true if --no-sni-policy relays a connection without a host name as is.
+fn skip_without_sni(skipped: &mut &str, args: &Args) -> bool {
+    let skip = matches!(args.no_sni_policy, NoSniPolicy::Passthrough);
+    if skip {
+        STATS.no_sni_passthroughs.incr();
+        *skipped = "skipped by --no-sni-policy";
+    }
+    skip
+}
+
+/// Returns true if connections to `dst` are relayed as is, whatever their payload. Notes the
+/// country of `dst` in `log` otherwise.
+fn relays_as_is(dst: SocketAddr, log: &mut String, conn: &str, args: &Args) -> bool {
+    if !args.desync_private && is_private(dst.ip()) {
+        STATS.private_destinations.incr();
+        debug!("{conn}: private destination, relaying as is");
+        return true;
+    }
+    if !args.desyncs_port(dst.port()) {
+        debug!("{conn}: port not in desync set, relaying as is");
+        return true;
+    }
+    if args.passes_through(dst.port()) {
+        debug!("{conn}: port not in --port-strategy, relaying as is");
+        return true;
+    }
+    !dstlist::targets(dst.ip(), conn) || !geoip::targets(dst.ip(), log, conn, args)
+}
+
+async fn client_to_server(
+    mut reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    conn: String,
+    starttls: Option<Arc<StartTls>>,
+    low_ttl: Option<u32>,
+    attempt: Option<Arc<Attempt>>,
+    args: Arc<Args>,
+) -> Result<()> {
+    let dst = writer.as_ref().peer_addr()?;
+    let port = dst.port();
+    let mut buf = vec![0u8; 8192];
+    let desync_budget = time::Duration::from_millis(args.desync_budget_ms);
+    let mut plan = Plan::default();
+    let mut desyncs = 0;
+    let mut nodelay = true;
+    let mut records: Option<tls::RecordTracker> = None;
+    let mut country = String::new();
+    let mut phase = if relays_as_is(dst, &mut country, &conn, &args) {
+        Phase::Relay
+    } else if starttls.is_some() {
+        Phase::Plaintext
+    } else {
+        Phase::Hello
+    };
+
+    loop {
+        plan.clear();
+
+        let Ok(mut read_bytes) = reader.read(&mut buf).await else {
+            break;
+        };
+        if read_bytes == 0 {
+            if let Some(attempt) = &attempt {
+                attempt.client_closed();
+            }
+            break;
+        }
+
+        let mut renegotiating = false;
+        if let (Phase::Relay, Some(records)) = (phase, &mut records) {
+            if let Some(start) = records.feed(&buf[..read_bytes]) {
+                STATS.renegotiations.incr();
+                debug!("{conn}: handshake record after application data");
+                writer.write_all(&buf[..start]).await?;
+                buf.copy_within(start..read_bytes, 0);
+                read_bytes -= start;
+                if !nodelay {
+                    writer.as_ref().set_nodelay(true)?;
+                    nodelay = true;
+                }
+                renegotiating = true;
+                phase = Phase::Hello;
+            }
+        }
+        if let (Phase::Plaintext, Some(starttls)) = (phase, &starttls) {
+            if starttls.is_accepted() {
+                phase = Phase::Hello;
+            } else {
+                starttls.client_data(&buf[..read_bytes]);
+            }
+        }
+        if let Phase::Body(left) = phase {
+            phase = if left == 0 {
+                Phase::Hello
+            } else if read_bytes <= left {
+                Phase::Body(left - read_bytes)
+            } else {
+                // A pipelined request starts within this read.
+                Phase::Relay
+            };
+        }
+        if phase != Phase::Hello {
+            if phase == Phase::Relay && nodelay && !args.nodelay_after_hello {
+                writer.as_ref().set_nodelay(false)?;
+                nodelay = false;
+            }
+            writer.write_all(&buf[..read_bytes]).await?;
+            continue;
+        }
+        desyncs += 1;
+        phase = if desyncs < args.desync_repeats {
+            Phase::Hello
+        } else {
+            Phase::Relay
+        };
+
+        let decoys = fake::decoys();
+        let mut decoy = &decoys.tls;
+        let h2c = http::is_h2c_preface(&buf[..read_bytes]);
+        if h2c {
+            STATS.h2c_prefaces.incr();
+            if args.h2c_split_positions.is_empty() {
+                writer.write_all(&buf[..read_bytes]).await?;
+                continue;
+            }
+            decoy = &decoys.http;
+        }
+        let http = !h2c && !tls::is_handshake(&buf[..read_bytes]);
+        if http {
+            STATS.non_tls.incr();
+            if !http::is_request(&buf[..read_bytes]) {
+                writer.write_all(&buf[..read_bytes]).await?;
+                continue;
+            }
+            STATS.http_requests.incr();
+            decoy = &decoys.http;
+        }
+
+        let pending: fn(&[u8]) -> Option<usize> = if h2c {
+            http::pending_h2c_preface
+        } else if http {
+            http::pending_request
+        } else {
+            tls::pending_client_hello
+        };
+        let what = if h2c {
+            "h2c preface"
+        } else if http {
+            http::Request::NAME
+        } else {
+            tls::ClientHello::NAME
+        };
+        let deadline = time::Instant::now() + desync_budget;
+        let tracked = read_bytes;
+        while let Some(needed_len) = pending(&buf[..read_bytes]) {
+            if buf.len() < needed_len {
+                buf.resize(needed_len, 0);
+            }
+            match time::timeout_at(deadline, reader.read(&mut buf[read_bytes..])).await {
+                Ok(Ok(n)) if n > 0 => read_bytes += n,
+                Ok(_) => break,
+                Err(_) => {
+                    STATS.desync_budget_exceeded.incr();
+                    debug!("{conn}: {what} incomplete after {desync_budget:?}, relaying as is");
+                    break;
+                }
+            }
+        }
+
+        if args.desync_renegotiation && !http && !h2c {
+            match &mut records {
+                Some(records) => {
+                    records.feed(&buf[tracked..read_bytes]);
+                }
+                None => {
+                    let mut tracker = tls::RecordTracker::default();
+                    tracker.feed(&buf[..read_bytes]);
+                    records = Some(tracker);
+                }
+            }
+        }
+
+        let map = strategy_map::get();
+        let mut strategy = args
+            .port_strategy(port)
+            .map_or(args.strategy(), |rule| &rule.strategy);
+        let mut log = None;
+        let mut rewritten = None;
+        let mut untouched = false;
+        let mut skipped = "skipped by hostlist";
+        if h2c {
+            log = Some(format!("{conn}: h2c preface"));
+            if hostlist::targets(None, &conn, &args) {
+                plan.split_positions
+                    .extend_from_slice(&args.h2c_split_positions);
+            } else {
+                untouched = true;
+            }
+        } else if http {
+            if let Some(request) = http::parse_request(&buf[..read_bytes]) {
+                if let (true, Some(body_len)) = (args.http_all_requests, request.body_len) {
+                    let body_read = read_bytes - request.header_len;
+                    if let Some(left) = body_len.checked_sub(body_read) {
+                        phase = Phase::Body(left);
+                    }
+                }
+
+                let log = log.insert(format!("{conn}: http"));
+                match &request.host {
+                    Some(host) => write!(log, " host {}", host.name)?,
+                    None => log.push_str(" no host"),
+                }
+                let host = request.host.as_ref().map(|host| host.name.as_str());
+                untouched = !hostlist::targets(host, &conn, &args);
+                learn(untouched, host, attempt.as_ref(), &args);
+                export(untouched, host, dst, &args);
+                if !untouched {
+                    strategy =
+                        pick_strategy(map.as_deref(), request.host.as_ref(), port, log, &args)?;
+                    if let Some(attempt) = &attempt {
+                        strategy = attempt.pick(host, strategy, log, &args);
+                    }
+                    let (request, tampered) =
+                        tamper_http(&mut buf[..read_bytes], request, log, &conn, &args)?;
+                    rewritten = tampered;
+                    plan_splits(strategy, Some(&request), &mut plan, log, &conn, &args)?;
+                }
+            }
+        } else if let Some(hello) = tls::parse_client_hello(&buf[..read_bytes]) {
+            let log = log.insert(format!("{conn}:"));
+            if !hello.strict {
+                STATS.lenient_hellos.incr();
+                log.push_str(" (lenient parse)");
+            }
+            if hello.hosts.is_empty() {
+                log.push_str(" no sni");
+            }
+            if let Some((host, others)) = hello.hosts.split_first() {
+                write!(log, " sni {}", host.name)?;
+                for other in others {
+                    write!(log, " (ignoring sni {})", other.name)?;
+                }
+            }
+            let host = hello.hosts.first().map(|host| host.name.as_str());
+            untouched = !hostlist::targets(host, &conn, &args);
+            learn(untouched, host, attempt.as_ref(), &args);
+            export(untouched, host, dst, &args);
+            if hello.ech {
+                STATS.ech_hellos.incr();
+                log.push_str(" (ech)");
+            }
+            if !untouched && hello.ech && !args.desync_ech {
+                untouched = true;
+                skipped = "encrypted ClientHello";
+            }
+            if !untouched && !alpn_targets(&hello.alpn, &conn, &args) {
+                untouched = true;
+                skipped = "skipped by --alpn-filter";
+            }
+            if !untouched && host.is_none() {
+                STATS.no_sni.incr();
+                untouched = skip_without_sni(&mut skipped, &args);
+            }
+            if !untouched {
+                strategy = match (host, &args.no_sni_policy) {
+                    (None, NoSniPolicy::Strategy(no_sni)) => {
+                        write!(log, " (strategy {no_sni} for no sni)")?;
+                        no_sni
+                    }
+                    _ => pick_strategy(map.as_deref(), hello.hosts.first(), port, log, &args)?,
+                };
+                if let Some(attempt) = &attempt {
+                    strategy = attempt.pick(host, strategy, log, &args);
+                }
+                plan_splits(strategy, Some(&hello), &mut plan, log, &conn, &args)?;
+            }
+        }
+        if let Some(log) = &mut log {
+            log.push_str(&country);
+        }
+        if log.is_none() && renegotiating {
+            debug!("{conn}: no cleartext ClientHello after application data, relaying as is");
+            writer.write_all(&buf[..read_bytes]).await?;
+            phase = Phase::Relay;
+            continue;
+        }
+        if log.is_none() && !hostlist::targets(None, &conn, &args) {
+            untouched = true;
+        }
+        if log.is_none() && !untouched && !http && !h2c {
+            STATS.no_sni.incr();
+            untouched = skip_without_sni(&mut skipped, &args);
+            if let NoSniPolicy::Strategy(no_sni) = &args.no_sni_policy {
+                strategy = no_sni;
+            }
+            if untouched {
+                debug!("{conn}: no ClientHello parsed, relaying as is with --no-sni-policy");
+            }
+        }
+        if untouched {
+            if let Some(log) = log {
+                eprintln!("{log}, {skipped}, relaying as is");
+            }
+            writer.write_all(&buf[..read_bytes]).await?;
+            continue;
+        }
+        if log.is_none() {
+            plan_splits::<http::Request>(
+                strategy,
+                None,
+                &mut plan,
+                &mut String::new(),
+                &conn,
+                &args,
+            )?;
+        }
+
+        let Plan {
+            split_positions,
+            record_cuts,
+            ..
+        } = &mut plan;
+        record_cuts.sort_unstable();
+        record_cuts.dedup();
+        if !record_cuts.is_empty() {
+            match tls::split_records(&buf[..read_bytes], record_cuts) {
+                Ok(data) => {
+                    // Keep TCP splits at the same bytes, before any record header inserted there.
+                    for pos in split_positions.iter_mut() {
+                        *pos +=
+                            tls::HEADER_LEN * record_cuts.iter().filter(|cut| **cut < *pos).count();
+                    }
+                    if let Some(log) = &mut log {
+                        write!(log, ", records cut at {record_cuts:?}")?;
+                    }
+                    rewritten = Some(data);
+                }
+                Err(err) => debug!("{conn}: cannot cut records at {record_cuts:?}: {err}"),
+            }
+        }
+        let payload = rewritten.as_deref().unwrap_or(&buf[..read_bytes]);
+
+        for step in plan.normalize(payload.len()) {
+            if let Some(log) = &mut log {
+                write!(log, ", no segment left for {step}")?;
+            }
+        }
+        let split_positions = &plan.split_positions;
+        if let Some(log) = log {
+            eprintln!("{log}, split at {split_positions:?}");
+        }
+        if split_positions.len() > 1 {
+            let segments: Vec<_> = [0]
+                .iter()
+                .chain(split_positions.iter())
+                .zip(split_positions.iter().chain([&payload.len()]))
+                .map(|(start, end)| end - start)
+                .collect();
+            debug!("{conn}: segment lengths {segments:?}");
+        }
+
+        let segments = plan.segments();
+        let mut desync = Desync {
+            writer: &mut writer,
+            args: &args,
+            conn: &conn,
+            segments: &segments,
+            delay: strategy.delay(),
+            fake_record: plan.fake_record,
+            decoy,
+            low_ttl,
+            deadline,
+        };
+        if let Err(err) = desync.send_split(payload, &plan.split_positions).await {
+            if args.reset_client {
+                reset_on_close(reader.as_ref());
+            }
+            return Err(err);
+        }
+    }
+
+    shutdown(&mut writer).await?;
+    Ok(())
+}
+
+/// Half-closes `writer`, ignoring peers that are already gone.
+async fn shutdown(writer: &mut OwnedWriteHalf) -> io::Result<()> {
+    match writer.shutdown().await {
+        Err(err) if err.kind() == io::ErrorKind::NotConnected => Ok(()),
+        result => result,
+    }
+}
+
+/// Makes closing `stream` send RST instead of FIN.
+fn reset_on_close(stream: &TcpStream) {
+    SockRef::from(stream)
+        .set_linger(Some(time::Duration::ZERO))
+        .ok();
+}
+
+async fn server_to_client(
+    mut reader: WatchedReader,
+    mut writer: OwnedWriteHalf,
+    conn: String,
+    starttls: Option<Arc<StartTls>>,
+    args: Arc<Args>,
+) -> Result<()> {
+    if let Some(starttls) = starttls {
+        let mut buf = vec![0u8; 8192];
+        while !starttls.is_accepted() {
+            let read_bytes = reader.read(&mut buf).await?;
+            if read_bytes == 0 {
+                shutdown(&mut writer).await?;
+                return Ok(());
+            }
+            starttls.server_data(&buf[..read_bytes]);
+            writer.write_all(&buf[..read_bytes]).await?;
+        }
+    }
+
+    if args.wssize.is_some() {
+        let mut buf = vec![0u8; 8192];
+        let (mut reads, mut received) = (0, 0);
+        while received < args.wssize_bytes && args.wssize_reads.is_none_or(|max| reads < max) {
+            let read_bytes = reader.read(&mut buf).await?;
+            if read_bytes == 0 {
+                shutdown(&mut writer).await?;
+                return Ok(());
+            }
+            writer.write_all(&buf[..read_bytes]).await?;
+            reads += 1;
+            received += read_bytes;
+        }
+        let fd = reader.get_ref().as_ref().as_raw_fd();
+        match sockopt::set_window_clamp(fd, UNCLAMPED_WINDOW) {
+            Ok(()) => debug!("{conn}: window unclamped after {reads} reads, {received} bytes"),
+            Err(err) => debug!("{conn}: cannot unclamp window: {err}"),
+        }
+    }
+
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    shutdown(&mut writer).await?;
+    Ok(())
+}
+
+/// Relays a connection accepted by a listener whose connections may carry a fwmark if `marked`.
+async fn handle_client(
+    client_stream: TcpStream,
+    client_addr: SocketAddr,
+    marked: bool,
+    args: Arc<Args>,
+) -> Result<()> {
+    if !dstlist::allows(unmap_ipv4(client_addr).ip()) {
+        STATS.clients_denied.incr();
+        log_denied(client_addr);
+        return Ok(());
+    }
+    let mark = match !args.skip_mark.is_empty() || (marked && args.fwmark != 0) {
+        true => SockRef::from(&client_stream).mark().unwrap_or_else(|err| {
+            eprintln!("{client_addr}: cannot read fwmark, taking it as 0: {err}");
+            0
+        }),
+        false => 0,
+    };
+    if mark != 0 && mark == args.fwmark {
+        STATS.loops_refused.incr();
+        return Err(anyhow!(
+            "connection carries --fwmark {mark}, which the proxy's own have"
+        ));
+    }
+    let skipped_mark = args.skip_mark.contains(&mark);
+    if skipped_mark {
+        STATS.skipped_marks.incr();
+        if args.skip_mark_refuse {
+            eprintln!("{client_addr}: fwmark {mark} in --skip-mark, closing");
+            return Ok(());
+        }
+    }
+    client_stream.set_nodelay(true)?;
+    let local_addr = client_stream.local_addr()?;
+    let original_dst = match get_original_dst(&client_stream) {
+        Ok(original_dst) if original_dst != local_addr => original_dst,
+        result => {
+            STATS.not_redirected.incr();
+            let reason = match result {
+                Ok(_) => anyhow!("connection was not redirected"),
+                Err(err) => anyhow!(err).context("no original destination"),
+            };
+            let Some(default_destination) = args.default_destination else {
+                return Err(reason);
+            };
+            eprintln!("{client_addr}: {reason:#}, forwarding to {default_destination}");
+            default_destination
+        }
+    };
+    let original_dst = unmap_ipv4(original_dst);
+    let original_dst = with_link_local_scope(original_dst, local_addr, args.link_local_interface);
+    eprintln!("{client_addr} -> {original_dst}");
+
+    if is_listen_address(original_dst, &args.listen_address) {
+        STATS.loops_refused.incr();
+        return Err(anyhow!("original destination is the proxy itself"));
+    }
+
+    let conn = format!("{client_addr} -> {original_dst}");
+    let probe_ttl = async {
+        if args.autottl
+            && !skipped_mark
+            && (args.fake_interleave || strategy_map::any(&args, Strategy::lowers_ttl))
+        {
+            autottl::low_ttl(original_dst, &args, &conn).await
+        } else {
+            None
+        }
+    };
+    let (server_stream, low_ttl) = tokio::join!(
+        connect_with_retries(client_addr, original_dst, &args),
+        probe_ttl
+    );
+    let server_stream = match server_stream {
+        Ok(server_stream) => server_stream,
+        Err(err) => {
+            STATS.connect_errors.incr();
+            let action = if args.reset_client {
+                reset_on_close(&client_stream);
+                "resetting"
+            } else {
+                "closing"
+            };
+            return Err(err.context(format!("connect to {original_dst}, {action} client")));
+        }
+    };
+
+    if skipped_mark {
+        eprintln!("{conn}: fwmark {mark} in --skip-mark, relaying as is");
+        let (mut client_stream, mut server_stream) = (client_stream, server_stream);
+        if let Err(err) =
+            tokio::io::copy_bidirectional(&mut client_stream, &mut server_stream).await
+        {
+            eprintln!("{conn}: relaying failed: {err}");
+        }
+        return Ok(());
+    }
+
+    let (client_reader, client_writer) = client_stream.into_split();
+    let (server_reader, server_writer) = server_stream.into_split();
+
+    let starttls = args
+        .starttls_ports
+        .contains(&original_dst.port())
+        .then(|| Arc::new(StartTls::new()));
+    let attempt = (!args.strategy_fallback.is_empty() || args.auto_hostlist.is_some())
+        .then(|| Attempt::new(original_dst.ip(), &conn, &args));
+
+    let mut upload = tokio::spawn(client_to_server(
+        client_reader,
+        server_writer,
+        conn.clone(),
+        starttls.clone(),
+        low_ttl,
+        attempt.clone(),
+        Arc::clone(&args),
+    ));
+    let mut download = tokio::spawn(server_to_client(
+        WatchedReader::new(server_reader, attempt),
+        client_writer,
+        conn.clone(),
+        starttls,
+        Arc::clone(&args),
+    ));
+
+    let (first, first_result, second, second_handle) = tokio::select! {
+        res = &mut upload => (Direction::Upload, res, Direction::Download, download),
+        res = &mut download => (Direction::Download, res, Direction::Upload, upload),
+    };
+
+    if let Err(err) = first_result
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res)
+    {
+        first.errors().incr();
+        eprintln!("{conn}: {first} failed, aborting {second}: {err:#}");
+        second_handle.abort();
+    }
+    match second_handle.await {
+        Err(err) if err.is_cancelled() => {}
+        res => {
+            if let Err(err) = res.map_err(anyhow::Error::from).and_then(|res| res) {
+                second.errors().incr();
+                eprintln!("{conn}: {second} failed after {first} finished: {err:#}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Logs a client denied by --allow-from, at most once per DENIED_WARNING_INTERVAL.
+fn log_denied(client_addr: SocketAddr) {
+    let mut warning = DENIED_WARNING.lock().unwrap();
+    let (last_warning, suppressed) = &mut *warning;
+    if last_warning.is_none_or(|at| at.elapsed() >= DENIED_WARNING_INTERVAL) {
+        eprintln!("{client_addr}: not in --allow-from, closing ({suppressed} similar suppressed)");
+        *last_warning = Some(time::Instant::now());
+        *suppressed = 0;
+    } else {
+        *suppressed += 1;
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+impl Direction {
+    fn errors(self) -> &'static stats::Counter {
+        match self {
+            Direction::Upload => &STATS.upload_errors,
+            Direction::Download => &STATS.download_errors,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Upload => "client->server",
+            Direction::Download => "server->client",
+        })
+    }
+}
+
+fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Fills in the scope id of link-local destinations, which SO_ORIGINAL_DST doesn't report.
+fn with_link_local_scope(
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    interface: Option<u32>,
+) -> SocketAddr {
+    let SocketAddr::V6(mut addr) = addr else {
+        return addr;
+    };
+    if addr.ip().is_unicast_link_local() {
+        if let Some(interface) = interface {
+            addr.set_scope_id(interface);
+        } else if let (0, SocketAddr::V6(local_addr)) = (addr.scope_id(), local_addr) {
+            addr.set_scope_id(local_addr.scope_id());
+        }
+    }
+    SocketAddr::V6(addr)
+}
+
+/// Returns true for private, loopback and link-local addresses, which traffic to doesn't cross
+/// the censor.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unique_local() || ip.is_loopback() || ip.is_unicast_link_local(),
+    }
+}
+
+fn is_listen_address(addr: SocketAddr, listen_addresses: &[SocketAddr]) -> bool {
+    listen_addresses.iter().any(|listen| {
+        listen.port() == addr.port()
+            && match listen.ip().is_unspecified() {
+                true => is_local_address(addr.ip()),
+                false => listen.ip() == addr.ip(),
+            }
+    })
+}
+
+/// Returns true if `ip` is a loopback address or one of the host's interfaces, which listeners
+/// on unspecified addresses accept connections to. Taken as local if the interfaces can't be
+/// listed, erring on the side of refusing loops.
+fn is_local_address(ip: IpAddr) -> bool {
+    static INTERFACES: Mutex<Option<(time::Instant, Vec<IpAddr>)>> = Mutex::new(None);
+    if ip.is_loopback() {
+        return true;
+    }
+    let mut interfaces = INTERFACES.lock().unwrap();
+    if interfaces
+        .as_ref()
+        .is_none_or(|(listed, _)| listed.elapsed() >= INTERFACES_TTL)
+    {
+        match sockopt::interface_addresses() {
+            Ok(addrs) => *interfaces = Some((time::Instant::now(), addrs)),
+            Err(err) => {
+                debug!("cannot list interface addresses: {err}");
+                return true;
+            }
+        }
+    }
+    let (_, addrs) = interfaces.as_ref().expect("listed");
+    addrs.contains(&ip)
+}
+
+async fn connect_with_retries(
+    client_addr: SocketAddr,
+    addr: SocketAddr,
+    args: &Args,
+) -> Result<TcpStream> {
+    let budget = time::Duration::from_millis(args.connect_retry_budget_ms);
+    let started = time::Instant::now();
+    let mut backoff = time::Duration::from_millis(args.connect_retry_backoff_ms);
+    let mut attempt = 1;
+
+    loop {
+        match connect_upstream(addr, args).await {
+            Ok(stream) => return Ok(stream),
+            Err(err)
+                if attempt <= args.connect_retries
+                    && is_transient_connect_error(&err)
+                    && started.elapsed() + backoff < budget =>
+            {
+                STATS.connect_retries.incr();
+                eprintln!(
+                    "{client_addr} -> {addr}: connect attempt {attempt} failed, \
+                     retrying in {backoff:?}: {err:#}"
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn is_transient_connect_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>().is_some_and(|err| {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENETUNREACH | libc::EHOSTUNREACH | libc::ENETDOWN)
+        )
+    })
+}
+
+/// Creates a socket for connecting to `addr` past the interception rules.
+fn upstream_socket(addr: SocketAddr, args: &Args) -> io::Result<Socket> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::STREAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.set_cloexec(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nodelay(true)?;
+    if args.fwmark != 0 {
+        socket.set_mark(args.fwmark)?;
+    }
+    Ok(socket)
+}
+
+async fn connect_upstream(addr: SocketAddr, args: &Args) -> Result<TcpStream> {
+    let socket = upstream_socket(addr, args)?;
+    if let Some(wssize) = args.wssize {
+        sockopt::set_window_clamp(socket.as_raw_fd(), wssize)?;
+    }
+    let std_stream: StdTcpStream = socket.into();
+    let connect = TcpSocket::from_std_stream(std_stream).connect(addr);
+    let stream = time::timeout(
+        time::Duration::from_millis(args.connect_timeout_ms),
+        connect,
+    )
+    .await
+    .map_err(|_| anyhow!("timed out after {} ms", args.connect_timeout_ms))??;
+    Ok(stream)
+}
+
+fn get_original_dst(stream: &TcpStream) -> Result<SocketAddr> {
+    let socket = SockRef::from(stream);
+    let original_dst = if socket.local_addr()?.is_ipv6() {
+        socket
+            .original_dst_ipv6()
+            .or_else(|_| socket.original_dst())?
+    } else {
+        socket.original_dst()?
+    };
+    original_dst.as_socket().context("socket is not inet")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    pub fn args(argv: &[&str]) -> Args {
+        let mut args = Args::try_parse_from(["adpi"].iter().chain(argv)).unwrap();
+        args.fill_strategy();
+        args
+    }
+
+    /// Starts a proxy on a free port that forwards its connections to `destination`.
+    async fn proxy(destination: SocketAddr, argv: &[&str]) -> SocketAddr {
+        let destination = destination.to_string();
+        let mut all = vec!["-l", "127.0.0.1:0", "--fwmark", "0", "--desync-private"];
+        all.extend([
+            "--desync-ports",
+            "any",
+            "--default-destination",
+            &destination,
+        ]);
+        all.extend(argv);
+        let args = args(&all);
+        fake::reload(&args).unwrap();
+        let listener = make_listener(args.listen_address[0], Transparent::Never).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_loop(listener, Arc::new(args)));
+        addr
+    }
+
+    /// Sends `payload` through a proxy started with `argv`, returning what the destination got.
+    async fn relay(payload: &[u8], argv: &[&str]) -> Vec<u8> {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = server.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut client = TcpStream::connect(proxy(destination, argv).await)
+            .await
+            .unwrap();
+        client.write_all(payload).await.unwrap();
+        client.shutdown().await.unwrap();
+        time::timeout(time::Duration::from_secs(5), received)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Sends `payload` through a proxy started with `argv` a byte per write, returning what the
+    /// destination got.
+    async fn trickle(payload: &[u8], argv: &[&str]) -> Vec<u8> {
+        let bytes: Vec<_> = payload.chunks(1).collect();
+        relay_writes(&bytes, argv).await
+    }
+
+    /// Sends each of `writes` through a proxy started with `argv` in its own write, returning
+    /// what the destination got.
+    async fn relay_writes(writes: &[&[u8]], argv: &[&str]) -> Vec<u8> {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = server.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut client = TcpStream::connect(proxy(destination, argv).await)
+            .await
+            .unwrap();
+        client.set_nodelay(true).unwrap();
+        for write in writes {
+            client.write_all(write).await.unwrap();
+            time::sleep(time::Duration::from_millis(1)).await;
+        }
+        client.shutdown().await.unwrap();
+        time::timeout(time::Duration::from_secs(10), received)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Returns a local address nothing listens on.
+    fn closed_port() -> SocketAddr {
+        StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    fn os_error(err: &anyhow::Error) -> Option<i32> {
+        err.downcast_ref::<io::Error>()
+            .and_then(io::Error::raw_os_error)
+    }
+
+    /// Returns the split positions client_to_server would use for the first payload `data`.
+    fn splits(data: &[u8], args: &Args) -> Vec<usize> {
+        let mut plan = Plan::default();
+        if let Some(hello) = tls::parse_client_hello(data) {
+            plan_splits(
+                args.strategy(),
+                Some(&hello),
+                &mut plan,
+                &mut String::new(),
+                "test",
+                args,
+            )
+            .unwrap();
+        }
+        plan.normalize(data.len());
+        plan.split_positions
+    }
+
+    #[test]
+    fn small_read_after_large_one_has_no_splits() {
+        let args = args(&["--split-host", "-c", "2", "-c", "100"]);
+        let mut buf = vec![0u8; 8192];
+        let hello = fake::client_hello("example.com");
+        buf[..hello.len()].copy_from_slice(&hello);
+        assert_eq!(splits(&buf[..hello.len()], &args), [2, 100, 122]);
+
+        // A second hello starts like the first, so the stale bytes after it would complete it.
+        let second = &fake::client_hello("example.org")[..20];
+        buf[..second.len()].copy_from_slice(second);
+        assert_eq!(splits(&buf[..hello.len()], &args), [2, 100, 122]);
+        assert_eq!(splits(&buf[..second.len()], &args), []);
+    }
+
+    #[test]
+    fn splits_large_hello_past_first_buffer() {
+        let padding = (0x0015, vec![0; 8920]);
+        let key_share = (0x0033, vec![0x33; 3200]);
+        let sni = tls::tests::server_name(&["example.com"]);
+        let hello = tls::tests::hello(&[padding, sni, key_share]);
+        assert!(hello.len() > 12000 && hello.len() < 12 * 1024);
+        let host = tls::tests::find(&hello, b"example.com");
+        assert!((9000..9100).contains(&host));
+
+        // The first read fills the 8 KiB buffer, which has to grow for the rest.
+        assert_eq!(tls::pending_client_hello(&hello[..8192]), Some(hello.len()));
+        let args = args(&["--split-host", "-c", "9000", "-c", "20000"]);
+        assert_eq!(splits(&hello, &args), [9000, host + 1]);
+    }
+
+    #[test]
+    fn splits_hello_in_three_records_at_straddling_sni() {
+        let original = fake::client_hello("example.com");
+        let host = tls::tests::find(&original, b"example.com");
+        let stream = tls::split_records(&original, &[50, host + 4]).unwrap();
+        let parsed = tls::parse_client_hello(&stream).unwrap();
+        assert_eq!(parsed.hosts[0].name, "example.com");
+
+        // The host starts after one inserted record header and ends after two.
+        let start = host + tls::HEADER_LEN;
+        assert_eq!(parsed.hosts[0].range, start..start + 11 + tls::HEADER_LEN);
+        let args = args(&["--split-host", "-c", "ext_sni"]);
+        let ext_sni = tls::tests::find(&stream, &[0, 0, 0, 16]);
+        assert_eq!(splits(&stream, &args), [ext_sni, start + 1]);
+        assert_eq!(&stream[start + 1..start + 4], b"xam");
+    }
+
+    #[tokio::test]
+    async fn connect_refused_closes_client() {
+        let closed = closed_port();
+        let err = connect_upstream(closed, &args(&["--fwmark", "0"])).await;
+        assert_eq!(os_error(&err.unwrap_err()), Some(libc::ECONNREFUSED));
+
+        let mut client = TcpStream::connect(proxy(closed, &[]).await).await.unwrap();
+        let read = time::timeout(time::Duration::from_secs(2), client.read(&mut [0; 1])).await;
+        assert!(matches!(read, Ok(Ok(0))));
+
+        let proxy = proxy(closed, &["--reset-client"]).await;
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let read = time::timeout(time::Duration::from_secs(2), client.read(&mut [0; 1])).await;
+        let err = read.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn connect_unreachable_is_retried() {
+        // Linux refuses TCP connects to the broadcast address with ENETUNREACH.
+        let broadcast = "255.255.255.255:80".parse().unwrap();
+        let args = args(&[
+            "--fwmark",
+            "0",
+            "--connect-retries",
+            "2",
+            "--connect-retry-backoff-ms",
+            "10",
+        ]);
+        let client = "127.0.0.1:1".parse().unwrap();
+        let started = time::Instant::now();
+        let err = connect_with_retries(client, broadcast, &args).await;
+        assert_eq!(os_error(&err.unwrap_err()), Some(libc::ENETUNREACH));
+        assert!(started.elapsed() >= time::Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn connect_times_out() {
+        // Further SYNs are dropped while the accept queue of a zero backlog listener is full.
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener.bind(&closed_port().into()).unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let _queued = StdTcpStream::connect(addr).unwrap();
+
+        let args = args(&["--fwmark", "0", "--connect-timeout-ms", "200"]);
+        let started = time::Instant::now();
+        let err = connect_upstream(addr, &args).await.unwrap_err();
+        assert_eq!(err.to_string(), "timed out after 200 ms");
+        assert!(started.elapsed() < time::Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn relays_half_close_both_ways() {
+        // Echoes until the client half-closes, then says goodbye and half-closes in turn.
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            writer.write_all(b"bye").await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut client = TcpStream::connect(proxy(destination, &[]).await)
+            .await
+            .unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        time::timeout(
+            time::Duration::from_secs(2),
+            client.read_to_end(&mut echoed),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(echoed, b"pingbye");
+
+        // Says hello and half-closes first, then reads until the client half-closes.
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = server.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut client = TcpStream::connect(proxy(destination, &[]).await)
+            .await
+            .unwrap();
+        let mut greeting = Vec::new();
+        time::timeout(
+            time::Duration::from_secs(2),
+            client.read_to_end(&mut greeting),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(greeting, b"hello");
+        client.write_all(b"still here").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(received.await.unwrap(), b"still here");
+    }
+
+    #[test]
+    fn splits_only_at_first_host_name() {
+        let sni = tls::tests::server_name(&["first.example", "second.example"]);
+        let hello = tls::tests::hello(&[sni]);
+        let parsed = tls::parse_client_hello(&hello).unwrap();
+        let names: Vec<_> = parsed.hosts.iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(names, ["first.example", "second.example"]);
+
+        let first = tls::tests::find(&hello, b"first.example");
+        assert_eq!(splits(&hello, &args(&["--split-host"])), [first + 1]);
+        assert_eq!(splits(&hello, &args(&["-c", "sni"])), [first]);
+    }
+
+    #[test]
+    fn rejects_split_position_zero() {
+        let err = Args::try_parse_from(["adpi", "-c", "0"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("split position must be greater than 0"));
+        assert!(Args::try_parse_from(["adpi", "--split-random", "0:10"]).is_err());
+    }
+
+    #[test]
+    fn normalizes_split_positions() {
+        let mut plan = Plan {
+            split_positions: vec![40, 0, 10, 40, 100, 10, 99, 250],
+            ..Plan::default()
+        };
+        plan.normalize(100);
+        assert_eq!(plan.split_positions, [10, 40, 99]);
+
+        let mut plan = Plan {
+            split_positions: vec![1],
+            ..Plan::default()
+        };
+        plan.normalize(1);
+        assert_eq!(plan.split_positions, []);
+    }
+
+    #[test]
+    fn plans_duplicate_and_out_of_range_positions_once() {
+        let hello = fake::client_hello("example.com");
+        let host = tls::tests::find(&hello, b"example.com");
+        let repeated = args(&[
+            "-c",
+            "5",
+            "-c",
+            "5",
+            "-c",
+            "sni+1",
+            "--split-host",
+            "-c",
+            "9999",
+        ]);
+        assert_eq!(splits(&hello, &repeated), [5, host + 1]);
+
+        let at_end = args(&["-c", "end", "-c", "end-1"]);
+        assert_eq!(splits(&hello, &at_end), [hello.len() - 1]);
+    }
+
+    #[test]
+    fn delays_preset_splits() {
+        let preset = Strategy::preset("split-sni");
+        assert_eq!(args(&["--preset", "split-sni"]).strategy(), &preset);
+
+        let delayed = args(&["--preset", "split-sni", "--split-delay", "5+-2"]);
+        assert_eq!(delayed.strategy().to_string(), "split:sni+1,delay:5±2");
+        assert_eq!(
+            delayed.strategy().delay(),
+            Some(SplitDelay {
+                ms: 5,
+                jitter_ms: 2
+            })
+        );
+
+        let overridden = args(&["--preset", "split-sni", "--split-delay", "5", "-c", "3"]);
+        assert_eq!(overridden.strategy().to_string(), "split:3,delay:5");
+    }
+
+    #[test]
+    fn moves_techniques_past_dropped_splits() {
+        let data = fake::client_hello("example.com");
+        let hello = tls::parse_client_hello(&data).unwrap();
+        let plan = |strategy: &str| {
+            let args = args(&["--strategy", strategy]);
+            let mut plan = Plan::default();
+            let mut log = String::new();
+            plan_splits(
+                args.strategy(),
+                Some(&hello),
+                &mut plan,
+                &mut log,
+                "test",
+                &args,
+            )
+            .unwrap();
+            let dropped = plan.normalize(data.len());
+            (plan, dropped)
+        };
+
+        // The duplicate split goes, leaving the disorder on the segment starting at 10.
+        let (duplicate, dropped) = plan("split:10,split:10,disorder,split:50");
+        assert_eq!(duplicate.split_positions, [10, 50]);
+        let segments = duplicate.segments();
+        assert!(segments[1].disorder.is_some());
+        assert!(segments[2].disorder.is_none());
+        assert_eq!(dropped, []);
+
+        // A split at the start goes, leaving the disorder on the first segment.
+        let host = hello.hosts[0].range.start;
+        let (at_start, dropped) = plan(&format!("split:sni-{host},disorder,split:50"));
+        assert_eq!(at_start.split_positions, [50]);
+        let segments = at_start.segments();
+        assert!(segments[0].disorder.is_some());
+        assert!(segments[1].disorder.is_none());
+        assert_eq!(dropped, []);
+
+        // Nothing is left of the segment after a split past the payload.
+        let disorder = Step::Disorder(strategy::Disorder { ttl: None });
+        let mut past_end = Plan {
+            split_positions: vec![10, 300],
+            techniques: vec![(2, disorder)],
+            ..Plan::default()
+        };
+        assert_eq!(past_end.normalize(100), [disorder]);
+        assert_eq!(past_end.split_positions, [10]);
+        assert!(past_end.segments().iter().all(|s| s.disorder.is_none()));
+    }
+
+    #[tokio::test]
+    async fn oob_leaves_payload_intact() {
+        let mut payload = fake::client_hello("example.com");
+        payload.extend(b"application data");
+        for argv in [
+            &["--oob", "--split-host"][..],
+            &["--oob", "--oob-byte", "0", "-c", "1", "-c", "sni+2"],
+            &["--strategy", "split:3,split:sni+1,oob:byte=7"],
+        ] {
+            assert_eq!(relay(&payload, argv).await, payload, "with {argv:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn disoob_leaves_payload_intact() {
+        let mut payload = fake::client_hello("example.com");
+        payload.extend(b"application data");
+        for argv in [
+            &["--disoob", "--split-host"][..],
+            &[
+                "--disoob",
+                "-c",
+                "1",
+                "-c",
+                "sni+1",
+                "--segmentation",
+                "cork",
+            ],
+            &["--strategy", "split:1,disorder,oob,split:sni+1"],
+            &["--strategy", "disorder,split:1,oob,split:midsld"],
+        ] {
+            assert_eq!(relay(&payload, argv).await, payload, "with {argv:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn reassembles_hello_written_a_byte_at_a_time() {
+        let original = tls::tests::hello(&[
+            tls::tests::server_name(&["example.com"]),
+            tls::tests::alpn(&["h2"]),
+        ]);
+        let cuts = [60, tls::tests::find(&original, b"example") + 3, 108];
+        let stream = tls::split_records(&original, &cuts).unwrap();
+        let mut payload = stream.clone();
+        payload.extend(b"after the hello");
+
+        // Cutting another record at the host name only works on the whole ClientHello.
+        let received = trickle(&payload, &["--tlsrec"]).await;
+        assert_eq!(received.len(), payload.len() + tls::HEADER_LEN);
+        assert!(received.ends_with(b"after the hello"));
+        let parsed = tls::parse_client_hello(&received).unwrap();
+        assert!(parsed.strict);
+        assert_eq!(parsed.len, stream.len() + tls::HEADER_LEN);
+        assert_eq!(parsed.hosts[0].name, "example.com");
+    }
+
+    #[tokio::test]
+    async fn tlsrec_delivers_the_same_hello() {
+        let hello = fake::client_hello("example.com");
+        let received = relay(&hello, &["--tlsrec", "--tlsrec-pos", "50", "-c", "sni+3"]).await;
+        assert_eq!(received.len(), hello.len() + 2 * tls::HEADER_LEN);
+        let parsed = tls::parse_client_hello(&received).unwrap();
+        assert!(parsed.strict);
+        assert_eq!(parsed.len, received.len());
+        assert_eq!(parsed.hosts[0].name, "example.com");
+    }
+
+    #[test]
+    fn skips_relative_positions_without_sni() {
+        let hello = tls::tests::hello(&[tls::tests::alpn(&["h2"])]);
+        let args = args(&["-c", "sni+1", "-c", "ext_sni", "-c", "5", "--split-host"]);
+        assert_eq!(splits(&hello, &args), [5]);
+    }
+
+    /// Runs a rustls handshake for example.com through a proxy started with `argv`, and a round
+    /// trip after it.
+    async fn handshake(argv: &[&str]) {
+        use rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+            ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+            StreamOwned,
+        };
+        use std::io::{Read, Write};
+
+        let cert = CertificateDer::from(&include_bytes!("../testdata/example.com.crt.der")[..]);
+        let key = PrivatePkcs8KeyDer::from(&include_bytes!("../testdata/example.com.key.der")[..]);
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key.clone_key()))
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let server = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = proxy(server.local_addr().unwrap(), argv).await;
+        let served = std::thread::spawn(move || {
+            let (stream, _) = server.accept().unwrap();
+            let conn = ServerConnection::new(Arc::new(server_config)).unwrap();
+            let mut tls = StreamOwned::new(conn, stream);
+            let mut ping = [0; 4];
+            tls.read_exact(&mut ping).unwrap();
+            tls.write_all(b"pong").unwrap();
+            tls.flush().unwrap();
+            ping
+        });
+        let pong = tokio::task::spawn_blocking(move || {
+            let stream = StdTcpStream::connect(proxy).unwrap();
+            stream
+                .set_read_timeout(Some(time::Duration::from_secs(5)))
+                .unwrap();
+            let name = ServerName::try_from("example.com").unwrap();
+            let conn = ClientConnection::new(Arc::new(client_config), name).unwrap();
+            let mut tls = StreamOwned::new(conn, stream);
+            tls.write_all(b"ping").unwrap();
+            let mut pong = [0; 4];
+            tls.read_exact(&mut pong).unwrap();
+            pong
+        })
+        .await
+        .unwrap();
+        assert_eq!(&pong, b"pong");
+        assert_eq!(&served.join().unwrap(), b"ping");
+    }
+
+    #[tokio::test]
+    async fn relays_encrypted_handshake_records_as_is() {
+        let hello = fake::client_hello("example.com");
+        let data = tls::record(tls::CONTENT_TYPE_APPLICATION_DATA, b"application data");
+        // A renegotiation within the encrypted session, or a TLS 1.3 KeyUpdate.
+        let encrypted = tls::record(tls::CONTENT_TYPE_HANDSHAKE, &[0xa5; 64]);
+        let writes = [&hello[..], &data, &encrypted];
+        for policy in ["split", "strategy:fake,split:1"] {
+            let argv = ["--desync-renegotiation", "--no-sni-policy", policy];
+            let received = relay_writes(&writes, &argv).await;
+            assert_eq!(received, writes.concat(), "with {policy}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn desync_keeps_the_handshake_transcript() {
+        // Both ends hash the ClientHello message, so any change to it fails the handshake.
+        for argv in [
+            &["--split-host", "-c", "1"][..],
+            &[
+                "--tlsrec",
+                "--tlsrec-pos",
+                "ext_sni,sni+2",
+                "--split-midsld",
+            ],
+            &[
+                "--strategy",
+                "fakerec:unfooled,tlsrec:50,split:2,disorder,split:sni+1,oob",
+            ],
+            &["--strategy", "split:every=7"],
+        ] {
+            handshake(argv).await;
+        }
+    }
+
+    #[test]
+    fn splits_at_alpn_before_and_after_sni() {
+        let sni = tls::tests::server_name(&["example.com"]);
+        let alpn = tls::tests::alpn(&["h2", "http/1.1"]);
+        let args = args(&["-c", "alpn", "-c", "alpn+6", "--split-host"]);
+        for extensions in [[alpn.clone(), sni.clone()], [sni, alpn]] {
+            let hello = tls::tests::hello(&extensions);
+            let parsed = tls::parse_client_hello(&hello).unwrap();
+            let at = tls::tests::find(&hello, b"\x00\x10\x00\x0e\x00\x0c\x02h2");
+            assert_eq!(parsed.alpn_extension, Some(at));
+            assert_eq!(&hello[at + 6..at + 9], b"\x02h2");
+
+            let host = tls::tests::find(&hello, b"example.com") + 1;
+            let mut expected = vec![at, at + 6, host];
+            expected.sort_unstable();
+            assert_eq!(splits(&hello, &args), expected);
+        }
+
+        let hello = tls::tests::hello(&[tls::tests::server_name(&["example.com"])]);
+        let host = tls::tests::find(&hello, b"example.com") + 1;
+        assert_eq!(splits(&hello, &args), [host]);
+    }
+
+    #[tokio::test]
+    async fn relays_h2c_preface_byte_for_byte() {
+        let mut preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        // SETTINGS with SETTINGS_ENABLE_PUSH off, then a PING frame.
+        preface.extend_from_slice(&[0, 0, 6, 4, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+        preface.extend_from_slice(&[0, 0, 8, 6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let received = relay(&preface, &["--h2c-split-positions", "1,20,30,35"]).await;
+        assert_eq!(received, preface);
+    }
+
+    #[tokio::test]
+    async fn serves_h2c_through_split_preface() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = proxy(
+            server.local_addr().unwrap(),
+            &["--h2c-split-positions", "1,30"],
+        )
+        .await;
+        tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let mut conn = h2::server::handshake(stream).await.unwrap();
+            while let Some(request) = conn.accept().await {
+                let (request, mut respond) = request.unwrap();
+                assert_eq!(request.uri().path(), "/ping");
+                let response = ::http::Response::new(());
+                let mut body = respond.send_response(response, false).unwrap();
+                body.send_data("pong".into(), true).unwrap();
+            }
+        });
+
+        let stream = TcpStream::connect(proxy).await.unwrap();
+        let pong = time::timeout(time::Duration::from_secs(5), async {
+            let (mut client, conn) = h2::client::handshake(stream).await.unwrap();
+            tokio::spawn(conn);
+            let request = ::http::Request::get("http://example.com/ping")
+                .body(())
+                .unwrap();
+            let (response, _) = client.send_request(request, true).unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), ::http::StatusCode::OK);
+            let mut body = response.into_body();
+            let mut pong = Vec::new();
+            while let Some(data) = body.data().await {
+                let data = data.unwrap();
+                body.flow_control().release_capacity(data.len()).unwrap();
+                pong.extend_from_slice(&data);
+            }
+            pong
+        })
+        .await
+        .unwrap();
+        assert_eq!(pong, b"pong");
+    }
+
+    #[test]
+    fn filters_by_offered_alpn() {
+        let alpn = |protocols: &[&str]| protocols.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let unfiltered = args(&[]);
+        assert!(alpn_targets(&alpn(&["acme-tls/1"]), "test", &unfiltered));
+        assert!(alpn_targets(&[], "test", &unfiltered));
+
+        let filter = args(&["--alpn-filter", "h2,http/1.1"]);
+        assert!(alpn_targets(&alpn(&["h2"]), "test", &filter));
+        assert!(alpn_targets(&alpn(&["h3", "http/1.1"]), "test", &filter));
+        assert!(!alpn_targets(&alpn(&["acme-tls/1"]), "test", &filter));
+        assert!(!alpn_targets(&alpn(&["H2"]), "test", &filter));
+        assert!(!alpn_targets(&[], "test", &filter));
+
+        let no_alpn = args(&["--alpn-filter", "h2", "--alpn-filter-no-alpn"]);
+        assert!(alpn_targets(&[], "test", &no_alpn));
+        assert!(!alpn_targets(&alpn(&["http/1.1"]), "test", &no_alpn));
+    }
+
+    #[test]
+    fn classifies_accept_errors() {
+        for errno in [
+            libc::EMFILE,
+            libc::ENFILE,
+            libc::ENOBUFS,
+            libc::ECONNABORTED,
+            libc::EINTR,
+        ] {
+            assert!(is_transient_accept_error(&io::Error::from_raw_os_error(
+                errno
+            )));
+        }
+        for errno in [libc::EBADF, libc::EINVAL, libc::ENOTSOCK] {
+            assert!(!is_transient_accept_error(&io::Error::from_raw_os_error(
+                errno
+            )));
+        }
+        assert!(!is_transient_accept_error(&io::Error::other("no errno")));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_accepting_after_transient_errors() {
+        let client: SocketAddr = "192.0.2.1:1234".parse().unwrap();
+        let mut results = vec![
+            Ok(1),
+            Err(libc::EMFILE),
+            Err(libc::ENFILE),
+            Err(libc::EMFILE),
+            Ok(2),
+            Err(libc::EMFILE),
+            Ok(3),
+            Err(libc::EBADF),
+        ]
+        .into_iter();
+        let mut accepted = Vec::new();
+        let mut waits = Vec::new();
+        let mut last = time::Instant::now();
+        let accept = || {
+            waits.push(last.elapsed());
+            last = time::Instant::now();
+            let result = results.next().unwrap();
+            async move {
+                result
+                    .map(|n| (n, client))
+                    .map_err(io::Error::from_raw_os_error)
+            }
+        };
+        let err = accept_connections(accept, |n, _| accepted.push(n))
+            .await
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        assert_eq!(accepted, [1, 2, 3]);
+        // Doubling while errors last, and starting over after a connection.
+        let ms = |ms| time::Duration::from_millis(ms);
+        assert_eq!(
+            waits[1..],
+            [ms(0), ms(10), ms(20), ms(40), ms(0), ms(10), ms(0)]
+        );
+    }
+
+    #[test]
+    fn unmaps_ipv4_mapped_addresses() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            unmap_ipv4(addr("[::ffff:192.0.2.1]:443")),
+            addr("192.0.2.1:443")
+        );
+        assert_eq!(unmap_ipv4(addr("192.0.2.1:443")), addr("192.0.2.1:443"));
+        for native in ["[2001:db8::1]:443", "[::1]:443", "[::192.0.2.1]:443"] {
+            assert_eq!(unmap_ipv4(addr(native)), addr(native));
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_ipv4_mapped_destinations() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = server.local_addr().unwrap().port();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mapped = format!("[::ffff:127.0.0.1]:{port}").parse().unwrap();
+        let mut client = TcpStream::connect(proxy(mapped, &[]).await).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+        let received = time::timeout(time::Duration::from_secs(5), received)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, b"ping");
+    }
+
+    #[test]
+    fn matches_listen_addresses() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let specific = [addr("192.0.2.1:443")];
+        assert!(is_listen_address(addr("192.0.2.1:443"), &specific));
+        assert!(!is_listen_address(addr("192.0.2.2:443"), &specific));
+        assert!(!is_listen_address(addr("192.0.2.1:80"), &specific));
+
+        for unspecified in [addr("0.0.0.0:443"), addr("[::]:443")] {
+            assert!(is_listen_address(addr("127.0.0.1:443"), &[unspecified]));
+            assert!(is_listen_address(addr("127.0.0.2:443"), &[unspecified]));
+            assert!(is_listen_address(addr("[::1]:443"), &[unspecified]));
+            // Remote hosts on the listen port are no loop.
+            assert!(!is_listen_address(
+                addr("93.184.215.14:443"),
+                &[unspecified]
+            ));
+            assert!(!is_listen_address(
+                addr("[2606:2800:21f:cb07::1]:443"),
+                &[unspecified]
+            ));
+            assert!(!is_listen_address(addr("127.0.0.1:444"), &[unspecified]));
+            for ip in sockopt::interface_addresses().unwrap() {
+                assert!(
+                    is_listen_address(SocketAddr::new(ip, 443), &[unspecified]),
+                    "{ip}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn desyncs_hello_after_pipelined_starttls() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = server.local_addr().unwrap();
+        let hello = tls::tests::hello(&[tls::tests::server_name(&["example.com"])]);
+        let served = tokio::spawn(async move {
+            let (stream, _) = server.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            writer
+                .write_all(b"220 mx.example.com ESMTP\r\n")
+                .await
+                .unwrap();
+            let mut commands = String::new();
+            while !commands.ends_with("STARTTLS\r\n") {
+                assert_ne!(reader.read_line(&mut commands).await.unwrap(), 0);
+            }
+            assert_eq!(commands, "EHLO client\r\nSTARTTLS\r\n");
+            writer
+                .write_all(b"250-mx.example.com\r\n250 STARTTLS\r\n")
+                .await
+                .unwrap();
+            writer
+                .write_all(b"220 Ready to start TLS\r\n")
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let port = destination.port().to_string();
+        let argv = ["--starttls-ports", &port, "--tlsrec"];
+        let client = TcpStream::connect(proxy(destination, &argv).await)
+            .await
+            .unwrap();
+        client.set_nodelay(true).unwrap();
+        let (reader, mut writer) = client.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut replies = String::new();
+        reader.read_line(&mut replies).await.unwrap();
+        // The commands split across writes, then pipelined in one.
+        writer.write_all(b"EHLO cli").await.unwrap();
+        time::sleep(time::Duration::from_millis(20)).await;
+        writer.write_all(b"ent\r\nSTART").await.unwrap();
+        time::sleep(time::Duration::from_millis(20)).await;
+        writer.write_all(b"TLS\r\n").await.unwrap();
+        while !replies.ends_with("220 Ready to start TLS\r\n") {
+            assert_ne!(reader.read_line(&mut replies).await.unwrap(), 0);
+        }
+        writer.write_all(&hello).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let received = time::timeout(time::Duration::from_secs(5), served)
+            .await
+            .unwrap()
+            .unwrap();
+        // --tlsrec cut the ClientHello record in two.
+        assert_eq!(received.len(), hello.len() + tls::HEADER_LEN);
+        let parsed = tls::parse_client_hello(&received).unwrap();
+        assert_eq!(parsed.hosts[0].name, "example.com");
+    }
 }
@@ -0,0 +1,291 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::IpAddr,
+    process::{Command, Stdio},
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::{sync::mpsc, time};
+
+use crate::{hostlist::normalize, stats::STATS, Args};
+
+/// How long to gather updates before running nft with them.
+const BATCH_DELAY: time::Duration = time::Duration::from_millis(500);
+const MAX_EXPORTED_ADDRS: usize = 1 << 16;
+const MAX_HOSTS_PER_ADDR: usize = 64;
+/// Updates run in one nft transaction at most, so that a big list changing doesn't make for a
+/// huge script, nor a failed one for many retries one at a time.
+const MAX_BATCH_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug)]
+enum Update {
+    Add(IpAddr),
+    Remove(IpAddr),
+}
+
+/// The addresses added to --nft-set and --nft-set6, with the hosts seen at each.
+#[derive(Default)]
+struct Exported {
+    hosts: HashMap<IpAddr, HashSet<String>>,
+    updates: Option<mpsc::UnboundedSender<Update>>,
+}
+
+static EXPORTED: LazyLock<Mutex<Exported>> = LazyLock::new(Mutex::default);
+
+/// Starts updating --nft-set and --nft-set6 in batches.
+pub fn start(args: &Arc<Args>) -> Result<()> {
+    for (name, set) in [("--nft-set", &args.nft_set), ("--nft-set6", &args.nft_set6)] {
+        if set.len() > 3 {
+            return Err(anyhow!("{name} given more than once"));
+        }
+    }
+    if args.nft_set.is_empty() && args.nft_set6.is_empty() {
+        return Ok(());
+    }
+    let (updates, receiver) = mpsc::unbounded_channel();
+    EXPORTED.lock().unwrap().updates = Some(updates);
+    tokio::spawn(apply_loop(receiver, Arc::clone(args)));
+    Ok(())
+}
+
+/// Exports `addr`, a destination of `host`, unless it is already or its family has no set.
+pub fn add(addr: IpAddr, host: &str, args: &Args) {
+    let set = if addr.is_ipv4() {
+        &args.nft_set
+    } else {
+        &args.nft_set6
+    };
+    if set.is_empty() {
+        return;
+    }
+    let mut exported = EXPORTED.lock().unwrap();
+    let Some(updates) = exported.updates.clone() else {
+        return;
+    };
+    if let Some(hosts) = exported.hosts.get_mut(&addr) {
+        if hosts.len() < MAX_HOSTS_PER_ADDR {
+            hosts.insert(normalize(host));
+        }
+        return;
+    }
+    if exported.hosts.len() >= MAX_EXPORTED_ADDRS {
+        return;
+    }
+    exported
+        .hosts
+        .insert(addr, HashSet::from([normalize(host)]));
+    _ = updates.send(Update::Add(addr));
+}
+
+/// Removes the addresses exported for hosts no longer `listed`.
+pub fn prune(listed: impl Fn(&str) -> bool) {
+    let mut exported = EXPORTED.lock().unwrap();
+    let Some(updates) = exported.updates.clone() else {
+        return;
+    };
+    exported.hosts.retain(|addr, hosts| {
+        hosts.retain(|host| listed(host));
+        if hosts.is_empty() {
+            _ = updates.send(Update::Remove(*addr));
+        }
+        !hosts.is_empty()
+    });
+}
+
+async fn apply_loop(mut receiver: mpsc::UnboundedReceiver<Update>, args: Arc<Args>) {
+    while let Some(update) = receiver.recv().await {
+        time::sleep(BATCH_DELAY).await;
+        let mut updates = vec![update];
+        while let Ok(update) = receiver.try_recv() {
+            updates.push(update);
+        }
+        let args = Arc::clone(&args);
+        _ = tokio::task::spawn_blocking(move || apply(&updates, &args, run_nft)).await;
+    }
+}
+
+/// Runs `updates` with `run` in transactions of up to MAX_BATCH_LEN, retrying those of a failed
+/// one one at a time, so that an address removed already or a bad one doesn't hold up the others.
+fn apply(updates: &[Update], args: &Args, mut run: impl FnMut(&str) -> Result<()>) {
+    for batch in updates.chunks(MAX_BATCH_LEN) {
+        let commands: Vec<_> = batch
+            .iter()
+            .map(|&update| (update, command(update, args)))
+            .collect();
+        let script: String = commands
+            .iter()
+            .map(|(_, command)| command.as_str())
+            .collect();
+        match run(&script) {
+            Ok(()) => {
+                batch.iter().for_each(|&update| count(update));
+                debug!("nft set: applied {} updates", batch.len());
+                continue;
+            }
+            Err(err) if commands.len() == 1 => {
+                failed(commands[0].0, &err);
+                continue;
+            }
+            Err(err) => debug!("nft set: {err:#}, applying updates one at a time"),
+        }
+        for (update, command) in commands {
+            match run(&command) {
+                Ok(()) => count(update),
+                Err(err) => failed(update, &err),
+            }
+        }
+    }
+}
+
+/// Returns the nft command for `update`, on the set of its address family.
+fn command(update: Update, args: &Args) -> String {
+    let (verb, addr) = match update {
+        Update::Add(addr) => ("add", addr),
+        Update::Remove(addr) => ("delete", addr),
+    };
+    let set = if addr.is_ipv4() {
+        &args.nft_set
+    } else {
+        &args.nft_set6
+    };
+    format!("{verb} element {} {{ {addr} }}\n", set.join(" "))
+}
+
+fn count(update: Update) {
+    match update {
+        Update::Add(_) => STATS.nft_set_additions.incr(),
+        Update::Remove(_) => STATS.nft_set_removals.incr(),
+    }
+}
+
+fn failed(update: Update, err: &anyhow::Error) {
+    match update {
+        // The element is gone either way.
+        Update::Remove(addr) if format!("{err:#}").contains("No such file or directory") => {
+            debug!("nft set: {addr} was not in the set");
+            STATS.nft_set_removals.incr();
+        }
+        Update::Remove(addr) => {
+            STATS.nft_set_errors.incr();
+            eprintln!("cannot remove {addr} from nft set: {err:#}");
+        }
+        Update::Add(addr) => {
+            STATS.nft_set_errors.incr();
+            eprintln!("cannot add {addr} to nft set: {err:#}");
+            // Forget the address, so that the next connection to it tries again.
+            EXPORTED.lock().unwrap().hosts.remove(&addr);
+        }
+    }
+}
+
+fn run_nft(script: &str) -> Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("cannot run nft")?;
+    let mut stdin = child.stdin.take().expect("piped");
+    stdin.write_all(script.as_bytes())?;
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let first_line = stderr.lines().next().unwrap_or("failed").trim();
+        return Err(anyhow!("nft: {first_line}"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args() -> Args {
+        crate::tests::args(&[
+            "--nft-set",
+            "inet",
+            "adpi",
+            "blocked",
+            "--nft-set6",
+            "inet",
+            "adpi",
+            "blocked6",
+        ])
+    }
+
+    #[test]
+    fn writes_commands_for_the_family_set() {
+        let args = args();
+        let v4 = "192.0.2.1".parse().unwrap();
+        let v6 = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            command(Update::Add(v4), &args),
+            "add element inet adpi blocked { 192.0.2.1 }\n"
+        );
+        assert_eq!(
+            command(Update::Remove(v6), &args),
+            "delete element inet adpi blocked6 { 2001:db8::1 }\n"
+        );
+    }
+
+    #[test]
+    fn batches_up_to_the_limit() {
+        let args = args();
+        let updates: Vec<_> = (0..MAX_BATCH_LEN * 2 + 1)
+            .map(|i| Update::Add(IpAddr::from([10, 0, (i >> 8) as u8, i as u8])))
+            .collect();
+        let mut scripts = Vec::new();
+        apply(&updates, &args, |script| {
+            scripts.push(script.to_owned());
+            Ok(())
+        });
+        let lens: Vec<_> = scripts
+            .iter()
+            .map(|script| script.lines().count())
+            .collect();
+        assert_eq!(lens, [MAX_BATCH_LEN, MAX_BATCH_LEN, 1]);
+        assert_eq!(scripts.concat().lines().count(), updates.len());
+        assert!(scripts[2].starts_with("add element inet adpi blocked { 10.0.2.0 }"));
+    }
+
+    #[test]
+    fn retries_a_failed_batch_one_at_a_time() {
+        let args = args();
+        let gone = "192.0.2.2".parse().unwrap();
+        let bad = "192.0.2.3".parse().unwrap();
+        let updates = [
+            Update::Add("192.0.2.1".parse().unwrap()),
+            Update::Remove(gone),
+            Update::Add(bad),
+        ];
+        let removals = STATS.nft_set_removals.get();
+        let errors = STATS.nft_set_errors.get();
+        let mut scripts = Vec::new();
+        apply(&updates, &args, |script| {
+            scripts.push(script.to_owned());
+            if script.contains("192.0.2.2") {
+                return Err(anyhow!(
+                    "nft: Error: Could not process rule: No such file or directory"
+                ));
+            }
+            match script.contains("192.0.2.3") {
+                true => Err(anyhow!(
+                    "nft: Error: Could not process rule: Invalid argument"
+                )),
+                false => Ok(()),
+            }
+        });
+        assert_eq!(scripts.len(), 4);
+        assert_eq!(scripts[0].lines().count(), 3);
+        assert!(scripts[1..]
+            .iter()
+            .all(|script| script.lines().count() == 1));
+        // Deleting an address missing from the set counts as removed, unlike other errors.
+        assert_eq!(STATS.nft_set_removals.get(), removals + 1);
+        assert_eq!(STATS.nft_set_errors.get(), errors + 1);
+    }
+}
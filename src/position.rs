@@ -0,0 +1,290 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    http::Request,
+    tls::{self, ClientHello, Host},
+};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Landmark {
+    /// Start of the stream.
+    Start,
+    /// Start of the header of the first TLS record, which offsets stay inside.
+    RecordHeader,
+    /// First byte of the first host name.
+    Sni,
+    /// Type of the server_name extension.
+    ExtSni,
+    /// Type of the application_layer_protocol_negotiation extension.
+    Alpn,
+    /// End of the ClientHello records or the HTTP header section.
+    End,
+}
+
+/// First payload parsed far enough to resolve positions in it.
+pub trait Landmarks {
+    /// Names the payload in messages.
+    const NAME: &'static str;
+    /// Whether the payload is made of TLS records that can be cut.
+    const RECORDS: bool = false;
+
+    /// Stream offset after the last byte that may be split.
+    fn end(&self) -> usize;
+
+    fn host(&self) -> Option<&Host>;
+
+    fn sni_extension(&self) -> Option<usize> {
+        None
+    }
+
+    fn record_header(&self) -> Option<usize> {
+        None
+    }
+
+    fn alpn_extension(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl Landmarks for ClientHello {
+    const NAME: &'static str = "ClientHello";
+    const RECORDS: bool = true;
+
+    fn end(&self) -> usize {
+        self.len
+    }
+
+    fn host(&self) -> Option<&Host> {
+        self.hosts.first()
+    }
+
+    fn sni_extension(&self) -> Option<usize> {
+        self.sni_extension
+    }
+
+    fn record_header(&self) -> Option<usize> {
+        Some(0)
+    }
+
+    fn alpn_extension(&self) -> Option<usize> {
+        self.alpn_extension
+    }
+}
+
+impl Landmarks for Request {
+    const NAME: &'static str = "request headers";
+
+    fn end(&self) -> usize {
+        self.header_len
+    }
+
+    fn host(&self) -> Option<&Host> {
+        self.host.as_ref()
+    }
+}
+
+/// Offset into a ClientHello or HTTP request relative to a landmark found when parsing it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Position {
+    landmark: Landmark,
+    offset: i64,
+}
+
+impl Position {
+    pub fn absolute(&self) -> Option<i64> {
+        (self.landmark == Landmark::Start).then_some(self.offset)
+    }
+
+    /// Returns the stream offset, or None if the landmark is missing or it would be negative.
+    pub fn resolve(&self, parsed: &impl Landmarks) -> Option<usize> {
+        let base = match self.landmark {
+            Landmark::Start => 0,
+            Landmark::RecordHeader => parsed.record_header()?,
+            Landmark::Sni => parsed.host()?.range.start,
+            Landmark::ExtSni => parsed.sni_extension()?,
+            Landmark::Alpn => parsed.alpn_extension()?,
+            Landmark::End => parsed.end(),
+        };
+        usize::try_from(base as i64 + self.offset).ok()
+    }
+}
+
+/// Second-level labels that make a two-letter country code TLD a two-label public suffix, as in
+/// co.uk or com.au.
+const SECOND_LEVEL_SUFFIXES: [&str; 12] = [
+    "ac", "co", "com", "edu", "gob", "gov", "gv", "ne", "net", "or", "org", "mil",
+];
+
+/// Returns the offset in `host` of the middle of its registrable label, like between "goo" and
+/// "gle" of google.com or "b" and "bc" of bbc.co.uk, or None if it has a single label.
+pub fn midsld(host: &str) -> Option<usize> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    let labels: Vec<_> = host.split('.').collect();
+    let [.., second, tld] = labels[..] else {
+        return None;
+    };
+    let suffix_labels = if labels.len() > 2
+        && tld.len() == 2
+        && SECOND_LEVEL_SUFFIXES.contains(&second.to_ascii_lowercase().as_str())
+    {
+        2
+    } else {
+        1
+    };
+    let sld_index = labels.len() - suffix_labels - 1;
+    let start: usize = labels[..sld_index]
+        .iter()
+        .map(|label| label.len() + 1)
+        .sum();
+    let len = labels[sld_index].len();
+    Some(start + (len / 2).max(1))
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (landmark, offset) = match s.find(['+', '-']) {
+            Some(0) => return Err("positions can't be negative".to_owned()),
+            Some(i) => (&s[..i], &s[i..]),
+            None if s.starts_with(|c: char| c.is_ascii_digit()) => ("", s),
+            None => (s, "0"),
+        };
+        let landmark = match landmark {
+            "" => Landmark::Start,
+            "rechdr" => Landmark::RecordHeader,
+            "sni" => Landmark::Sni,
+            "ext_sni" => Landmark::ExtSni,
+            "alpn" => Landmark::Alpn,
+            "end" => Landmark::End,
+            _ => {
+                return Err(format!(
+                    "unknown landmark {landmark:?}, expected rechdr, sni, ext_sni, alpn or end"
+                ))
+            }
+        };
+        let offset = offset
+            .strip_prefix('+')
+            .unwrap_or(offset)
+            .parse()
+            .map_err(|err| format!("bad offset {offset:?}: {err}"))?;
+        if landmark == Landmark::RecordHeader && !(1..tls::HEADER_LEN as i64).contains(&offset) {
+            return Err(format!(
+                "rechdr offsets must be 1 to {}, inside the record header",
+                tls::HEADER_LEN - 1
+            ));
+        }
+        Ok(Position { landmark, offset })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let landmark = match self.landmark {
+            Landmark::Start => return write!(f, "{}", self.offset),
+            Landmark::RecordHeader => "rechdr",
+            Landmark::Sni => "sni",
+            Landmark::ExtSni => "ext_sni",
+            Landmark::Alpn => "alpn",
+            Landmark::End => "end",
+        };
+        match self.offset {
+            0 => write!(f, "{landmark}"),
+            offset => write!(f, "{landmark}{offset:+}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ClientHello {
+        tls::parse_client_hello(&crate::fake::client_hello("www.example.com")).unwrap()
+    }
+
+    #[test]
+    fn round_trips_positions() {
+        for s in [
+            "1",
+            "500",
+            "sni",
+            "sni+3",
+            "sni-2",
+            "ext_sni",
+            "ext_sni+4",
+            "alpn",
+            "alpn-1",
+            "end",
+            "end-10",
+            "rechdr+1",
+            "rechdr+4",
+        ] {
+            let pos: Position = s.parse().unwrap();
+            assert_eq!(pos.to_string(), s);
+        }
+        assert_eq!("sni+0".parse::<Position>().unwrap().to_string(), "sni");
+        assert_eq!("end+0".parse::<Position>(), "end".parse());
+    }
+
+    #[test]
+    fn rejects_bad_positions() {
+        for s in [
+            "-1", "+1", "host", "sni+", "sni+x", "end-1.5", "rechdr", "rechdr+5",
+        ] {
+            assert!(s.parse::<Position>().is_err(), "{s:?} parsed");
+        }
+        assert_eq!(
+            "foo+1".parse::<Position>().unwrap_err(),
+            "unknown landmark \"foo\", expected rechdr, sni, ext_sni, alpn or end"
+        );
+    }
+
+    #[test]
+    fn resolves_against_landmarks() {
+        let hello = sample();
+        let host = hello.hosts[0].range.start;
+        let resolve = |s: &str| s.parse::<Position>().unwrap().resolve(&hello);
+        assert_eq!(resolve("7"), Some(7));
+        assert_eq!(resolve("sni"), Some(host));
+        assert_eq!(resolve("sni+3"), Some(host + 3));
+        assert_eq!(resolve("sni-2"), Some(host - 2));
+        assert_eq!(resolve("ext_sni"), hello.sni_extension);
+        assert_eq!(resolve("end-10"), Some(hello.len - 10));
+        assert_eq!(resolve("rechdr+2"), Some(2));
+        assert_eq!(resolve("sni-10000"), None);
+    }
+
+    #[test]
+    fn skips_missing_landmarks() {
+        let mut hello = sample();
+        hello.hosts.clear();
+        hello.sni_extension = None;
+        hello.alpn_extension = None;
+        for s in ["sni", "sni+1", "ext_sni", "alpn+2"] {
+            assert_eq!(s.parse::<Position>().unwrap().resolve(&hello), None, "{s}");
+        }
+
+        let request = crate::http::parse_request(b"GET / HTTP/1.1\r\nHost: a.example\r\n\r\n");
+        let request = request.unwrap();
+        let resolve = |s: &str| s.parse::<Position>().unwrap().resolve(&request);
+        assert_eq!(resolve("rechdr+1"), None);
+        assert_eq!(resolve("ext_sni"), None);
+        assert_eq!(resolve("sni"), Some(22));
+        assert_eq!(resolve("end"), Some(35));
+    }
+
+    #[test]
+    fn finds_middle_of_registrable_label() {
+        assert_eq!(midsld("google.com"), Some(3));
+        assert_eq!(midsld("www.google.com"), Some(7));
+        assert_eq!(midsld("bbc.co.uk"), Some(1));
+        assert_eq!(midsld("news.bbc.co.uk"), Some(6));
+        assert_eq!(midsld("WWW.BBC.CO.UK."), Some(5));
+        assert_eq!(midsld("co.uk"), Some(1));
+        assert_eq!(midsld("x.com"), Some(1));
+        assert_eq!(midsld("example.co"), Some(3));
+        assert_eq!(midsld("localhost"), None);
+        assert_eq!(midsld("localhost."), None);
+    }
+}
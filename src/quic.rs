@@ -0,0 +1,748 @@
+use std::{
+    ops::{Range, RangeInclusive},
+    time::Instant,
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use aes_gcm::{aead::AeadInPlace, Aes128Gcm, Tag};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::{
+    rng,
+    tls::{self, ClientHello},
+};
+
+const VERSION_1: u32 = 1;
+/// RFC 9001, section 5.2.
+const INITIAL_SALT_V1: [u8; 20] = [
+    0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8, 0x0c, 0xad,
+    0xcc, 0xbb, 0x7f, 0x0a,
+];
+/// Datagrams carrying client Initial packets must be padded to at least this.
+const MIN_INITIAL_DATAGRAM_LEN: usize = 1200;
+const MAX_CID_LEN: usize = 20;
+const LONG_HEADER: u8 = 0x80;
+const FIXED_BIT: u8 = 0x40;
+const PACKET_TYPE_MASK: u8 = 0x30;
+const PACKET_TYPE_INITIAL: u8 = 0x00;
+const PACKET_TYPE_RETRY: u8 = 0x30;
+const MAX_PN_LEN: usize = 4;
+const SAMPLE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const FRAME_PADDING: u8 = 0x00;
+const FRAME_PING: u8 = 0x01;
+const FRAME_ACK: u8 = 0x02;
+const FRAME_ACK_ECN: u8 = 0x03;
+const FRAME_CRYPTO: u8 = 0x06;
+const FRAME_CONNECTION_CLOSE: u8 = 0x1c;
+/// Type and two varints of up to four bytes.
+const MAX_CRYPTO_FRAME_HEADER_LEN: usize = 9;
+/// Datagrams held waiting for the end of a ClientHello, which fits in two or three.
+const MAX_HELD_DATAGRAMS: usize = 4;
+
+/// Packet protection keys of one side (RFC 9001, section 5).
+struct Keys {
+    aead: Aes128Gcm,
+    iv: [u8; 12],
+    hp: Aes128,
+}
+
+impl Keys {
+    /// Derives the Initial keys of the client, or of the server, from the destination connection
+    /// ID chosen by the client.
+    fn initial(dcid: &[u8], server: bool) -> Self {
+        let (initial_secret, _) = Hkdf::<Sha256>::extract(Some(&INITIAL_SALT_V1), dcid);
+        let label = if server { "server in" } else { "client in" };
+        let secret: [u8; 32] = expand_label(&initial_secret, label);
+        let key: [u8; 16] = expand_label(&secret, "quic key");
+        let hp: [u8; 16] = expand_label(&secret, "quic hp");
+        Keys {
+            aead: Aes128Gcm::new(&key.into()),
+            iv: expand_label(&secret, "quic iv"),
+            hp: Aes128::new(&hp.into()),
+        }
+    }
+
+    fn mask(&self, sample: &[u8]) -> [u8; SAMPLE_LEN] {
+        let mut block = GenericArray::clone_from_slice(sample);
+        self.hp.encrypt_block(&mut block);
+        block.into()
+    }
+
+    fn nonce(&self, pn: u64) -> GenericArray<u8, aes_gcm::aead::consts::U12> {
+        let mut nonce = self.iv;
+        for (byte, pn_byte) in nonce[4..].iter_mut().zip(pn.to_be_bytes()) {
+            *byte ^= pn_byte;
+        }
+        nonce.into()
+    }
+}
+
+/// HKDF-Expand-Label from TLS 1.3 with an empty context.
+fn expand_label<const N: usize>(secret: &[u8], label: &str) -> [u8; N] {
+    let hkdf = Hkdf::<Sha256>::from_prk(secret).expect("secrets are hash sized");
+    let label = format!("tls13 {label}");
+    let mut info = (N as u16).to_be_bytes().to_vec();
+    info.push(label.len() as u8);
+    info.extend_from_slice(label.as_bytes());
+    info.push(0);
+    let mut okm = [0; N];
+    hkdf.expand(&info, &mut okm)
+        .expect("labels are much shorter than the hash");
+    okm
+}
+
+/// QUIC v1 Initial packet without its protection.
+struct Initial {
+    fixed_bit: u8,
+    dcid: Vec<u8>,
+    scid: Vec<u8>,
+    token: Vec<u8>,
+    pn: u64,
+    pn_len: usize,
+    payload: Vec<u8>,
+}
+
+/// Offsets in a protected Initial packet.
+struct Header {
+    dcid: Range<usize>,
+    scid: Range<usize>,
+    token: Range<usize>,
+    pn_offset: usize,
+    /// End of the packet, which other packets may follow in the datagram.
+    end: usize,
+}
+
+fn parse_header(data: &[u8]) -> Option<Header> {
+    let first = *data.first()?;
+    // The fixed bit may be greased (RFC 9287).
+    if first & (LONG_HEADER | PACKET_TYPE_MASK) != LONG_HEADER | PACKET_TYPE_INITIAL {
+        return None;
+    }
+    if u32::from_be_bytes(data.get(1..5)?.try_into().ok()?) != VERSION_1 {
+        return None;
+    }
+    let mut reader = Reader { data, pos: 5 };
+    let dcid_len = reader.byte()? as usize;
+    let dcid = reader.range(dcid_len.min(MAX_CID_LEN + 1))?;
+    let scid_len = reader.byte()? as usize;
+    let scid = reader.range(scid_len.min(MAX_CID_LEN + 1))?;
+    if dcid.len() > MAX_CID_LEN || scid.len() > MAX_CID_LEN {
+        return None;
+    }
+    let token_len = reader.varint()? as usize;
+    let token = reader.range(token_len)?;
+    let len = reader.varint()? as usize;
+    let pn_offset = reader.pos;
+    let end = pn_offset.checked_add(len)?;
+    if end > data.len() || len < MAX_PN_LEN + SAMPLE_LEN {
+        return None;
+    }
+    Some(Header {
+        dcid,
+        scid,
+        token,
+        pn_offset,
+        end,
+    })
+}
+
+fn is_retry(datagram: &[u8]) -> bool {
+    match datagram {
+        [first, version @ ..] if version.len() >= 4 => {
+            first & (LONG_HEADER | PACKET_TYPE_MASK) == LONG_HEADER | PACKET_TYPE_RETRY
+                && version[..4] == VERSION_1.to_be_bytes()
+        }
+        _ => false,
+    }
+}
+
+/// Returns the destination connection ID of the first packet of `datagram` if it is a QUIC v1
+/// Initial packet.
+pub fn initial_dcid(datagram: &[u8]) -> Option<&[u8]> {
+    parse_header(datagram).map(|header| &datagram[header.dcid])
+}
+
+/// Removes the protection of the Initial packet at the start of `data`, returning it and its
+/// length.
+fn open(data: &[u8], keys: &Keys, largest_pn: Option<u64>) -> Option<(Initial, usize)> {
+    let header = parse_header(data)?;
+    let pn_offset = header.pn_offset;
+    let mut packet = data[..header.end].to_vec();
+    let mask = keys.mask(&packet[pn_offset + MAX_PN_LEN..][..SAMPLE_LEN]);
+    packet[0] ^= mask[0] & 0x0f;
+    let pn_len = (packet[0] & 0x03) as usize + 1;
+    let mut truncated = 0u64;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+        truncated = truncated << 8 | packet[pn_offset + i] as u64;
+    }
+    let pn = decode_pn(largest_pn, truncated, pn_len);
+    let (aad, rest) = packet.split_at_mut(pn_offset + pn_len);
+    if rest.len() < TAG_LEN {
+        return None;
+    }
+    let (payload, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+    keys.aead
+        .decrypt_in_place_detached(&keys.nonce(pn), aad, payload, Tag::from_slice(tag))
+        .ok()?;
+    let initial = Initial {
+        fixed_bit: data[0] & FIXED_BIT,
+        dcid: data[header.dcid].to_vec(),
+        scid: data[header.scid].to_vec(),
+        token: data[header.token].to_vec(),
+        pn,
+        pn_len,
+        payload: payload.to_vec(),
+    };
+    Some((initial, header.end))
+}
+
+/// Recovers a full packet number from its `len` least significant bytes (RFC 9000, appendix
+/// A.3).
+fn decode_pn(largest_pn: Option<u64>, truncated: u64, len: usize) -> u64 {
+    let expected = largest_pn.map_or(0, |pn| pn + 1);
+    let window = 1u64 << (len * 8);
+    let half_window = window / 2;
+    let candidate = (expected & !(window - 1)) | truncated;
+    if candidate + half_window <= expected && candidate < (1 << 62) - window {
+        candidate + window
+    } else if candidate > expected + half_window && candidate >= window {
+        candidate - window
+    } else {
+        candidate
+    }
+}
+
+impl Initial {
+    /// Header up to the packet number for a payload of `payload_len` bytes.
+    fn header(&self, payload_len: usize) -> Vec<u8> {
+        let mut header =
+            vec![LONG_HEADER | self.fixed_bit | PACKET_TYPE_INITIAL | (self.pn_len as u8 - 1)];
+        header.extend_from_slice(&VERSION_1.to_be_bytes());
+        header.push(self.dcid.len() as u8);
+        header.extend_from_slice(&self.dcid);
+        header.push(self.scid.len() as u8);
+        header.extend_from_slice(&self.scid);
+        write_varint(&mut header, self.token.len() as u64);
+        header.extend_from_slice(&self.token);
+        write_varint(&mut header, (self.pn_len + payload_len + TAG_LEN) as u64);
+        header
+    }
+
+    /// Bytes a packet takes besides its payload.
+    fn overhead(&self, payload_len: usize) -> usize {
+        self.header(payload_len).len() + self.pn_len + TAG_LEN
+    }
+
+    fn seal(&self, keys: &Keys) -> Vec<u8> {
+        let mut payload = self.payload.clone();
+        // The header protection sample starts four bytes after the packet number.
+        payload.resize(
+            payload
+                .len()
+                .max(MAX_PN_LEN + SAMPLE_LEN - self.pn_len - TAG_LEN),
+            0,
+        );
+        let mut packet = self.header(payload.len());
+        let pn_offset = packet.len();
+        packet.extend_from_slice(&self.pn.to_be_bytes()[8 - self.pn_len..]);
+        let tag = keys
+            .aead
+            .encrypt_in_place_detached(&keys.nonce(self.pn), &packet, &mut payload)
+            .expect("Initial packets are far below the AEAD limit");
+        packet.extend(payload);
+        packet.extend_from_slice(&tag);
+        let mask = keys.mask(&packet[pn_offset + MAX_PN_LEN..][..SAMPLE_LEN]);
+        packet[0] ^= mask[0] & 0x0f;
+        for i in 0..self.pn_len {
+            packet[pn_offset + i] ^= mask[1 + i];
+        }
+        packet
+    }
+}
+
+/// Builds a client Initial carrying `hello`, a ClientHello message, for a new connection, padded
+/// like a real one.
+pub fn decoy_initial(hello: &[u8]) -> Vec<u8> {
+    let mut packet = Initial {
+        fixed_bit: FIXED_BIT,
+        dcid: rng::next_u64().to_be_bytes().to_vec(),
+        scid: rng::next_u64().to_be_bytes().to_vec(),
+        token: Vec::new(),
+        pn: 0,
+        pn_len: 1,
+        payload: Vec::new(),
+    };
+    let crypto = Frame::Crypto {
+        offset: 0,
+        data: hello,
+    };
+    write_frames(&mut packet.payload, &[crypto]);
+    let padded = MIN_INITIAL_DATAGRAM_LEN.saturating_sub(packet.overhead(MIN_INITIAL_DATAGRAM_LEN));
+    packet
+        .payload
+        .resize(padded.max(packet.payload.len()), FRAME_PADDING);
+    packet.seal(&Keys::initial(&packet.dcid, false))
+}
+
+/// Returns true if `datagram` starts with a QUIC v1 Initial packet.
+pub fn is_initial(datagram: &[u8]) -> bool {
+    parse_header(datagram).is_some()
+}
+
+/// Frames allowed in Initial packets (RFC 9000, section 12.4).
+enum Frame<'a> {
+    Padding,
+    Ping,
+    Ack(Ack),
+    Crypto {
+        offset: u64,
+        data: &'a [u8],
+    },
+    /// Kept as is.
+    ConnectionClose(&'a [u8]),
+}
+
+struct Ack {
+    delay: u64,
+    /// Acknowledged packet numbers, highest first.
+    ranges: Vec<RangeInclusive<u64>>,
+    ecn: Option<[u64; 3]>,
+}
+
+fn parse_frames(payload: &[u8]) -> Option<Vec<Frame<'_>>> {
+    let mut reader = Reader {
+        data: payload,
+        pos: 0,
+    };
+    let mut frames = Vec::new();
+    while reader.pos < payload.len() {
+        let start = reader.pos;
+        let frame = match reader.byte()? {
+            FRAME_PADDING => {
+                while reader.data.get(reader.pos) == Some(&FRAME_PADDING) {
+                    reader.pos += 1;
+                }
+                Frame::Padding
+            }
+            FRAME_PING => Frame::Ping,
+            kind @ (FRAME_ACK | FRAME_ACK_ECN) => {
+                let largest = reader.varint()?;
+                let delay = reader.varint()?;
+                let count = reader.varint()?;
+                let first = reader.varint()?;
+                let mut ranges = vec![largest.checked_sub(first)?..=largest];
+                for _ in 0..count {
+                    let gap = reader.varint()?;
+                    let len = reader.varint()?;
+                    let end = ranges.last()?.start().checked_sub(gap + 2)?;
+                    ranges.push(end.checked_sub(len)?..=end);
+                }
+                let ecn = match kind {
+                    FRAME_ACK_ECN => Some([reader.varint()?, reader.varint()?, reader.varint()?]),
+                    _ => None,
+                };
+                Frame::Ack(Ack { delay, ranges, ecn })
+            }
+            FRAME_CRYPTO => {
+                let offset = reader.varint()?;
+                let len = reader.varint()? as usize;
+                let data = &payload[reader.range(len)?];
+                Frame::Crypto { offset, data }
+            }
+            FRAME_CONNECTION_CLOSE => {
+                reader.varint()?;
+                reader.varint()?;
+                let len = reader.varint()? as usize;
+                reader.range(len)?;
+                Frame::ConnectionClose(&payload[start..reader.pos])
+            }
+            _ => return None,
+        };
+        frames.push(frame);
+    }
+    Some(frames)
+}
+
+/// Writes `frames`, leaving out padding.
+fn write_frames(buf: &mut Vec<u8>, frames: &[Frame]) {
+    for frame in frames {
+        match frame {
+            Frame::Padding => {}
+            Frame::Ping => buf.push(FRAME_PING),
+            Frame::Ack(ack) => {
+                let Some((first, rest)) = ack.ranges.split_first() else {
+                    continue;
+                };
+                buf.push(if ack.ecn.is_some() {
+                    FRAME_ACK_ECN
+                } else {
+                    FRAME_ACK
+                });
+                write_varint(buf, *first.end());
+                write_varint(buf, ack.delay);
+                write_varint(buf, rest.len() as u64);
+                write_varint(buf, first.end() - first.start());
+                let mut previous = first;
+                for range in rest {
+                    write_varint(buf, previous.start() - range.end() - 2);
+                    write_varint(buf, range.end() - range.start());
+                    previous = range;
+                }
+                for count in ack.ecn.iter().flatten() {
+                    write_varint(buf, *count);
+                }
+            }
+            Frame::Crypto { offset, data } => {
+                buf.push(FRAME_CRYPTO);
+                write_varint(buf, *offset);
+                write_varint(buf, data.len() as u64);
+                buf.extend_from_slice(data);
+            }
+            Frame::ConnectionClose(frame) => buf.extend_from_slice(frame),
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn range(&mut self, len: usize) -> Option<Range<usize>> {
+        let range = self.pos..self.pos.checked_add(len)?;
+        if range.end > self.data.len() {
+            return None;
+        }
+        self.pos = range.end;
+        Some(range)
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.byte()?;
+        let len = 1 << (first >> 6);
+        let mut value = (first & 0x3f) as u64;
+        for _ in 1..len {
+            value = value << 8 | self.byte()? as u64;
+        }
+        Some(value)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    match value {
+        0..=0x3f => buf.push(value as u8),
+        0x40..=0x3fff => buf.extend_from_slice(&(value as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => buf.extend_from_slice(&(value as u32 | 0x8000_0000).to_be_bytes()),
+        _ => buf.extend_from_slice(&(value | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+}
+
+/// Packet numbers taken by the split ClientHello, which the client doesn't know about, so its
+/// later Initial packets and the acknowledgments of the server are shifted to make room.
+#[derive(Clone, Copy)]
+struct Renumbering {
+    first: u64,
+    /// Packets of the client that carried the ClientHello.
+    original: u64,
+    /// Packets that carry it now.
+    sent: u64,
+}
+
+impl Renumbering {
+    fn offset(&self) -> u64 {
+        self.sent - self.original
+    }
+
+    /// Maps packet numbers acknowledged by the server to those the client sent, its ClientHello
+    /// being acknowledged only once all of the packets it was split into are.
+    fn map_acked(&self, ranges: &[RangeInclusive<u64>]) -> Vec<RangeInclusive<u64>> {
+        let hello_end = self.first + self.sent;
+        let mut mapped = Vec::new();
+        for range in ranges {
+            let (start, end) = (*range.start(), *range.end());
+            if start < self.first {
+                mapped.push(start..=end.min(self.first - 1));
+            }
+            if end >= hello_end {
+                mapped.push(start.max(hello_end) - self.offset()..=end - self.offset());
+            }
+            if start <= self.first && end >= hello_end - 1 {
+                mapped.push(self.first..=self.first + self.original - 1);
+            }
+        }
+        mapped.sort_unstable_by_key(|range| std::cmp::Reverse(*range.start()));
+        let mut merged: Vec<RangeInclusive<u64>> = Vec::new();
+        for range in mapped {
+            match merged.last_mut() {
+                Some(last) if *range.end() + 1 >= *last.start() => {
+                    *last = *range.start()..=*last.end().max(range.end());
+                }
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+}
+
+/// Initial datagrams of a QUIC connection, from the client's first to its last, which are held
+/// until they carry the whole ClientHello and then resent with the ClientHello split across
+/// more datagrams.
+pub struct Session {
+    held: Vec<Vec<u8>>,
+    held_since: Option<Instant>,
+    /// The ClientHello has been sent on, split or not.
+    sent: bool,
+    /// Initial keys, derived from the destination connection ID of the first client Initial.
+    client_keys: Option<Keys>,
+    server_keys: Option<Keys>,
+    largest_client_pn: Option<u64>,
+    largest_server_pn: Option<u64>,
+    renumbering: Option<Renumbering>,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            held: Vec::new(),
+            held_since: None,
+            sent: false,
+            client_keys: None,
+            server_keys: None,
+            largest_client_pn: None,
+            largest_server_pn: None,
+            renumbering: None,
+        }
+    }
+
+    /// When the first datagram still held was received.
+    pub fn held_since(&self) -> Option<Instant> {
+        self.held_since
+    }
+
+    /// Returns the datagrams to send to the server for `datagram` from the client, holding it
+    /// until the ClientHello is complete. `split` gets the ClientHello, or None if it can't be
+    /// parsed, and returns offsets into it to split it at.
+    pub fn client_datagram(
+        &mut self,
+        datagram: Vec<u8>,
+        split: impl FnOnce(Option<&ClientHello>) -> Vec<usize>,
+    ) -> Vec<Vec<u8>> {
+        if self.sent {
+            return vec![self.renumber(datagram)];
+        }
+        match initial_dcid(&datagram) {
+            Some(dcid) if self.held.is_empty() => {
+                self.client_keys = Some(Keys::initial(dcid, false));
+                self.server_keys = Some(Keys::initial(dcid, true));
+            }
+            None if self.held.is_empty() => {
+                self.sent = true;
+                return vec![datagram];
+            }
+            _ => {}
+        }
+        self.held.push(datagram);
+        self.held_since.get_or_insert_with(Instant::now);
+        match self.split_hello(split) {
+            Some(datagrams) => datagrams,
+            None if self.held.len() >= MAX_HELD_DATAGRAMS => self.release(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the held datagrams as they are, giving up on the ClientHello.
+    pub fn release(&mut self) -> Vec<Vec<u8>> {
+        self.sent = true;
+        self.held_since = None;
+        std::mem::take(&mut self.held)
+    }
+
+    /// Splits the ClientHello if the held datagrams carry all of it, returning None if they
+    /// don't yet.
+    fn split_hello(
+        &mut self,
+        split: impl FnOnce(Option<&ClientHello>) -> Vec<usize>,
+    ) -> Option<Vec<Vec<u8>>> {
+        let keys = self.client_keys.as_ref()?;
+        let mut packets = Vec::new();
+        let mut others = Vec::new();
+        let mut largest_pn = self.largest_client_pn;
+        for datagram in &self.held {
+            let mut rest = &datagram[..];
+            while let Some((packet, len)) = open(rest, keys, largest_pn) {
+                largest_pn = largest_pn.max(Some(packet.pn));
+                packets.push(packet);
+                rest = &rest[len..];
+            }
+            if !rest.is_empty() {
+                others.push(rest.to_vec());
+            }
+        }
+
+        let mut crypto = Vec::new();
+        let mut extra = Vec::new();
+        for packet in &packets {
+            for frame in parse_frames(&packet.payload)? {
+                match frame {
+                    Frame::Crypto { offset, data } => crypto.push((offset as usize, data)),
+                    Frame::Padding => {}
+                    frame => extra.push(frame),
+                }
+            }
+        }
+        crypto.sort_unstable_by_key(|(offset, _)| *offset);
+        let mut hello = Vec::new();
+        for (offset, data) in crypto {
+            if offset > hello.len() {
+                return None;
+            }
+            if offset + data.len() > hello.len() {
+                hello.extend_from_slice(&data[hello.len() - offset..]);
+            }
+        }
+        let hello_len = match hello.get(..4)? {
+            [1, len @ ..] => 4 + u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize,
+            _ => return Some(self.release()),
+        };
+        if hello_len > tls::MAX_HELLO_LEN {
+            return Some(self.release());
+        }
+        if hello.len() < hello_len {
+            return None;
+        }
+
+        let mut positions = split(tls::parse_handshake_client_hello(&hello[..hello_len]).as_ref());
+        positions.retain(|pos| (1..hello_len).contains(pos));
+        positions.sort_unstable();
+        positions.dedup();
+        let first_pn = packets.iter().map(|packet| packet.pn).min()?;
+        let consecutive = largest_pn? - first_pn + 1 == packets.len() as u64;
+        if positions.is_empty() || !consecutive {
+            return Some(self.release());
+        }
+
+        let datagram_len = self
+            .held
+            .iter()
+            .map(Vec::len)
+            .max()?
+            .max(MIN_INITIAL_DATAGRAM_LEN);
+        let template = &packets[0];
+        let mut split_packets = Vec::new();
+        let mut start = 0;
+        while start < hello.len() {
+            let frames = if split_packets.is_empty() {
+                std::mem::take(&mut extra)
+            } else {
+                Vec::new()
+            };
+            let mut payload = Vec::new();
+            write_frames(&mut payload, &frames);
+            let room = datagram_len
+                .checked_sub(template.overhead(datagram_len) + payload.len())?
+                .checked_sub(MAX_CRYPTO_FRAME_HEADER_LEN)?;
+            let cut = positions
+                .iter()
+                .copied()
+                .find(|pos| *pos > start)
+                .unwrap_or(hello.len());
+            let end = cut.min(start + room.max(1));
+            let crypto = Frame::Crypto {
+                offset: start as u64,
+                data: &hello[start..end],
+            };
+            write_frames(&mut payload, &[crypto]);
+            let mut packet = Initial {
+                fixed_bit: template.fixed_bit,
+                dcid: template.dcid.clone(),
+                scid: template.scid.clone(),
+                token: template.token.clone(),
+                pn: first_pn + split_packets.len() as u64,
+                pn_len: template.pn_len,
+                payload,
+            };
+            let padded = datagram_len.saturating_sub(packet.overhead(datagram_len));
+            packet
+                .payload
+                .resize(padded.max(packet.payload.len()), FRAME_PADDING);
+            split_packets.push(packet.seal(keys));
+            start = end;
+        }
+
+        let renumbering = Renumbering {
+            first: first_pn,
+            original: packets.len() as u64,
+            sent: split_packets.len() as u64,
+        };
+        self.largest_client_pn = largest_pn;
+        self.renumbering = Some(renumbering);
+        self.release();
+        split_packets.extend(others);
+        Some(split_packets)
+    }
+
+    /// Shifts the packet number of a client Initial packet sent after the ClientHello.
+    fn renumber(&mut self, datagram: Vec<u8>) -> Vec<u8> {
+        let Some(renumbering) = self.renumbering else {
+            return datagram;
+        };
+        let Some(keys) = &self.client_keys else {
+            return datagram;
+        };
+        let Some((mut packet, len)) = open(&datagram, keys, self.largest_client_pn) else {
+            return datagram;
+        };
+        self.largest_client_pn = self.largest_client_pn.max(Some(packet.pn));
+        if packet.pn >= renumbering.first + renumbering.original {
+            packet.pn += renumbering.offset();
+        }
+        let mut renumbered = packet.seal(keys);
+        renumbered.extend_from_slice(&datagram[len..]);
+        renumbered
+    }
+
+    /// Returns `datagram` from the server with the acknowledgments in its Initial packet mapped
+    /// to the packets the client sent.
+    pub fn server_datagram(&mut self, datagram: Vec<u8>) -> Vec<u8> {
+        if is_retry(&datagram) {
+            // The client starts over with keys from the connection ID the server picked.
+            *self = Session::new();
+            return datagram;
+        }
+        let (Some(renumbering), Some(keys)) = (&self.renumbering, &self.server_keys) else {
+            return datagram;
+        };
+        let Some((mut packet, len)) = open(&datagram, keys, self.largest_server_pn) else {
+            return datagram;
+        };
+        self.largest_server_pn = self.largest_server_pn.max(Some(packet.pn));
+        let Some(mut frames) = parse_frames(&packet.payload) else {
+            return datagram;
+        };
+        for frame in &mut frames {
+            if let Frame::Ack(ack) = frame {
+                ack.ranges = renumbering.map_acked(&ack.ranges);
+            }
+        }
+        let mut payload = Vec::new();
+        write_frames(&mut payload, &frames);
+        payload.resize(payload.len().max(packet.payload.len()), FRAME_PADDING);
+        packet.payload = payload;
+        let mut mapped = packet.seal(keys);
+        mapped.extend_from_slice(&datagram[len..]);
+        mapped
+    }
+}
@@ -0,0 +1,525 @@
+use std::{
+    collections::HashMap,
+    io,
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+    slice,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, LazyLock, Mutex, OnceLock,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use tokio::{
+    io::unix::AsyncFd,
+    net::{TcpStream, UdpSocket},
+    time,
+};
+
+use crate::{fake::MAX_DECOY_LEN, rng, sockopt, strategy::Fake, Args};
+
+/// How far behind the connection a badseq fake is, so that the destination takes it for an old
+/// duplicate whatever its receive window.
+const BADSEQ_OFFSET: u32 = 0x20000;
+/// Flips some bits of the TCP checksum for badsum, but not all since 0 and 0xffff are equivalent.
+const BADSUM_XOR: u16 = 0x5a5a;
+/// Added to two round trips when waiting for an answer to a checksum probe.
+const CHECKSUM_PROBE_SLACK: time::Duration = time::Duration::from_millis(20);
+const MAX_CHECKSUM_PROBE_WAIT: time::Duration = time::Duration::from_millis(500);
+const CHECKSUMS_UNKNOWN: u8 = 0;
+const CHECKSUMS_PROBING: u8 = 1;
+const CHECKSUMS_DROPPED: u8 = 2;
+const CHECKSUMS_FIXED: u8 = 3;
+const MAX_TRACKED_HANDSHAKES: usize = 4096;
+/// Connections can send their first payload well after connecting, after STARTTLS for instance.
+const MAX_HANDSHAKE_AGE: time::Duration = time::Duration::from_secs(300);
+/// The SYN-ACK reaches the raw socket along with the connection, but is read separately.
+const HANDSHAKE_WAIT: time::Duration = time::Duration::from_millis(50);
+const TCP_FLAGS_SYN_ACK: u32 = 0x12;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+const TCP_WINDOW: u16 = 0xffff;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const IP_MORE_FRAGMENTS: u16 = 0x2000;
+
+static INJECTOR: OnceLock<Result<Injector, String>> = OnceLock::new();
+/// Send-only raw IPv4 socket for --quic-ipfrag.
+static FRAGMENTER: OnceLock<Result<AsyncFd<Socket>, String>> = OnceLock::new();
+/// Whether segments with a bad checksum reach destinations, as probed by the first badsum fake.
+static CHECKSUMS: AtomicU8 = AtomicU8::new(CHECKSUMS_UNKNOWN);
+
+/// Initial sequence numbers of both sides, from the SYN-ACKs seen by the raw sockets, by
+/// destination and local port.
+static HANDSHAKES: LazyLock<Mutex<HashMap<(SocketAddr, u16), Handshake>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy)]
+struct Handshake {
+    /// Of our first byte.
+    seq: u32,
+    /// Of the destination's first byte.
+    peer_seq: u32,
+    seen: time::Instant,
+}
+
+/// Raw TCP sockets which send crafted segments and watch SYN-ACKs to learn the sequence numbers
+/// of upstream connections, which the kernel doesn't tell without TCP_REPAIR.
+struct Injector {
+    v4: Arc<AsyncFd<Socket>>,
+    v6: Option<Arc<AsyncFd<Socket>>>,
+}
+
+/// Opens the raw sockets unless they are open, returning why they can't be otherwise. Must be
+/// called before the connections to inject into are made.
+pub fn start(args: &Args) -> Result<(), String> {
+    let injector = INJECTOR.get_or_init(|| {
+        let v4 = Arc::new(open(Domain::IPV4, args).map_err(|err| format!("{err:#}"))?);
+        tokio::spawn(track(Arc::clone(&v4), false));
+        let v6 = match open(Domain::IPV6, args) {
+            Ok(socket) => {
+                let socket = Arc::new(socket);
+                tokio::spawn(track(Arc::clone(&socket), true));
+                Some(socket)
+            }
+            Err(err) => {
+                eprintln!("no raw IPv6 socket, IPv6 destinations get no injected fakes: {err:#}");
+                None
+            }
+        };
+        Ok(Injector { v4, v6 })
+    });
+    injector.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// Opens the socket for --quic-ipfrag unless it is open, returning why it can't be otherwise.
+pub fn start_fragmenter(args: &Args) -> Result<(), String> {
+    let fragmenter = FRAGMENTER.get_or_init(|| {
+        let open = || -> Result<AsyncFd<Socket>> {
+            // IPPROTO_RAW sockets only send, with the IP header we build.
+            let socket = Socket::new(
+                Domain::IPV4,
+                Type::RAW,
+                Some(Protocol::from(libc::IPPROTO_RAW)),
+            )
+            .context("cannot open raw socket")?;
+            socket.set_nonblocking(true)?;
+            if args.fwmark != 0 {
+                socket.set_mark(args.fwmark)?;
+            }
+            Ok(AsyncFd::new(socket)?)
+        };
+        open().map_err(|err| format!("{err:#}"))
+    });
+    fragmenter.as_ref().map(|_| ()).map_err(Clone::clone)
+}
+
+/// Sends `datagram` from the local address of `upstream` as two IPv4 fragments, the first one
+/// carrying `offset` bytes of it, which must be a multiple of 8 below its length.
+pub async fn send_fragmented(upstream: &UdpSocket, datagram: &[u8], offset: usize) -> Result<()> {
+    let socket = match FRAGMENTER.get() {
+        Some(Ok(socket)) => socket,
+        Some(Err(err)) => return Err(anyhow!("{err}")),
+        None => return Err(anyhow!("no raw socket")),
+    };
+    let (SocketAddr::V4(local), SocketAddr::V4(peer)) =
+        (upstream.local_addr()?, upstream.peer_addr()?)
+    else {
+        return Err(anyhow!("only IPv4 datagrams can be fragmented"));
+    };
+    if !offset.is_multiple_of(8) || offset >= datagram.len() {
+        return Err(anyhow!(
+            "cannot fragment {} bytes at {offset}",
+            datagram.len()
+        ));
+    }
+    let ttl = SockRef::from(upstream).ttl()? as u8;
+
+    let udp_len = UDP_HEADER_LEN + datagram.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&local.port().to_be_bytes());
+    udp.extend_from_slice(&peer.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(datagram);
+    let mut pseudo_header = local.ip().octets().to_vec();
+    pseudo_header.extend_from_slice(&peer.ip().octets());
+    pseudo_header.extend_from_slice(&[0, libc::IPPROTO_UDP as u8]);
+    pseudo_header.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    // Zero means no checksum in UDP.
+    let checksum = match checksum(&[&pseudo_header, &udp]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    let id = rng::next_u64() as u16 | 1;
+    let cut = UDP_HEADER_LEN + offset;
+    let to = SockAddr::from(SocketAddr::from((*peer.ip(), 0)));
+    for (start, end) in [(0, cut), (cut, udp_len)] {
+        let more_fragments = if end < udp_len { IP_MORE_FRAGMENTS } else { 0 };
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&((IPV4_HEADER_LEN + end - start) as u16).to_be_bytes());
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(&(more_fragments | (start / 8) as u16).to_be_bytes());
+        // The kernel fills in the header checksum.
+        packet.extend_from_slice(&[ttl, libc::IPPROTO_UDP as u8, 0, 0]);
+        packet.extend_from_slice(&local.ip().octets());
+        packet.extend_from_slice(&peer.ip().octets());
+        packet.extend_from_slice(&udp[start..end]);
+        loop {
+            let mut guard = socket.writable().await?;
+            if let Ok(sent) = guard.try_io(|socket| socket.get_ref().send_to(&packet, &to)) {
+                sent?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn open(domain: Domain, args: &Args) -> Result<AsyncFd<Socket>> {
+    let socket =
+        Socket::new(domain, Type::RAW, Some(Protocol::TCP)).context("cannot open raw socket")?;
+    let ipv6 = domain == Domain::IPV6;
+    socket.set_nonblocking(true)?;
+    sockopt::set_header_included(socket.as_raw_fd(), ipv6)?;
+    if args.fwmark != 0 {
+        socket.set_mark(args.fwmark)?;
+    }
+    // IPv4 raw sockets get the IP header, IPv6 ones start at the TCP header.
+    let mut filter = Vec::new();
+    if !ipv6 {
+        filter.push(bpf(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0));
+    }
+    filter.extend([
+        bpf(libc::BPF_LD | libc::BPF_B | libc::BPF_IND, 13),
+        bpf(
+            libc::BPF_ALU | libc::BPF_AND | libc::BPF_K,
+            TCP_FLAGS_SYN_ACK,
+        ),
+        libc::sock_filter {
+            code: (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 1,
+            k: TCP_FLAGS_SYN_ACK,
+        },
+        bpf(libc::BPF_RET | libc::BPF_K, u32::MAX),
+        bpf(libc::BPF_RET | libc::BPF_K, 0),
+    ]);
+    sockopt::attach_filter(socket.as_raw_fd(), &filter)?;
+    Ok(AsyncFd::new(socket)?)
+}
+
+fn bpf(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+/// Records the SYN-ACKs received by `socket`.
+async fn track(socket: Arc<AsyncFd<Socket>>, ipv6: bool) {
+    let mut buf = [MaybeUninit::<u8>::uninit(); 128];
+    loop {
+        let mut guard = match socket.readable().await {
+            Ok(guard) => guard,
+            Err(err) => {
+                eprintln!("raw socket stopped, fakes won't be injected: {err}");
+                return;
+            }
+        };
+        let (len, from) = match guard.try_io(|socket| socket.get_ref().recv_from(&mut buf)) {
+            Ok(Ok(received)) => received,
+            Ok(Err(err)) => {
+                debug!("cannot read raw socket: {err}");
+                continue;
+            }
+            Err(_would_block) => continue,
+        };
+        let packet = unsafe { slice::from_raw_parts(buf.as_ptr().cast::<u8>(), len) };
+        let (peer_ip, tcp) = match (ipv6, from.as_socket()) {
+            (true, Some(from)) => (from.ip(), packet),
+            (false, _) if packet.len() >= IPV4_HEADER_LEN => {
+                let src: [u8; 4] = packet[12..16].try_into().unwrap();
+                let header_len = (packet[0] & 0xf) as usize * 4;
+                (
+                    IpAddr::from(src),
+                    packet.get(header_len..).unwrap_or_default(),
+                )
+            }
+            _ => continue,
+        };
+        if tcp.len() < 12 {
+            continue;
+        }
+        let peer = SocketAddr::new(peer_ip, u16::from_be_bytes([tcp[0], tcp[1]]));
+        let local_port = u16::from_be_bytes([tcp[2], tcp[3]]);
+        let handshake = Handshake {
+            seq: u32::from_be_bytes(tcp[8..12].try_into().unwrap()),
+            peer_seq: u32::from_be_bytes(tcp[4..8].try_into().unwrap()).wrapping_add(1),
+            seen: time::Instant::now(),
+        };
+
+        let mut handshakes = HANDSHAKES.lock().unwrap();
+        if handshakes.len() >= MAX_TRACKED_HANDSHAKES {
+            handshakes.retain(|_, handshake| handshake.seen.elapsed() < MAX_HANDSHAKE_AGE);
+        }
+        if handshakes.len() >= MAX_TRACKED_HANDSHAKES {
+            let oldest = handshakes
+                .iter()
+                .min_by_key(|(_, handshake)| handshake.seen);
+            if let Some(oldest) = oldest.map(|(key, _)| *key) {
+                handshakes.remove(&oldest);
+            }
+        }
+        handshakes.insert((peer, local_port), handshake);
+    }
+}
+
+async fn handshake(local: SocketAddr, peer: SocketAddr) -> Option<Handshake> {
+    let deadline = time::Instant::now() + HANDSHAKE_WAIT;
+    loop {
+        if let Some(handshake) = HANDSHAKES.lock().unwrap().get(&(peer, local.port())) {
+            return Some(*handshake);
+        }
+        if time::Instant::now() >= deadline {
+            return None;
+        }
+        time::sleep(time::Duration::from_millis(1)).await;
+    }
+}
+
+/// Sends `payload`, cut to the MSS, in a segment of the connection of `stream` that the
+/// destination will discard as `fake` says, with `ttl`. The connection itself is left alone.
+pub async fn send_fake(stream: &TcpStream, payload: &[u8], fake: Fake, ttl: u32) -> Result<()> {
+    let injector = match INJECTOR.get() {
+        Some(Ok(injector)) => injector,
+        _ => return Err(anyhow!("raw sockets are not open")),
+    };
+    let (local, peer) = (stream.local_addr()?, stream.peer_addr()?);
+    let socket = match peer {
+        SocketAddr::V4(_) => &injector.v4,
+        SocketAddr::V6(_) => injector
+            .v6
+            .as_ref()
+            .ok_or_else(|| anyhow!("no raw IPv6 socket"))?,
+    };
+    let handshake = handshake(local, peer)
+        .await
+        .ok_or_else(|| anyhow!("missed the handshake"))?;
+
+    // Sequence numbers sent, acknowledged or not, and received so far.
+    let fd = stream.as_raw_fd();
+    let info = sockopt::get_tcp_info(fd)?;
+    let (Some(acked), Some(received), Some(notsent)) = (
+        info.tcpi_bytes_acked(),
+        info.tcpi_bytes_received(),
+        info.tcpi_notsent_bytes(),
+    ) else {
+        return Err(anyhow!("kernel doesn't report the bytes sent and received"));
+    };
+    let in_flight = sockopt::outq(fd)?.saturating_sub(notsent);
+    // The kernel counts our SYN as acknowledged.
+    let seq = handshake
+        .seq
+        .wrapping_add(acked.saturating_sub(1) as u32)
+        .wrapping_add(in_flight);
+    let target = Target {
+        socket,
+        local,
+        peer,
+        ack: handshake.peer_seq.wrapping_add(received as u32),
+        ttl,
+    };
+
+    let badsum = fake.badsum && checksums_dropped(stream, &target, seq).await?;
+    if !badsum && !fake.badseq {
+        return Err(anyhow!("bad checksums are not dropped, see above"));
+    }
+    let seq = match fake.badseq {
+        true => seq.wrapping_sub(BADSEQ_OFFSET),
+        false => seq,
+    };
+    let mss = sockopt::mss(fd).unwrap_or(536) as usize;
+    let payload = &payload[..payload.len().min(mss).min(MAX_DECOY_LEN)];
+    Ok(target.send(seq, payload, badsum).await?)
+}
+
+/// Returns true once segments with a bad checksum are known not to reach the destination, probing
+/// with `stream` if that hasn't been done. Offloading the transmit checksum to a network card or
+/// a hypervisor can get it fixed along the way, which the configuration of local interfaces
+/// doesn't always tell.
+async fn checksums_dropped(stream: &TcpStream, target: &Target<'_>, seq: u32) -> Result<bool> {
+    match CHECKSUMS.compare_exchange(
+        CHECKSUMS_UNKNOWN,
+        CHECKSUMS_PROBING,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    ) {
+        Ok(_) => {}
+        Err(CHECKSUMS_DROPPED) => return Ok(true),
+        Err(CHECKSUMS_PROBING) => return Err(anyhow!("bad checksums are being probed")),
+        Err(_) => return Ok(false),
+    }
+    let dropped = probe_checksums(stream, target, seq).await;
+    let state = match dropped {
+        Ok(Some(true)) => CHECKSUMS_DROPPED,
+        Ok(Some(false)) => {
+            eprintln!(
+                "{} answered a segment with a bad checksum, which must have been fixed on the way; \
+                 disabling badsum fooling",
+                target.peer
+            );
+            CHECKSUMS_FIXED
+        }
+        Ok(None) | Err(_) => CHECKSUMS_UNKNOWN,
+    };
+    CHECKSUMS.store(state, Ordering::Relaxed);
+    match dropped? {
+        Some(dropped) => Ok(dropped),
+        None => Err(anyhow!("{} answered no checksum probe", target.peer)),
+    }
+}
+
+/// Sends a byte long before the stream, which the destination acknowledges at once unless it
+/// drops it, first with a bad checksum, then with a good one since out of window segments are
+/// acknowledged at most twice a second. Returns None if neither is answered. Answers are counted
+/// with `tcpi_segs_in`, so this must be done while the destination is waiting for us.
+async fn probe_checksums(
+    stream: &TcpStream,
+    target: &Target<'_>,
+    seq: u32,
+) -> Result<Option<bool>> {
+    let segs_in = || -> Result<u32> {
+        sockopt::get_tcp_info(stream.as_raw_fd())?
+            .tcpi_segs_in()
+            .ok_or_else(|| anyhow!("kernel doesn't report the segments received"))
+    };
+    let rtt = sockopt::get_tcp_info(stream.as_raw_fd())?
+        .tcpi_rtt()
+        .unwrap_or(0);
+    let wait = (time::Duration::from_micros(rtt as u64) * 2 + CHECKSUM_PROBE_SLACK)
+        .min(MAX_CHECKSUM_PROBE_WAIT);
+    let seq = seq.wrapping_sub(BADSEQ_OFFSET);
+    // A TTL low enough to fool DPI would keep probes from the destination.
+    let target = Target {
+        ttl: sockopt::ttl(stream)?,
+        ..*target
+    };
+    for badsum in [true, false] {
+        let before = segs_in()?;
+        target.send(seq, &[0], badsum).await?;
+        time::sleep(wait).await;
+        if segs_in()? != before {
+            return Ok(Some(!badsum));
+        }
+    }
+    Ok(None)
+}
+
+/// Connection to inject segments into.
+struct Target<'a> {
+    socket: &'a AsyncFd<Socket>,
+    local: SocketAddr,
+    peer: SocketAddr,
+    ack: u32,
+    ttl: u32,
+}
+
+impl Target<'_> {
+    async fn send(&self, seq: u32, payload: &[u8], badsum: bool) -> io::Result<()> {
+        let packet = build_packet(self, seq, payload, badsum);
+        let to = SockAddr::from(SocketAddr::new(self.peer.ip(), 0));
+        loop {
+            let mut guard = self.socket.writable().await?;
+            match guard.try_io(|socket| socket.get_ref().send_to(&packet, &to)) {
+                Ok(sent) => return sent.map(|_| ()),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+/// Builds an IP packet to `target` with a TCP segment carrying `payload`, with a wrong checksum
+/// if `badsum`.
+fn build_packet(target: &Target, seq: u32, payload: &[u8], badsum: bool) -> Vec<u8> {
+    let Target {
+        local,
+        peer,
+        ack,
+        ttl,
+        ..
+    } = *target;
+    let tcp_len = TCP_HEADER_LEN + payload.len();
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + tcp_len);
+    let pseudo_header = match (local.ip(), peer.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((IPV4_HEADER_LEN + tcp_len) as u16).to_be_bytes());
+            // The kernel fills in the ID and the header checksum.
+            packet.extend_from_slice(&[0, 0, 0x40, 0, ttl as u8, libc::IPPROTO_TCP as u8, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let mut pseudo_header = packet[12..20].to_vec();
+            pseudo_header.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+            pseudo_header.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            pseudo_header
+        }
+        (src, dst) => {
+            let (src, dst) = (to_ipv6(src), to_ipv6(dst));
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[libc::IPPROTO_TCP as u8, ttl as u8]);
+            packet.extend_from_slice(&src);
+            packet.extend_from_slice(&dst);
+            let mut pseudo_header = packet[8..40].to_vec();
+            pseudo_header.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, libc::IPPROTO_TCP as u8]);
+            pseudo_header
+        }
+    };
+
+    let tcp_start = packet.len();
+    packet.extend_from_slice(&local.port().to_be_bytes());
+    packet.extend_from_slice(&peer.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.extend_from_slice(&[(TCP_HEADER_LEN as u8 / 4) << 4, TCP_FLAGS_PSH_ACK]);
+    packet.extend_from_slice(&TCP_WINDOW.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+    packet.extend_from_slice(payload);
+    let mut checksum = checksum(&[&pseudo_header, &packet[tcp_start..]]);
+    if badsum {
+        checksum ^= BADSUM_XOR;
+    }
+    packet[tcp_start + 16..tcp_start + 18].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Internet checksum of `parts`, all but the last of which must have an even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            sum += u16::from_be_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u32;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
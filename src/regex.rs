@@ -0,0 +1,172 @@
+use std::{fs, path::Path};
+
+use ::regex::{RegexBuilder, RegexSetBuilder};
+use anyhow::{anyhow, Context, Result};
+
+use crate::hostlist::normalize;
+
+/// Memory the --host-regex lines may compile to, each and all together, so that a pattern like
+/// `(a{1000}){1000}` fails to load instead of eating memory on every connection.
+const SIZE_LIMIT: usize = 1 << 20;
+/// Memory the lazy DFA of the set may cache while matching, past which it falls back to slower
+/// matching, still in time linear in the host name.
+const DFA_SIZE_LIMIT: usize = 1 << 20;
+
+/// Host name regexes from a --host-regex file, one per line.
+pub struct RegexSet {
+    lines: Vec<String>,
+    set: ::regex::RegexSet,
+}
+
+impl RegexSet {
+    /// Reads one regex per line, skipping blank lines and those starting with #.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let mut lines = Vec::new();
+        let mut patterns = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // Compiled alone first, to tell which line is bad.
+            let pattern = anchored(line);
+            RegexBuilder::new(&pattern)
+                .size_limit(SIZE_LIMIT)
+                .dfa_size_limit(DFA_SIZE_LIMIT)
+                .build()
+                .map_err(|err| anyhow!("{}:{}: regex {line:?}: {err}", path.display(), i + 1))?;
+            lines.push(line.to_owned());
+            patterns.push(pattern);
+        }
+        let set = RegexSetBuilder::new(&patterns)
+            .size_limit(SIZE_LIMIT)
+            .dfa_size_limit(DFA_SIZE_LIMIT)
+            .build()
+            .map_err(|err| anyhow!("{}: regexes too big together: {err}", path.display()))?;
+        Ok(RegexSet { lines, set })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns the first regex matching all of `host`, which is compared in lowercase.
+    pub fn lookup(&self, host: &str) -> Option<&str> {
+        let first = self.set.matches(&normalize(host)).into_iter().next()?;
+        Some(&self.lines[first])
+    }
+}
+
+/// Makes `line` match whole host names only.
+fn anchored(line: &str) -> String {
+    format!("^(?:{line})$")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    /// Writes `text` to a --host-regex file of its own for `test`.
+    fn file(test: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("adpi-regex-{test}-{}", std::process::id()));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn load(test: &str, text: &str) -> Result<RegexSet> {
+        let path = file(test, text);
+        let set = RegexSet::load(&path);
+        fs::remove_file(&path).unwrap();
+        set
+    }
+
+    #[test]
+    fn matches_whole_lowercase_host_names() {
+        let set = load(
+            "whole",
+            "# blocked\n\n(www\\.)?example\\.com\n  ^[a-z]+\\.example\\.org$  \n.*\\.test\n",
+        )
+        .unwrap();
+        assert_eq!(set.len(), 3);
+        assert_eq!(
+            set.lookup("WWW.Example.com"),
+            Some("(www\\.)?example\\.com")
+        );
+        assert_eq!(set.lookup("example.com."), Some("(www\\.)?example\\.com"));
+        assert_eq!(set.lookup("mail.example.com"), None);
+        assert_eq!(set.lookup("example.com.ru"), None);
+        assert_eq!(
+            set.lookup("cdn.example.org"),
+            Some("^[a-z]+\\.example\\.org$")
+        );
+        assert_eq!(set.lookup("cdn1.example.org"), None);
+        assert_eq!(set.lookup("a.b.test"), Some(".*\\.test"));
+    }
+
+    #[test]
+    fn returns_the_first_matching_line() {
+        let set = load("first", "a.*\n.*\\.com\nabc\\.com\n").unwrap();
+        assert_eq!(set.lookup("abc.com"), Some("a.*"));
+        assert_eq!(set.lookup("b.com"), Some(".*\\.com"));
+    }
+
+    #[test]
+    fn matches_nested_stars_in_linear_time() {
+        for pattern in ["(a*)*b", "(?:a|a)*b", "(a|aa)*c", "(?:a?){30}a{30}b"] {
+            let set = load("linear", pattern).unwrap();
+            let text = "a".repeat(100_000);
+            let start = Instant::now();
+            assert_eq!(set.lookup(&text), None, "{pattern}");
+            let matched = set.lookup(&format!("{}b", &text[..30])).is_some();
+            assert_eq!(matched, pattern.ends_with('b'), "{pattern}");
+            assert!(
+                start.elapsed() < Duration::from_secs(1),
+                "{pattern} took {:?}",
+                start.elapsed()
+            );
+        }
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        for (text, error) in [
+            ("a\n# (\nb(\n", ":3: regex \"b(\""),
+            ("a\n\n[z-a]\n", ":3: regex \"[z-a]\""),
+            ("x{2,1}\n", ":1: regex \"x{2,1}\""),
+        ] {
+            let err = format!("{}", load("bad", text).err().unwrap());
+            assert!(err.contains(error), "{text:?}: {err}");
+        }
+        // Far past the nesting limit, where recursing would overflow the stack.
+        let nested = format!("{}a{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert!(load("nested", &nested).is_err());
+    }
+
+    #[test]
+    fn limits_compiled_size() {
+        for pattern in ["(a{1000}){1000}", "((a{100}){100}){100}"] {
+            let err = format!("{:#}", load("size", pattern).err().unwrap());
+            assert!(err.contains(":1: regex"), "{err}");
+            assert!(err.contains("size limit"), "{err}");
+        }
+        assert!(load("size", "a{1000}")
+            .unwrap()
+            .lookup(&"a".repeat(1000))
+            .is_some());
+    }
+
+    #[test]
+    fn limits_compiled_size_across_lines() {
+        let half = "(?:[a-z]{100}){100}\n";
+        assert!(load("across", half).is_ok());
+        let err = format!("{}", load("across", &half.repeat(2)).err().unwrap());
+        assert!(err.contains("regexes too big together"), "{err}");
+        assert!(err.contains("size limit"), "{err}");
+    }
+}
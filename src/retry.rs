@@ -0,0 +1,483 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs, io,
+    net::IpAddr,
+    os::fd::AsRawFd,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context as _, Result};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    net::tcp::OwnedReadHalf,
+    time,
+};
+
+use crate::{autohostlist, sockopt, stats::STATS, strategy::Strategy, Args};
+
+const MAX_TRACKED_DESTINATIONS: usize = 4096;
+
+/// Position on the --strategy-fallback ladder of destinations where the first strategy failed.
+struct Rung {
+    level: usize,
+    /// Whether a connection at this level got a response.
+    confirmed: bool,
+    used: time::Instant,
+}
+
+/// Ladder positions by host name, or by destination address for connections without one. Hosts
+/// without a position of their own start where a working strategy to their address is.
+struct Ladder {
+    rungs: HashMap<String, Rung>,
+}
+
+static LADDER: LazyLock<Mutex<Ladder>> = LazyLock::new(|| {
+    Mutex::new(Ladder {
+        rungs: HashMap::new(),
+    })
+});
+
+impl Ladder {
+    /// Returns the level for `key`, falling back to a working one for `dst`.
+    fn level(&mut self, key: &str, dst: &str, max_age: time::Duration) -> usize {
+        self.rungs.retain(|_, rung| rung.used.elapsed() < max_age);
+        if let Some(rung) = self.rungs.get_mut(key) {
+            rung.used = time::Instant::now();
+            return rung.level;
+        }
+        let level = match self.rungs.get(dst) {
+            Some(rung) if rung.confirmed => rung.level,
+            _ => return 0,
+        };
+        self.set(key, level, false, time::Instant::now());
+        level
+    }
+
+    fn get(&self, key: &str) -> usize {
+        self.rungs.get(key).map_or(0, |rung| rung.level)
+    }
+
+    /// Sets the level for `key`, evicting the least recently used destination if there are too
+    /// many.
+    fn set(&mut self, key: &str, level: usize, confirmed: bool, used: time::Instant) {
+        if self.rungs.len() >= MAX_TRACKED_DESTINATIONS && !self.rungs.contains_key(key) {
+            let oldest = self.rungs.iter().min_by_key(|(_, rung)| rung.used);
+            if let Some(oldest) = oldest.map(|(key, _)| key.clone()) {
+                self.rungs.remove(&oldest);
+            }
+        }
+        let rung = Rung {
+            level,
+            confirmed,
+            used,
+        };
+        self.rungs.insert(key.to_owned(), rung);
+    }
+}
+
+/// First payload of a connection and whether the destination answered it, moving the destination
+/// down the ladder if it didn't. Connections are never retried themselves.
+pub struct Attempt {
+    dst: IpAddr,
+    conn: String,
+    args: Arc<Args>,
+    /// Destination key, ladder level and strategy of the first payload, once sent.
+    hello: Mutex<Option<(String, usize, String)>>,
+    /// Host of a first payload relayed as is, watched for --auto-hostlist.
+    learning: Mutex<Option<String>>,
+    /// Destination socket while the connection is open, for timeouts to check retransmissions.
+    upstream: Mutex<Option<i32>>,
+    settled: AtomicBool,
+    client_closed: AtomicBool,
+}
+
+impl Attempt {
+    pub fn new(dst: IpAddr, conn: &str, args: &Arc<Args>) -> Arc<Self> {
+        Arc::new(Attempt {
+            dst,
+            conn: conn.to_owned(),
+            args: Arc::clone(args),
+            hello: Mutex::new(None),
+            learning: Mutex::new(None),
+            upstream: Mutex::new(None),
+            settled: AtomicBool::new(false),
+            client_closed: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the strategy for the first payload to `host`, or the destination address without
+    /// one, which is `strategy` unless it failed before, and starts watching for a response.
+    pub fn pick<'a>(
+        self: &Arc<Self>,
+        host: Option<&str>,
+        strategy: &'a Strategy,
+        log: &mut String,
+        args: &'a Args,
+    ) -> &'a Strategy {
+        let mut hello = self.hello.lock().unwrap();
+        if hello.is_some() || args.strategy_fallback.is_empty() {
+            return strategy;
+        }
+        let dst = self.dst.to_string();
+        let key = host.map_or_else(|| dst.clone(), str::to_ascii_lowercase);
+        let max_age = time::Duration::from_millis(args.strategy_cache_ms);
+        let level = LADDER.lock().unwrap().level(&key, &dst, max_age);
+        let strategy = match level {
+            0 => strategy,
+            level => {
+                let fallback = &args.strategy_fallback[level - 1];
+                log.push_str(&format!(" (fallback {level}: {fallback})"));
+                fallback
+            }
+        };
+        *hello = Some((key, level, strategy.to_string()));
+
+        let attempt = Arc::clone(self);
+        tokio::spawn(async move {
+            time::sleep(time::Duration::from_millis(
+                attempt.args.fallback_timeout_ms,
+            ))
+            .await;
+            attempt.fail("no response");
+        });
+        strategy
+    }
+
+    /// Watches the first payload to `host`, relayed as is, counting a failure for --auto-hostlist
+    /// if the destination closes or resets the connection before answering, or hasn't answered
+    /// within --fallback-timeout-ms while the payload is being retransmitted.
+    pub fn learn(self: &Arc<Self>, host: &str) {
+        let mut learning = self.learning.lock().unwrap();
+        if learning.is_some() || self.hello.lock().unwrap().is_some() {
+            return;
+        }
+        *learning = Some(host.to_owned());
+
+        let attempt = Arc::clone(self);
+        tokio::spawn(async move {
+            time::sleep(time::Duration::from_millis(
+                attempt.args.fallback_timeout_ms,
+            ))
+            .await;
+            let retransmitting = attempt.upstream.lock().unwrap().is_some_and(|fd| {
+                sockopt::get_tcp_info(fd)
+                    .ok()
+                    .and_then(|info| info.tcpi_total_retrans())
+                    .is_some_and(|retransmits| retransmits > 0)
+            });
+            match retransmitting {
+                true => attempt.fail("no response"),
+                false => attempt.settled.store(true, Ordering::Relaxed),
+            }
+        });
+    }
+
+    /// Stops failures from being reported once the client is done.
+    pub fn client_closed(&self) {
+        self.client_closed.store(true, Ordering::Relaxed);
+    }
+
+    fn server_data(&self) {
+        if self.settled.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(host) = &*self.learning.lock().unwrap() {
+            self.settled.store(true, Ordering::Relaxed);
+            autohostlist::success(host);
+            return;
+        }
+        let hello = self.hello.lock().unwrap();
+        let Some((key, level, strategy)) = &*hello else {
+            return;
+        };
+        if self.settled.swap(true, Ordering::Relaxed) || *level == 0 {
+            return;
+        }
+        let mut ladder = LADDER.lock().unwrap();
+        match ladder.rungs.get_mut(key) {
+            Some(rung) if rung.level == *level => {
+                if !rung.confirmed {
+                    rung.confirmed = true;
+                    eprintln!(
+                        "{}: fallback {level} ({strategy}) works for {key}",
+                        self.conn
+                    );
+                }
+            }
+            _ => return,
+        }
+        let dst = self.dst.to_string();
+        if *key != dst {
+            ladder.set(&dst, *level, true, time::Instant::now());
+        }
+    }
+
+    fn fail(&self, reason: &str) {
+        if let Some(host) = &*self.learning.lock().unwrap() {
+            if !self.client_closed.load(Ordering::Relaxed)
+                && !self.settled.swap(true, Ordering::Relaxed)
+            {
+                autohostlist::failure(host, reason, &self.conn, &self.args);
+            }
+            return;
+        }
+        let hello = self.hello.lock().unwrap();
+        let Some((key, level, strategy)) = &*hello else {
+            return;
+        };
+        if self.client_closed.load(Ordering::Relaxed) || self.settled.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+        STATS.desync_failures.incr();
+
+        let mut ladder = LADDER.lock().unwrap();
+        if ladder.get(key) != *level {
+            // Another connection has already moved it.
+            return;
+        }
+        let fallbacks = &self.args.strategy_fallback;
+        let conn = &self.conn;
+        if *level == fallbacks.len() {
+            ladder.rungs.remove(key);
+            let dst = self.dst.to_string();
+            if ladder.get(&dst) == *level {
+                ladder.rungs.remove(&dst);
+            }
+            eprintln!(
+                "{conn}: {reason} with strategy {strategy}, which was the last fallback for \
+                 {key}; starting over"
+            );
+            return;
+        }
+        let next = level + 1;
+        ladder.set(key, next, false, time::Instant::now());
+        eprintln!(
+            "{conn}: {reason} with strategy {strategy}, trying fallback {next} ({}) for {key}",
+            fallbacks[next - 1]
+        );
+    }
+}
+
+/// Lists the destinations on the ladder, for SIGUSR1.
+pub fn report(args: &Args) -> String {
+    let ladder = LADDER.lock().unwrap();
+    let mut rungs: Vec<_> = ladder.rungs.iter().collect();
+    rungs.sort_unstable_by_key(|(key, _)| *key);
+    let mut report = format!("strategy cache: {} destinations", rungs.len());
+    for (key, rung) in rungs {
+        let state = if rung.confirmed { "works" } else { "trying" };
+        let _ = write!(
+            report,
+            "\n  {key}: fallback {} ({}) {state}, used {}s ago",
+            rung.level,
+            args.strategy_fallback[rung.level - 1],
+            rung.used.elapsed().as_secs()
+        );
+    }
+    report
+}
+
+/// Reads the working fallbacks saved by [`save`], skipping those that expired or are no longer
+/// in --strategy-fallback.
+pub fn load(args: &Args) -> Result<()> {
+    let Some(path) = &args.strategy_cache_file else {
+        return Ok(());
+    };
+    let text = match fs::read_to_string(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        result => result.with_context(|| format!("cannot read {}", path.display()))?,
+    };
+    let max_age = time::Duration::from_millis(args.strategy_cache_ms);
+    let now = unix_time();
+    let mut ladder = LADDER.lock().unwrap();
+    for line in text.lines() {
+        let mut fields = line.splitn(4, ' ');
+        let (Some(key), Some(level), Some(used), Some(strategy)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(level), Ok(used)) = (level.parse::<usize>(), used.parse::<u64>()) else {
+            continue;
+        };
+        let fallback = level
+            .checked_sub(1)
+            .and_then(|i| args.strategy_fallback.get(i));
+        if fallback.is_none_or(|fallback| fallback.to_string() != strategy) {
+            continue;
+        }
+        let age = time::Duration::from_secs(now.saturating_sub(used));
+        if let (true, Some(used)) = (age < max_age, time::Instant::now().checked_sub(age)) {
+            ladder.set(key, level, true, used);
+        }
+    }
+    println!(
+        "strategy cache: {} destinations from {}",
+        ladder.rungs.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Writes the working fallbacks to --strategy-cache-file.
+pub fn save(args: &Args) -> Result<()> {
+    let Some(path) = &args.strategy_cache_file else {
+        return Ok(());
+    };
+    let now = unix_time();
+    let mut text = String::new();
+    for (key, rung) in &LADDER.lock().unwrap().rungs {
+        if rung.confirmed {
+            let used = now.saturating_sub(rung.used.elapsed().as_secs());
+            let strategy = &args.strategy_fallback[rung.level - 1];
+            writeln!(text, "{key} {} {used} {strategy}", rung.level)?;
+        }
+    }
+    fs::write(path, text).with_context(|| format!("cannot write {}", path.display()))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// Destination half of a connection, telling the attempt when the destination answers or closes.
+pub struct WatchedReader {
+    inner: OwnedReadHalf,
+    attempt: Option<Arc<Attempt>>,
+}
+
+impl WatchedReader {
+    pub fn new(inner: OwnedReadHalf, attempt: Option<Arc<Attempt>>) -> Self {
+        if let Some(attempt) = &attempt {
+            *attempt.upstream.lock().unwrap() = Some(inner.as_ref().as_raw_fd());
+        }
+        WatchedReader { inner, attempt }
+    }
+
+    pub fn get_ref(&self) -> &OwnedReadHalf {
+        &self.inner
+    }
+}
+
+impl Drop for WatchedReader {
+    fn drop(&mut self) {
+        if let Some(attempt) = &self.attempt {
+            // Taken before the socket can close, which waits for a timeout checking it.
+            *attempt.upstream.lock().unwrap() = None;
+        }
+    }
+}
+
+impl AsyncRead for WatchedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        if let Some(attempt) = &self.attempt {
+            match &result {
+                Ok(()) if buf.filled().len() > filled => attempt.server_data(),
+                Ok(()) => attempt.fail("closed"),
+                Err(_) => attempt.fail("reset"),
+            }
+            if attempt.settled.load(Ordering::Relaxed) {
+                *attempt.upstream.lock().unwrap() = None;
+                self.attempt = None;
+            }
+        }
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn forgets_destinations_unused_for_a_while() {
+        let mut ladder = Ladder {
+            rungs: HashMap::new(),
+        };
+        let max_age = time::Duration::from_secs(60);
+        ladder.set("example.com", 2, true, time::Instant::now());
+        ladder.set("192.0.2.1", 1, true, time::Instant::now());
+
+        time::advance(time::Duration::from_secs(40)).await;
+        assert_eq!(ladder.level("example.com", "192.0.2.1", max_age), 2);
+        // Using the host doesn't keep its address.
+        time::advance(time::Duration::from_secs(40)).await;
+        assert_eq!(ladder.level("example.com", "192.0.2.1", max_age), 2);
+        assert_eq!(ladder.get("192.0.2.1"), 0);
+
+        time::advance(max_age).await;
+        assert_eq!(ladder.level("example.com", "192.0.2.1", max_age), 0);
+        assert!(ladder.rungs.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_new_hosts_where_their_address_works() {
+        let mut ladder = Ladder {
+            rungs: HashMap::new(),
+        };
+        let max_age = time::Duration::from_secs(60);
+        ladder.set("192.0.2.1", 2, true, time::Instant::now());
+        ladder.set("192.0.2.2", 2, false, time::Instant::now());
+        assert_eq!(ladder.level("a.example.com", "192.0.2.1", max_age), 2);
+        assert_eq!(ladder.level("b.example.com", "192.0.2.2", max_age), 0);
+        assert_eq!(ladder.get("a.example.com"), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moves_to_the_next_strategy_after_failures() {
+        let args = Arc::new(crate::tests::args(&[
+            "--strategy",
+            "split:1",
+            "--strategy-fallback",
+            "split:2",
+            "--strategy-fallback",
+            "split:3",
+        ]));
+        let dst = "192.0.2.10".parse().unwrap();
+        let connect = |log: &mut String| {
+            let attempt = Attempt::new(dst, "test", &args);
+            let strategy = attempt.pick(Some("Retry.example.com"), args.strategy(), log, &args);
+            (attempt, strategy.to_string())
+        };
+        let timeout = time::Duration::from_millis(args.fallback_timeout_ms + 1);
+
+        let mut log = String::new();
+        for expected in ["split:1", "split:2", "split:3", "split:1"] {
+            let (_attempt, strategy) = connect(&mut log);
+            assert_eq!(strategy, expected);
+            // Unanswered until the fallback timeout, which counts as a failure.
+            time::sleep(timeout).await;
+        }
+        assert!(
+            log.ends_with(" (fallback 1: split:2) (fallback 2: split:3)"),
+            "{log}"
+        );
+
+        // An answer keeps the fallback, also for other hosts at the address.
+        let (attempt, strategy) = connect(&mut String::new());
+        assert_eq!(strategy, "split:2");
+        attempt.server_data();
+        time::sleep(timeout).await;
+        assert_eq!(connect(&mut String::new()).1, "split:2");
+        let attempt = Attempt::new(dst, "test", &args);
+        let other = attempt.pick(None, args.strategy(), &mut String::new(), &args);
+        assert_eq!(other.to_string(), "split:2");
+        assert!(report(&args).contains("retry.example.com: fallback 1 (split:2) works"));
+    }
+}
@@ -0,0 +1,35 @@
+use std::{
+    ops::RangeInclusive,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// splitmix64 state shared by all threads.
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Seeds the generator, from the clock if `seed` is None.
+pub fn seed(seed: Option<u64>) {
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64)
+    });
+    STATE.store(seed, Ordering::Relaxed);
+}
+
+pub fn next_u64() -> u64 {
+    let mut z = STATE
+        .fetch_add(GAMMA, Ordering::Relaxed)
+        .wrapping_add(GAMMA);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Returns a number in `range`, which must not be empty.
+pub fn in_range(range: RangeInclusive<usize>) -> usize {
+    let span = (range.end() - range.start()) as u64 + 1;
+    range.start() + (next_u64() % span) as usize
+}
@@ -0,0 +1,385 @@
+use std::{
+    io,
+    mem::{offset_of, size_of, size_of_val, MaybeUninit},
+    net::{IpAddr, SocketAddr},
+    os::fd::AsRawFd,
+};
+
+use linux_raw_sys::net::{tcp_info, tcp_md5sig};
+use socket2::{SockAddr, SockRef};
+use tokio::net::TcpStream;
+
+/// `tcp_info` as filled by the kernel, which may be shorter than our definition.
+pub struct TcpInfo {
+    info: tcp_info,
+    len: usize,
+}
+
+macro_rules! field {
+    ($name:ident, $ty:ty) => {
+        pub fn $name(&self) -> Option<$ty> {
+            (self.len >= offset_of!(tcp_info, $name) + size_of::<$ty>()).then_some(self.info.$name)
+        }
+    };
+}
+
+impl TcpInfo {
+    field!(tcpi_notsent_bytes, u32);
+    field!(tcpi_rtt, u32);
+    field!(tcpi_unacked, u32);
+    field!(tcpi_total_retrans, u32);
+    #[cfg(feature = "raw")]
+    field!(tcpi_bytes_acked, u64);
+    #[cfg(feature = "raw")]
+    field!(tcpi_bytes_received, u64);
+    #[cfg(feature = "raw")]
+    field!(tcpi_segs_in, u32);
+
+    /// Returns what a kernel filling only `len` bytes would have given.
+    #[cfg(test)]
+    pub fn truncated(&self, len: usize) -> TcpInfo {
+        TcpInfo {
+            info: self.info,
+            len: len.min(self.len),
+        }
+    }
+}
+
+pub fn get_tcp_info(fd: i32) -> io::Result<TcpInfo> {
+    unsafe {
+        let mut payload: MaybeUninit<tcp_info> = MaybeUninit::zeroed();
+        let mut len = size_of::<tcp_info>() as libc::socklen_t;
+
+        if libc::getsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            payload.as_mut_ptr().cast(),
+            &mut len,
+        ) == 0
+        {
+            Ok(TcpInfo {
+                info: payload.assume_init(),
+                len: len as usize,
+            })
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+fn set_int(fd: i32, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const i32).cast(),
+            size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn get_int(fd: i32, level: i32, name: i32) -> io::Result<i32> {
+    let mut value = 0i32;
+    let mut len = size_of::<i32>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockopt(fd, level, name, (&mut value as *mut i32).cast(), &mut len) };
+    if ret == 0 {
+        Ok(value)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub fn set_quickack(fd: i32, enabled: bool) -> io::Result<()> {
+    set_int(fd, libc::IPPROTO_TCP, libc::TCP_QUICKACK, enabled as i32)
+}
+
+/// While corked, the kernel holds partial segments back until uncorked.
+pub fn set_cork(fd: i32, enabled: bool) -> io::Result<()> {
+    set_int(fd, libc::IPPROTO_TCP, libc::TCP_CORK, enabled as i32)
+}
+
+pub fn mss(fd: i32) -> io::Result<u32> {
+    get_int(fd, libc::IPPROTO_TCP, libc::TCP_MAXSEG).map(|mss| mss as u32)
+}
+
+pub fn set_mss(fd: i32, mss: u32) -> io::Result<()> {
+    set_int(fd, libc::IPPROTO_TCP, libc::TCP_MAXSEG, mss as i32)
+}
+
+/// Limits the receive window advertised to the peer. Before connecting, this also limits the window
+/// scale, and so the window for the whole connection.
+pub fn set_window_clamp(fd: i32, bytes: u32) -> io::Result<()> {
+    set_int(
+        fd,
+        libc::IPPROTO_TCP,
+        libc::TCP_WINDOW_CLAMP,
+        bytes.min(i32::MAX as u32) as i32,
+    )
+}
+
+/// Returns the bytes in the send queue, sent or not.
+#[cfg(feature = "raw")]
+pub fn outq(fd: i32) -> io::Result<u32> {
+    let mut value = 0i32;
+    match unsafe { libc::ioctl(fd, libc::TIOCOUTQ, &mut value) } {
+        0 => Ok(value as u32),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Makes a raw socket send packets with the IP header we built.
+#[cfg(feature = "raw")]
+pub fn set_header_included(fd: i32, ipv6: bool) -> io::Result<()> {
+    match ipv6 {
+        true => set_int(fd, libc::IPPROTO_IPV6, libc::IPV6_HDRINCL, 1),
+        false => set_int(fd, libc::IPPROTO_IP, libc::IP_HDRINCL, 1),
+    }
+}
+
+#[cfg(feature = "raw")]
+pub fn attach_filter(fd: i32, filter: &[libc::sock_filter]) -> io::Result<()> {
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr().cast_mut(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            (&program as *const libc::sock_fprog).cast(),
+            size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Makes a UDP socket report the destination of datagrams redirected by TPROXY.
+pub fn set_recv_original_dst(fd: i32, ipv6: bool) -> io::Result<()> {
+    match ipv6 {
+        true => set_int(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR, 1),
+        false => set_int(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR, 1),
+    }
+}
+
+/// Receives a datagram, returning its length, source and original destination, if reported.
+pub fn recv_with_original_dst(
+    fd: i32,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let mut source: libc::sockaddr_storage = unsafe { MaybeUninit::zeroed().assume_init() };
+    // Aligned for cmsghdr, and room for a sockaddr_in6.
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+    msg.msg_name = (&mut source as *mut libc::sockaddr_storage).cast();
+    msg.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control);
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let source = unsafe { SockAddr::new(source, msg.msg_namelen) }
+        .as_socket()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+    let mut original_dst = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        let addr_len = match (header.cmsg_level, header.cmsg_type) {
+            (libc::SOL_IP, libc::IP_ORIGDSTADDR) => size_of::<libc::sockaddr_in>(),
+            (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => size_of::<libc::sockaddr_in6>(),
+            _ => 0,
+        };
+        if addr_len > 0 {
+            let mut addr: libc::sockaddr_storage = unsafe { MaybeUninit::zeroed().assume_init() };
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    libc::CMSG_DATA(cmsg),
+                    (&mut addr as *mut libc::sockaddr_storage).cast::<u8>(),
+                    addr_len,
+                );
+                original_dst = SockAddr::new(addr, addr_len as libc::socklen_t).as_socket();
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok((len as usize, source, original_dst))
+}
+
+/// Returns the addresses of the host's network interfaces.
+pub fn interface_addresses() -> io::Result<Vec<IpAddr>> {
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addrs = Vec::new();
+    let mut ifaddr = ifaddrs;
+    while !ifaddr.is_null() {
+        let entry = unsafe { &*ifaddr };
+        ifaddr = entry.ifa_next;
+        if entry.ifa_addr.is_null() {
+            continue;
+        }
+        let addr_len = match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => size_of::<libc::sockaddr_in>(),
+            libc::AF_INET6 => size_of::<libc::sockaddr_in6>(),
+            _ => continue,
+        };
+        let mut addr: libc::sockaddr_storage = unsafe { MaybeUninit::zeroed().assume_init() };
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                entry.ifa_addr.cast::<u8>(),
+                (&mut addr as *mut libc::sockaddr_storage).cast::<u8>(),
+                addr_len,
+            );
+        }
+        if let Some(addr) = unsafe { SockAddr::new(addr, addr_len as libc::socklen_t) }.as_socket()
+        {
+            addrs.push(addr.ip());
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(addrs)
+}
+
+/// Sends `buf` on a connected socket with its own TTL, without touching the socket's, so that
+/// concurrent sends keep theirs.
+pub fn send_with_ttl(fd: i32, buf: &[u8], ttl: u32, ipv6: bool) -> io::Result<usize> {
+    let (level, kind) = match ipv6 {
+        true => (libc::SOL_IPV6, libc::IPV6_HOPLIMIT),
+        false => (libc::SOL_IP, libc::IP_TTL),
+    };
+    // Aligned for cmsghdr, and room for an int.
+    let mut control = [0u64; 4];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr().cast_mut().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { MaybeUninit::zeroed().assume_init() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<i32>() as u32) } as usize;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = level;
+        (*cmsg).cmsg_type = kind;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<i32>() as u32) as usize;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<i32>(), ttl as i32);
+    }
+
+    let len = unsafe { libc::sendmsg(fd, &msg, 0) };
+    if len < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(len as usize)
+    }
+}
+
+pub fn ttl(stream: &TcpStream) -> io::Result<u32> {
+    let socket = SockRef::from(stream);
+    if socket.local_addr()?.is_ipv6() {
+        socket.unicast_hops_v6()
+    } else {
+        socket.ttl()
+    }
+}
+
+pub fn set_ttl(stream: &TcpStream, ttl: u32) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    if socket.local_addr()?.is_ipv6() {
+        socket.set_unicast_hops_v6(ttl)
+    } else {
+        socket.set_ttl(ttl)
+    }
+}
+
+/// Signs segments to the peer of `stream` with `key` from now on, or stops if it is empty.
+pub fn set_md5sig(stream: &TcpStream, key: &[u8]) -> io::Result<()> {
+    let peer = SockAddr::from(stream.peer_addr()?);
+    let mut md5sig: tcp_md5sig = unsafe { MaybeUninit::zeroed().assume_init() };
+    if key.len() > md5sig.tcpm_key.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(
+            peer.as_ptr().cast::<u8>(),
+            std::ptr::addr_of_mut!(md5sig.tcpm_addr).cast::<u8>(),
+            peer.len() as usize,
+        );
+    }
+    md5sig.tcpm_keylen = key.len() as u16;
+    md5sig.tcpm_key[..key.len()].copy_from_slice(key);
+
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_MD5SIG,
+            (&md5sig as *const tcp_md5sig).cast(),
+            size_of::<tcp_md5sig>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn info() -> (TcpInfo, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (get_tcp_info(stream.as_raw_fd()).unwrap(), stream)
+    }
+
+    #[test]
+    fn reads_full_tcp_info() {
+        let (info, _stream) = info();
+        assert_eq!(info.tcpi_notsent_bytes(), Some(0));
+        assert_eq!(info.tcpi_unacked(), Some(0));
+        assert!(info.tcpi_rtt().is_some());
+    }
+
+    #[test]
+    fn hides_fields_past_truncated_tcp_info() {
+        let (info, _stream) = info();
+        let notsent = offset_of!(tcp_info, tcpi_notsent_bytes);
+        assert_eq!(info.truncated(notsent).tcpi_notsent_bytes(), None);
+        assert_eq!(info.truncated(notsent + 3).tcpi_notsent_bytes(), None);
+        assert_eq!(info.truncated(notsent + 4).tcpi_notsent_bytes(), Some(0));
+        assert!(info.truncated(notsent).tcpi_rtt().is_some());
+
+        let empty = info.truncated(0);
+        assert_eq!(empty.tcpi_rtt(), None);
+        assert_eq!(empty.tcpi_unacked(), None);
+        assert_eq!(empty.tcpi_total_retrans(), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Mutex,
+};
+
+const PLAINTEXT: u8 = 0;
+const REQUESTED: u8 = 1;
+const ACCEPTED: u8 = 2;
+
+/// Longest line kept while waiting for the rest of it, past what SMTP, POP3 and IMAP allow for
+/// commands and replies. Longer ones are skipped.
+const MAX_LINE_LEN: usize = 4096;
+
+/// STARTTLS negotiation progress shared by both relay directions.
+pub struct StartTls {
+    state: AtomicU8,
+    client: Mutex<Lines>,
+    server: Mutex<Lines>,
+}
+
+impl StartTls {
+    pub fn new() -> Self {
+        StartTls {
+            state: AtomicU8::new(PLAINTEXT),
+            client: Mutex::default(),
+            server: Mutex::default(),
+        }
+    }
+
+    /// Inspects a client payload for a STARTTLS command, which may follow other commands in
+    /// the same payload or be split across several.
+    pub fn client_data(&self, data: &[u8]) {
+        self.client.lock().unwrap().feed(data, |line| {
+            if is_command(line) {
+                self.state.store(REQUESTED, Ordering::Release);
+            }
+        });
+    }
+
+    /// Inspects a server payload for the response to a pending STARTTLS command, past those to
+    /// the commands before it.
+    pub fn server_data(&self, data: &[u8]) {
+        self.server.lock().unwrap().feed(data, |line| {
+            if self.state.load(Ordering::Acquire) != REQUESTED {
+                return;
+            }
+            match response(line) {
+                Some(true) => self.state.store(ACCEPTED, Ordering::Release),
+                Some(false) => self.state.store(PLAINTEXT, Ordering::Release),
+                None => {}
+            }
+        });
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.state.load(Ordering::Acquire) == ACCEPTED
+    }
+}
+
+/// The end of a stream cut into lines.
+#[derive(Default)]
+struct Lines {
+    /// The line started by the last payload, unless it grew past MAX_LINE_LEN.
+    partial: Vec<u8>,
+    skipping: bool,
+}
+
+impl Lines {
+    /// Calls `line` with each line `data` completes, without its line ending.
+    fn feed(&mut self, mut data: &[u8], mut line: impl FnMut(&[u8])) {
+        while let Some(end) = data.iter().position(|b| *b == b'\n') {
+            if !self.skipping {
+                self.partial.extend_from_slice(&data[..end]);
+                line(self.partial.strip_suffix(b"\r").unwrap_or(&self.partial));
+            }
+            self.partial.clear();
+            self.skipping = false;
+            data = &data[end + 1..];
+        }
+        if self.partial.len() + data.len() > MAX_LINE_LEN {
+            self.partial.clear();
+            self.skipping = true;
+        }
+        if !self.skipping {
+            self.partial.extend_from_slice(data);
+        }
+    }
+}
+
+/// Matches SMTP/IMAP `[tag] STARTTLS` and POP3 `STLS` command lines.
+fn is_command(line: &[u8]) -> bool {
+    let words: Vec<&[u8]> = line
+        .split(|b| *b == b' ')
+        .filter(|w| !w.is_empty())
+        .collect();
+    match words[..] {
+        [cmd] | [_, cmd] => {
+            cmd.eq_ignore_ascii_case(b"STARTTLS") || cmd.eq_ignore_ascii_case(b"STLS")
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether `line` accepts a STARTTLS command, such as SMTP `220`, POP3 `+OK` and IMAP
+/// `tag OK`, or refuses it, or None if it answers something else or isn't a final reply line.
+fn response(line: &[u8]) -> Option<bool> {
+    let mut words = line.split(|b| *b == b' ');
+    let first = words.next()?;
+    match first {
+        b"220" => return Some(true),
+        b"+OK" => return Some(true),
+        b"-ERR" => return Some(false),
+        // Untagged IMAP data and continuations.
+        b"*" | b"+" => return None,
+        _ => {}
+    }
+    match first {
+        [b'4' | b'5', b'0'..=b'9', b'0'..=b'9'] => return Some(false),
+        // Replies to the commands pipelined before, and all but the last line of multiline
+        // SMTP ones.
+        [b'0'..=b'9', b'0'..=b'9', b'0'..=b'9', ..] => return None,
+        _ => {}
+    }
+    let status = words.next()?;
+    if status.eq_ignore_ascii_case(b"OK") {
+        Some(true)
+    } else if status.eq_ignore_ascii_case(b"NO") || status.eq_ignore_ascii_case(b"BAD") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_pipelined_and_split_commands() {
+        for reads in [
+            &[&b"EHLO client\r\nSTARTTLS\r\n"[..]][..],
+            &[b"EHLO client\r\nSTA", b"RTT", b"LS\r", b"\n"],
+            &[b"a1 CAPABILITY\r\n", b"a2 starttls\r\n"],
+            &[b"CAPA\r\nSTLS\r\n"],
+        ] {
+            let starttls = StartTls::new();
+            for read in reads {
+                starttls.client_data(read);
+            }
+            assert_eq!(
+                starttls.state.load(Ordering::Acquire),
+                REQUESTED,
+                "{reads:?}"
+            );
+        }
+        let starttls = StartTls::new();
+        starttls.client_data(b"MAIL FROM:<starttls@example.com>\r\nSTARTTLS please");
+        assert_eq!(starttls.state.load(Ordering::Acquire), PLAINTEXT);
+    }
+
+    #[test]
+    fn waits_for_the_starttls_response() {
+        let starttls = StartTls::new();
+        starttls.server_data(b"220 mx.example.com ESMTP\r\n");
+        starttls.client_data(b"EHLO client\r\nSTARTTLS\r\n");
+        starttls.server_data(b"250-mx.example.com\r\n250-PIPELINING\r\n250 STA");
+        starttls.server_data(b"RTTLS\r\n");
+        assert!(!starttls.is_accepted());
+        assert_eq!(starttls.state.load(Ordering::Acquire), REQUESTED);
+        starttls.server_data(b"220 2.0.0 Ready to start TLS\r\n");
+        assert!(starttls.is_accepted());
+    }
+
+    #[test]
+    fn gives_up_on_refused_starttls() {
+        for reply in [
+            &b"454 TLS not available\r\n"[..],
+            b"-ERR no\r\n",
+            b"a2 NO no\r\n",
+        ] {
+            let starttls = StartTls::new();
+            starttls.client_data(b"a2 STARTTLS\r\n");
+            starttls.server_data(reply);
+            assert_eq!(starttls.state.load(Ordering::Acquire), PLAINTEXT);
+        }
+        let starttls = StartTls::new();
+        starttls.client_data(b"a2 STARTTLS\r\n");
+        starttls.server_data(b"* CAPABILITY IMAP4rev1\r\na2 OK Begin TLS\r\n");
+        assert!(starttls.is_accepted());
+    }
+
+    #[test]
+    fn skips_overlong_lines() {
+        let mut lines = Lines::default();
+        let mut seen = Vec::new();
+        lines.feed(&[b'x'; MAX_LINE_LEN + 1], |line| seen.push(line.to_vec()));
+        lines.feed(b"STARTTLS\r\nSTLS\r\n", |line| seen.push(line.to_vec()));
+        assert_eq!(seen, [b"STLS".to_vec()]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Counter(AtomicU64::new(0))
+    }
+
+    pub fn incr(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decr(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+macro_rules! stats {
+    ($($name:ident),* $(,)?) => {
+        pub struct Stats {
+            $(pub $name: Counter,)*
+        }
+
+        pub static STATS: Stats = Stats {
+            $($name: Counter::new(),)*
+        };
+
+        impl Stats {
+            pub fn report(&self) -> String {
+                [$(format!("{}={}", stringify!($name), self.$name.get()),)*].join(" ")
+            }
+        }
+    };
+}
+
+stats! {
+    connections,
+    clients_denied,
+    active_connections,
+    accept_errors,
+    setup_errors,
+    not_redirected,
+    loops_refused,
+    skipped_marks,
+    private_destinations,
+    connect_errors,
+    connect_retries,
+    upload_errors,
+    download_errors,
+    non_tls,
+    http_requests,
+    h2c_prefaces,
+    lenient_hellos,
+    no_sni,
+    no_sni_passthroughs,
+    ech_hellos,
+    renegotiations,
+    flushes,
+    flush_timeouts,
+    flush_fallbacks,
+    flush_downgrades,
+    desync_budget_exceeded,
+    autottl_probes,
+    autottl_failures,
+    md5sig_fallbacks,
+    fake_repeats,
+    desync_failures,
+    auto_hostlist_additions,
+    auto_hostlist_hits,
+    nft_set_additions,
+    nft_set_removals,
+    nft_set_errors,
+    injected_fakes,
+    injection_failures,
+    quic_flows,
+    quic_splits,
+    quic_fakes,
+    ipfrag_initials,
+    ipfrag_fallbacks,
+}
+
+/// Counts a connection in `active_connections` while alive.
+pub struct ActiveGuard;
+
+impl ActiveGuard {
+    pub fn new() -> Self {
+        STATS.active_connections.incr();
+        ActiveGuard
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        STATS.active_connections.decr();
+    }
+}
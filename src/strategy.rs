@@ -0,0 +1,584 @@
+use std::{fmt, str::FromStr};
+
+use crate::{parse_byte, parse_split_delay, position::Position, Args, Fooling, SplitDelay};
+
+const STEPS: &str = "split, tlsrec, fake, fakerec, disorder, oob or delay";
+
+/// Named strategies for --preset, from the simplest to the most intrusive.
+const PRESETS: [(&str, &str); 6] = [
+    ("split-sni", "split:sni+1"),
+    ("split-midsld", "split:midsld"),
+    ("oob-sni", "oob,split:sni+1"),
+    ("fake-tls", "fake,split:sni+1"),
+    ("disorder-md5", "disorder,split:1,fake:md5sig,split:sni+1"),
+    (
+        "tlsrec-aggressive",
+        "tlsrec:ext_sni,tlsrec:sni,disorder,split:1,split:sni+1,split:end-5",
+    ),
+];
+
+/// Checks that `name` is a preset, for --preset.
+pub fn parse_preset(name: &str) -> Result<String, String> {
+    match PRESETS.iter().any(|(preset, _)| *preset == name) {
+        true => Ok(name.to_owned()),
+        false => {
+            let names: Vec<_> = PRESETS.iter().map(|(preset, _)| *preset).collect();
+            Err(format!(
+                "unknown preset {name:?}, expected one of {}",
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Where a split step cuts the payload.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SplitAt {
+    Position(Position),
+    /// One byte into the host name, unless it is an IP address.
+    Host,
+    /// Middle of the registrable domain label, or one byte into the host name.
+    Midsld,
+    /// After every dot of the host name, unless it is an IP address.
+    Labels,
+    /// Anywhere in MIN..=MAX, chosen for every connection.
+    Random(usize, usize),
+    /// Every this many bytes.
+    Every(usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Fake {
+    /// Overrides --autottl and --fake-ttl.
+    pub ttl: Option<u32>,
+    pub md5sig: bool,
+    /// Injected with a wrong sequence number instead of being sent on the connection.
+    pub badseq: bool,
+    /// Injected with a wrong checksum instead of being sent on the connection.
+    pub badsum: bool,
+}
+
+impl Fake {
+    /// Returns true if the decoy is injected with raw sockets.
+    pub fn injected(&self) -> bool {
+        self.badseq || self.badsum
+    }
+}
+
+/// Bogus TLS record sent before the ClientHello.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FakeRecord {
+    /// A change_cipher_spec record instead of an alert.
+    pub ccs: bool,
+    /// How to keep it from the destination, which sees it as well without.
+    pub fooling: Option<Fake>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Disorder {
+    /// Overrides --autottl and --disorder-ttl.
+    pub ttl: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Step {
+    Split(SplitAt),
+    /// Cuts the ClientHello record, at the host name if no position is given.
+    Tlsrec(Option<Position>),
+    Fake(Fake),
+    FakeRecord(FakeRecord),
+    Disorder(Disorder),
+    Oob(u8),
+    Delay(SplitDelay),
+}
+
+/// Ways a segment is sent, besides being written as is.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Techniques {
+    pub fake: Option<Fake>,
+    pub disorder: Option<Disorder>,
+    pub oob: Option<u8>,
+}
+
+impl Techniques {
+    pub fn add(&mut self, step: Step) {
+        match step {
+            Step::Fake(fake) => self.fake = Some(fake),
+            Step::Disorder(disorder) => self.disorder = Some(disorder),
+            Step::Oob(byte) => self.oob = Some(byte),
+            Step::Split(_) | Step::Tlsrec(_) | Step::FakeRecord(_) | Step::Delay(_) => {}
+        }
+    }
+}
+
+/// A strategy resolved for one payload.
+#[derive(Default)]
+pub struct Plan {
+    pub split_positions: Vec<usize>,
+    pub record_cuts: Vec<usize>,
+    pub fake_record: Option<FakeRecord>,
+    /// Technique steps with the number of the segment they apply to.
+    pub techniques: Vec<(usize, Step)>,
+}
+
+impl Plan {
+    pub fn clear(&mut self) {
+        self.split_positions.clear();
+        self.record_cuts.clear();
+        self.fake_record = None;
+        self.techniques.clear();
+    }
+
+    /// Sorts the split positions and drops duplicates and those that would produce empty
+    /// segments of a `len` byte payload. Techniques move to the segment now starting at the byte
+    /// theirs did, and those for segments starting past the payload are dropped and returned.
+    pub fn normalize(&mut self, len: usize) -> Vec<Step> {
+        let mut starts = self.split_positions.clone();
+        starts.sort_unstable();
+        self.split_positions.retain(|pos| *pos > 0 && *pos < len);
+        self.split_positions.sort_unstable();
+        self.split_positions.dedup();
+
+        let mut dropped = Vec::new();
+        let positions = &self.split_positions;
+        self.techniques.retain_mut(|(segment, step)| {
+            let start = segment.checked_sub(1).map_or(0, |i| starts[i]);
+            if start >= len {
+                dropped.push(*step);
+                return false;
+            }
+            *segment = positions.partition_point(|pos| *pos <= start);
+            true
+        });
+        dropped
+    }
+
+    /// Returns the techniques for each of the segments between the split positions.
+    pub fn segments(&self) -> Vec<Techniques> {
+        let mut segments = vec![Techniques::default(); self.split_positions.len() + 1];
+        for (i, step) in &self.techniques {
+            if let Some(segment) = segments.get_mut(*i) {
+                segment.add(*step);
+            }
+        }
+        segments
+    }
+}
+
+/// Desync steps in the order they were given. Splits can be given in any order, but segments are
+/// numbered in stream order, and fake, disorder and oob apply to the segment numbered by the
+/// splits before them: the first one if there are none.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Strategy {
+    pub steps: Vec<Step>,
+}
+
+impl Strategy {
+    /// Translates the individual desync flags.
+    pub fn from_args(args: &Args) -> Self {
+        let mut steps = Vec::new();
+        if args.fake {
+            steps.push(Step::Fake(Fake {
+                ttl: None,
+                md5sig: args.fooling.contains(&Fooling::Md5sig),
+                badseq: args.fooling.contains(&Fooling::Badseq),
+                badsum: args.fooling.contains(&Fooling::Badsum),
+            }));
+        }
+        if args.disorder || args.disoob {
+            steps.push(Step::Disorder(Disorder { ttl: None }));
+        }
+        if args.oob || args.disoob {
+            steps.push(Step::Oob(args.oob_byte));
+        }
+        steps.extend(
+            args.split_positions
+                .iter()
+                .map(|pos| Step::Split(SplitAt::Position(*pos))),
+        );
+        if let Some((min, max)) = args.split_random {
+            steps.push(Step::Split(SplitAt::Random(min, max)));
+        }
+        if let Some(every) = args.split_every {
+            steps.push(Step::Split(SplitAt::Every(every)));
+        }
+        if args.split_host {
+            steps.push(Step::Split(SplitAt::Host));
+        }
+        if args.split_midsld {
+            steps.push(Step::Split(SplitAt::Midsld));
+        }
+        if args.split_labels {
+            steps.push(Step::Split(SplitAt::Labels));
+        }
+        if args.split_record_header {
+            let pos = "rechdr+1".parse().expect("valid position");
+            steps.push(Step::Split(SplitAt::Position(pos)));
+        }
+        if args.tlsrec {
+            steps.push(Step::Tlsrec(None));
+        }
+        steps.extend(args.tlsrec_pos.iter().map(|pos| Step::Tlsrec(Some(*pos))));
+        if let Some(delay) = args.split_delay {
+            steps.push(Step::Delay(delay));
+        }
+        Strategy { steps }
+    }
+
+    /// Expands a preset checked by [`parse_preset`].
+    pub fn preset(name: &str) -> Self {
+        let (_, strategy) = PRESETS
+            .iter()
+            .find(|(preset, _)| *preset == name)
+            .expect("checked by parse_preset");
+        strategy.parse().expect("presets are valid strategies")
+    }
+
+    pub fn fakes(&self) -> bool {
+        self.steps.iter().any(|step| matches!(step, Step::Fake(_)))
+    }
+
+    /// Returns true if some segment may be sent with a low TTL.
+    pub fn lowers_ttl(&self) -> bool {
+        self.steps.iter().any(|step| match step {
+            Step::Fake(fake) => !fake.injected(),
+            Step::Disorder(_) => true,
+            Step::FakeRecord(record) => record.fooling.is_some_and(|fake| !fake.injected()),
+            _ => false,
+        })
+    }
+
+    /// Returns true if some decoy is injected with raw sockets.
+    pub fn injects(&self) -> bool {
+        self.steps.iter().any(|step| match step {
+            Step::Fake(fake) => fake.injected(),
+            Step::FakeRecord(record) => record.fooling.is_some_and(|fake| fake.injected()),
+            _ => false,
+        })
+    }
+
+    /// Wait after each split segment has left.
+    pub fn delay(&self) -> Option<SplitDelay> {
+        self.steps.iter().rev().find_map(|step| match step {
+            Step::Delay(delay) => Some(*delay),
+            _ => None,
+        })
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = Vec::new();
+        if s == "none" {
+            return Ok(Strategy { steps });
+        }
+        let mut column = 1;
+        for step in s.split(',') {
+            let parsed = parse_step(step)
+                .map_err(|(offset, err)| format!("column {}: {err}", column + offset))?;
+            // A destination keeps one urgent byte out of the stream, so an earlier one ends up
+            // in it when the next arrives before it was read.
+            if matches!(parsed, Step::Oob(_)) && steps.iter().any(|s| matches!(s, Step::Oob(_))) {
+                return Err(format!("column {column}: only one oob step is allowed"));
+            }
+            steps.push(parsed);
+            column += step.chars().count() + 1;
+        }
+        Ok(Strategy { steps })
+    }
+}
+
+/// Parses `name[:arg]...`, where each arg is a value or key=value, returning the error with its
+/// offset in `s`.
+fn parse_step(s: &str) -> Result<Step, (usize, String)> {
+    let mut args = Vec::new();
+    let mut offset = 0;
+    for arg in s.split(':') {
+        args.push((offset, arg));
+        offset += arg.chars().count() + 1;
+    }
+    let (_, name) = args.remove(0);
+    let mut args = StepArgs::new(args);
+
+    let step = match name {
+        "split" => {
+            let (at, arg) = args.value("a position")?;
+            let target = match arg.split_once('=') {
+                Some(("random", range)) => {
+                    let (min, max) = crate::parse_split_range(&range.replacen('-', ":", 1))
+                        .map_err(|err| (at, format!("bad random range {range:?}: {err}")))?;
+                    SplitAt::Random(min, max)
+                }
+                Some(("every", every)) => {
+                    SplitAt::Every(crate::parse_split_position(every).map_err(|err| (at, err))?)
+                }
+                Some((key, _)) => return Err((at, format!("unknown split parameter {key:?}"))),
+                None if arg == "host" => SplitAt::Host,
+                None if arg == "midsld" => SplitAt::Midsld,
+                None if arg == "labels" => SplitAt::Labels,
+                None => SplitAt::Position(
+                    crate::parse_relative_split_position(arg).map_err(|err| (at, err))?,
+                ),
+            };
+            Step::Split(target)
+        }
+        "tlsrec" => Step::Tlsrec(
+            args.optional_value()
+                .map(|(at, arg)| arg.parse().map_err(|err| (at, err)))
+                .transpose()?,
+        ),
+        "fake" => Step::Fake(Fake {
+            ttl: args.ttl()?,
+            md5sig: args.flag("md5sig"),
+            badseq: args.flag("badseq"),
+            badsum: args.flag("badsum"),
+        }),
+        "fakerec" => {
+            let ccs = args.flag("ccs");
+            let fooling = Fake {
+                ttl: args.ttl()?,
+                md5sig: args.flag("md5sig"),
+                badseq: args.flag("badseq"),
+                badsum: args.flag("badsum"),
+            };
+            let unfooled = args.flag("unfooled");
+            if unfooled && fooling != Fake::default() {
+                return Err((0, "unfooled records can't have a TTL or fooling".to_owned()));
+            }
+            Step::FakeRecord(FakeRecord {
+                ccs,
+                fooling: (!unfooled).then_some(fooling),
+            })
+        }
+        "disorder" => Step::Disorder(Disorder { ttl: args.ttl()? }),
+        "oob" => Step::Oob(match args.param("byte") {
+            Some((at, byte)) => parse_byte(byte).map_err(|err| (at, err))?,
+            None => b'a',
+        }),
+        "delay" => {
+            let (at, arg) = args.value("a delay in ms")?;
+            Step::Delay(parse_split_delay(arg).map_err(|err| (at, err))?)
+        }
+        "" => return Err((0, format!("expected a step: {STEPS}"))),
+        _ => return Err((0, format!("unknown step {name:?}, expected {STEPS}"))),
+    };
+    args.finish()?;
+    Ok(step)
+}
+
+/// Arguments of a step with their offsets, removed as they are used.
+struct StepArgs<'a> {
+    args: Vec<(usize, &'a str)>,
+}
+
+impl<'a> StepArgs<'a> {
+    fn new(args: Vec<(usize, &'a str)>) -> Self {
+        StepArgs { args }
+    }
+
+    fn optional_value(&mut self) -> Option<(usize, &'a str)> {
+        let i = self.args.iter().position(|(_, arg)| !arg.contains('='))?;
+        Some(self.args.remove(i))
+    }
+
+    fn value(&mut self, expected: &str) -> Result<(usize, &'a str), (usize, String)> {
+        if self.args.is_empty() {
+            return Err((0, format!("expected {expected}")));
+        }
+        Ok(self.args.remove(0))
+    }
+
+    fn param(&mut self, key: &str) -> Option<(usize, &'a str)> {
+        let i = self
+            .args
+            .iter()
+            .position(|(_, arg)| arg.split_once('=').is_some_and(|(k, _)| k == key))?;
+        let (at, arg) = self.args.remove(i);
+        Some((at + key.len() + 1, &arg[key.len() + 1..]))
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        let i = self.args.iter().position(|(_, arg)| *arg == name);
+        i.map(|i| self.args.remove(i)).is_some()
+    }
+
+    fn ttl(&mut self) -> Result<Option<u32>, (usize, String)> {
+        self.param("ttl")
+            .map(|(at, ttl)| match ttl.parse() {
+                Ok(ttl @ 1..=255) => Ok(ttl),
+                _ => Err((at, format!("bad TTL {ttl:?}, expected 1 to 255"))),
+            })
+            .transpose()
+    }
+
+    fn finish(self) -> Result<(), (usize, String)> {
+        match self.args.first() {
+            Some((at, arg)) => Err((*at, format!("unexpected argument {arg:?}"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Split(SplitAt::Position(pos)) => write!(f, "split:{pos}"),
+            Step::Split(SplitAt::Host) => write!(f, "split:host"),
+            Step::Split(SplitAt::Midsld) => write!(f, "split:midsld"),
+            Step::Split(SplitAt::Labels) => write!(f, "split:labels"),
+            Step::Split(SplitAt::Random(min, max)) => write!(f, "split:random={min}-{max}"),
+            Step::Split(SplitAt::Every(every)) => write!(f, "split:every={every}"),
+            Step::Tlsrec(None) => write!(f, "tlsrec"),
+            Step::Tlsrec(Some(pos)) => write!(f, "tlsrec:{pos}"),
+            Step::Fake(fake) => write!(f, "fake{fake}"),
+            Step::FakeRecord(record) => {
+                write!(f, "fakerec")?;
+                if record.ccs {
+                    write!(f, ":ccs")?;
+                }
+                match record.fooling {
+                    Some(fake) => write!(f, "{fake}"),
+                    None => write!(f, ":unfooled"),
+                }
+            }
+            Step::Disorder(Disorder { ttl: None }) => write!(f, "disorder"),
+            Step::Disorder(Disorder { ttl: Some(ttl) }) => write!(f, "disorder:ttl={ttl}"),
+            Step::Oob(b'a') => write!(f, "oob"),
+            Step::Oob(byte) if byte.is_ascii_graphic() && !byte.is_ascii_digit() => {
+                write!(f, "oob:byte={}", *byte as char)
+            }
+            Step::Oob(byte) => write!(f, "oob:byte={byte}"),
+            Step::Delay(delay) => write!(f, "delay:{delay}"),
+        }
+    }
+}
+
+/// Writes the arguments of a fake step, each with a leading colon.
+impl fmt::Display for Fake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ttl) = self.ttl {
+            write!(f, ":ttl={ttl}")?;
+        }
+        if self.md5sig {
+            write!(f, ":md5sig")?;
+        }
+        if self.badseq {
+            write!(f, ":badseq")?;
+        }
+        if self.badsum {
+            write!(f, ":badsum")?;
+        }
+        Ok(())
+    }
+}
+
+/// A --port-strategy rule, picking the default strategy for connections to a port.
+#[derive(Clone, Debug)]
+pub struct PortStrategy {
+    pub port: u16,
+    pub strategy: Strategy,
+}
+
+impl FromStr for PortStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (port, strategy) = s.split_once('=').ok_or("expected PORT=STRATEGY")?;
+        Ok(PortStrategy {
+            port: port
+                .trim()
+                .parse()
+                .map_err(|err| format!("bad port {port:?}: {err}"))?,
+            strategy: strategy.trim().parse()?,
+        })
+    }
+}
+
+/// What --no-sni-policy does with connections without a host name.
+#[derive(Clone, Debug)]
+pub enum NoSniPolicy {
+    /// Split with the strategy picked as for other connections, keeping only steps that don't
+    /// need a host name.
+    Split,
+    Passthrough,
+    Strategy(Strategy),
+}
+
+impl FromStr for NoSniPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "split" => Ok(NoSniPolicy::Split),
+            "passthrough" => Ok(NoSniPolicy::Passthrough),
+            s => match s.strip_prefix("strategy:") {
+                Some(strategy) => Ok(NoSniPolicy::Strategy(strategy.parse()?)),
+                None => Err("expected split, passthrough or strategy:STRATEGY".to_owned()),
+            },
+        }
+    }
+}
+
+impl fmt::Display for NoSniPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoSniPolicy::Split => write!(f, "split"),
+            NoSniPolicy::Passthrough => write!(f, "passthrough"),
+            NoSniPolicy::Strategy(strategy) => write!(f, "strategy:{strategy}"),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.steps.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{step}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_one_oob_step() {
+        assert!(Strategy::from_str("split:1,oob,split:sni+1").is_ok());
+        let err = Strategy::from_str("oob,split:1,oob:byte=7").unwrap_err();
+        assert_eq!(err, "column 13: only one oob step is allowed");
+    }
+
+    #[test]
+    fn plans_every_preset() {
+        let args = crate::tests::args(&[]);
+        let data = crate::fake::client_hello("www.example.com");
+        let hello = crate::tls::parse_client_hello(&data).unwrap();
+        for (name, steps) in PRESETS {
+            assert_eq!(parse_preset(name).unwrap(), name);
+            let strategy = Strategy::preset(name);
+            assert_eq!(strategy.to_string(), steps);
+
+            let mut plan = Plan::default();
+            let mut log = String::new();
+            crate::plan_splits(&strategy, Some(&hello), &mut plan, &mut log, "test", &args)
+                .unwrap();
+            plan.normalize(hello.len);
+            assert!(!plan.split_positions.is_empty(), "{name} doesn't split");
+            let segments = plan.segments();
+            for (i, segment) in segments.iter().enumerate() {
+                // A fake segment is sent before any disorder is looked at.
+                let both = segment.fake.is_some() && segment.disorder.is_some();
+                assert!(!both, "{name} fakes and disorders segment {i}");
+            }
+        }
+    }
+}
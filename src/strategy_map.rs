@@ -0,0 +1,94 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::{
+    hostlist::Pattern,
+    strategy::{NoSniPolicy, Strategy},
+    Args,
+};
+
+static MAP: RwLock<Option<Arc<StrategyMap>>> = RwLock::new(None);
+
+/// Strategies picked by host name with --strategy-map, the first matching line winning.
+pub struct StrategyMap {
+    entries: Vec<(Pattern, Strategy)>,
+}
+
+impl StrategyMap {
+    fn load(path: &Path) -> Result<Self> {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((pattern, strategy)) = line.split_once(char::is_whitespace) else {
+                return Err(anyhow!(
+                    "{}:{}: expected a host pattern and a strategy",
+                    path.display(),
+                    i + 1
+                ));
+            };
+            let pattern = match pattern.parse() {
+                Ok(pattern) => pattern,
+                Err(err) if !pattern.is_ascii() => {
+                    eprintln!("{}:{}: {err}, skipping", path.display(), i + 1);
+                    continue;
+                }
+                Err(err) => return Err(anyhow!("{}:{}: {err}", path.display(), i + 1)),
+            };
+            let strategy = strategy
+                .trim()
+                .parse()
+                .map_err(|err| anyhow!("{}:{}: {err}", path.display(), i + 1))?;
+            entries.push((pattern, strategy));
+        }
+        Ok(StrategyMap { entries })
+    }
+
+    /// Returns the first entry matching `host`.
+    pub fn lookup(&self, host: &str) -> Option<&(Pattern, Strategy)> {
+        self.entries
+            .iter()
+            .find(|(pattern, _)| pattern.matches(host))
+    }
+}
+
+/// Reads the strategy map file, keeping the current map if that fails.
+pub fn reload(args: &Args) -> Result<()> {
+    let Some(path) = &args.strategy_map else {
+        return Ok(());
+    };
+    let map = StrategyMap::load(path)?;
+    println!(
+        "strategy map: {} entries from {}",
+        map.entries.len(),
+        path.display()
+    );
+    *MAP.write().unwrap() = Some(Arc::new(map));
+    Ok(())
+}
+
+pub fn get() -> Option<Arc<StrategyMap>> {
+    MAP.read().unwrap().clone()
+}
+
+/// Returns true if `f` holds for the default strategy, a fallback, a port's, one for connections
+/// without a host name or any in the map.
+pub fn any(args: &Args, f: impl Fn(&Strategy) -> bool) -> bool {
+    f(args.strategy())
+        || args.strategy_fallback.iter().any(&f)
+        || args.port_strategy.iter().any(|rule| f(&rule.strategy))
+        || matches!(&args.no_sni_policy, NoSniPolicy::Strategy(strategy) if f(strategy))
+        || args.listener_override.iter().any(|entry| {
+            matches!(&entry.no_sni_policy, Some(NoSniPolicy::Strategy(strategy)) if f(strategy))
+        })
+        || get().is_some_and(|map| map.entries.iter().any(|(_, strategy)| f(strategy)))
+}
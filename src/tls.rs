@@ -0,0 +1,786 @@
+use std::ops::Range;
+
+use tls_parser::{
+    parse_tls_client_hello_extensions, parse_tls_message_handshake, TlsMessage, TlsMessageHandshake,
+};
+
+pub const HEADER_LEN: usize = 5;
+pub const MAX_RECORD_LEN: usize = 16384;
+pub const MAX_HELLO_LEN: usize = 65536;
+
+pub const CONTENT_TYPE_CHANGE_CIPHER_SPEC: u8 = 0x14;
+pub const CONTENT_TYPE_ALERT: u8 = 0x15;
+pub const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+pub const CONTENT_TYPE_APPLICATION_DATA: u8 = 0x17;
+const CONTENT_TYPE_HEARTBEAT: u8 = 0x18;
+/// Largest record body, with room for the encryption overhead.
+const MAX_CIPHERTEXT_LEN: usize = MAX_RECORD_LEN + 2048;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const HANDSHAKE_HEADER_LEN: usize = 4;
+const EXTENSION_HEADER_LEN: usize = 4;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+const EXTENSION_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+const SERVER_NAME_HOST_NAME: u8 = 0x00;
+
+pub struct ClientHello {
+    /// Length of the records carrying the ClientHello.
+    pub len: usize,
+    /// False if only the lenient structure walk could make sense of it.
+    pub strict: bool,
+    pub hosts: Vec<Host>,
+    /// Stream offset of the server_name extension header.
+    pub sni_extension: Option<usize>,
+    /// Stream offset of the application_layer_protocol_negotiation extension header.
+    pub alpn_extension: Option<usize>,
+    /// Protocols offered in the application_layer_protocol_negotiation extension.
+    pub alpn: Vec<String>,
+    /// Whether the encrypted_client_hello extension is there, making `hosts` the outer names.
+    pub ech: bool,
+}
+
+pub struct Host {
+    pub name: String,
+    /// Offsets of the host name in the original byte stream.
+    pub range: Range<usize>,
+}
+
+/// Bodies of the handshake records at the start of a stream, concatenated.
+struct Records {
+    payload: Vec<u8>,
+    /// Stream offset of each record body and its offset in `payload`.
+    bodies: Vec<(usize, usize)>,
+    /// Stream offset after the last collected record.
+    end: usize,
+    /// Stream length needed before the ClientHello message is complete.
+    pending: Option<usize>,
+}
+
+impl Records {
+    fn collect(data: &[u8]) -> Self {
+        let mut records = Records {
+            payload: Vec::new(),
+            bodies: Vec::new(),
+            end: 0,
+            pending: None,
+        };
+
+        loop {
+            if records.payload.len() >= HANDSHAKE_HEADER_LEN {
+                let payload = &records.payload;
+                let msg_len = HANDSHAKE_HEADER_LEN
+                    + u32::from_be_bytes([0, payload[1], payload[2], payload[3]]) as usize;
+                if payload[0] != HANDSHAKE_CLIENT_HELLO
+                    || payload.len() >= msg_len
+                    || msg_len > MAX_HELLO_LEN
+                {
+                    break;
+                }
+            }
+
+            let pos = records.end;
+            let rest = &data[pos..];
+            let record_len = match rest {
+                [CONTENT_TYPE_HANDSHAKE, 0x03, _, hi, lo, ..] => {
+                    HEADER_LEN + u16::from_be_bytes([*hi, *lo]) as usize
+                }
+                []
+                | [CONTENT_TYPE_HANDSHAKE]
+                | [CONTENT_TYPE_HANDSHAKE, 0x03]
+                | [CONTENT_TYPE_HANDSHAKE, 0x03, _]
+                | [CONTENT_TYPE_HANDSHAKE, 0x03, _, _] => {
+                    records.pending = Some(pos + HEADER_LEN);
+                    break;
+                }
+                _ => break,
+            };
+            if record_len == HEADER_LEN || record_len > HEADER_LEN + MAX_RECORD_LEN {
+                break;
+            }
+            if rest.len() < record_len {
+                records.pending = Some(pos + record_len);
+                break;
+            }
+
+            records
+                .bodies
+                .push((pos + HEADER_LEN, records.payload.len()));
+            records
+                .payload
+                .extend_from_slice(&rest[HEADER_LEN..record_len]);
+            records.end += record_len;
+        }
+
+        records.pending = records.pending.filter(|len| *len <= MAX_HELLO_LEN);
+        records
+    }
+
+    fn stream_offset(&self, payload_offset: usize) -> usize {
+        let (body, start) = self
+            .bodies
+            .iter()
+            .rev()
+            .find(|(_, start)| *start <= payload_offset)
+            .copied()
+            .unwrap_or_default();
+        body + (payload_offset - start)
+    }
+
+    fn stream_range(&self, payload_range: Range<usize>) -> Range<usize> {
+        let start = self.stream_offset(payload_range.start);
+        if payload_range.is_empty() {
+            start..start
+        } else {
+            start..self.stream_offset(payload_range.end - 1) + 1
+        }
+    }
+}
+
+/// Follows the record framing of what a client sends, skipping over the record bodies, to find
+/// handshake records sent after application data, such as a renegotiation ClientHello.
+#[derive(Default)]
+pub struct RecordTracker {
+    /// Bytes of the current record body not seen yet.
+    left: usize,
+    header: [u8; HEADER_LEN],
+    /// Bytes of the next record header seen so far.
+    header_len: usize,
+    application_data: bool,
+    /// Set once the stream no longer looks like records, which stops the tracking for good.
+    lost: bool,
+}
+
+impl RecordTracker {
+    /// Follows the framing through `data`, the next bytes of the stream, returning the offset of
+    /// the first handshake record in it that follows application data. Records whose header
+    /// started in earlier bytes are never returned.
+    pub fn feed(&mut self, data: &[u8]) -> Option<usize> {
+        let mut found = None;
+        let mut pos = 0;
+        while !self.lost && pos < data.len() {
+            if self.left > 0 {
+                let skipped = self.left.min(data.len() - pos);
+                self.left -= skipped;
+                pos += skipped;
+                continue;
+            }
+            let start = pos.checked_sub(self.header_len);
+            let taken = (HEADER_LEN - self.header_len).min(data.len() - pos);
+            self.header[self.header_len..self.header_len + taken]
+                .copy_from_slice(&data[pos..pos + taken]);
+            self.header_len += taken;
+            pos += taken;
+            if self.header_len < HEADER_LEN {
+                break;
+            }
+            self.header_len = 0;
+
+            let [content_type, major, _, hi, lo] = self.header;
+            self.left = u16::from_be_bytes([hi, lo]) as usize;
+            if !(CONTENT_TYPE_CHANGE_CIPHER_SPEC..=CONTENT_TYPE_HEARTBEAT).contains(&content_type)
+                || major != 0x03
+                || self.left > MAX_CIPHERTEXT_LEN
+            {
+                self.lost = true;
+                break;
+            }
+            match content_type {
+                CONTENT_TYPE_APPLICATION_DATA => self.application_data = true,
+                CONTENT_TYPE_HANDSHAKE if self.application_data => {
+                    self.application_data = false;
+                    found = found.or(start);
+                }
+                _ => {}
+            }
+        }
+        found
+    }
+}
+
+/// Returns true if `data` starts like a TLS handshake record.
+pub fn is_handshake(data: &[u8]) -> bool {
+    match data {
+        [CONTENT_TYPE_HANDSHAKE] => true,
+        [CONTENT_TYPE_HANDSHAKE, major, ..] => *major == 0x03,
+        _ => false,
+    }
+}
+
+/// Returns the stream length needed to complete the ClientHello `data` starts with.
+pub fn pending_client_hello(data: &[u8]) -> Option<usize> {
+    Records::collect(data).pending
+}
+
+pub fn parse_client_hello(data: &[u8]) -> Option<ClientHello> {
+    let records = Records::collect(data);
+    let payload = &records.payload;
+
+    let strict = match parse_tls_message_handshake(payload) {
+        Ok((_, TlsMessage::Handshake(TlsMessageHandshake::ClientHello(ch)))) => ch
+            .ext
+            .is_none_or(|ext| parse_tls_client_hello_extensions(ext).is_ok()),
+        _ => false,
+    };
+
+    let mut hosts = Vec::new();
+    let mut sni_extension = None;
+    let mut alpn_extension = None;
+    let mut alpn = Vec::new();
+    let mut ech = false;
+    for ext in client_hello_extensions(payload)? {
+        ech |= ext.kind == EXTENSION_ENCRYPTED_CLIENT_HELLO;
+        if ext.kind == EXTENSION_ALPN {
+            alpn_extension
+                .get_or_insert(records.stream_offset(ext.data.start - EXTENSION_HEADER_LEN));
+            let protocols = protocol_names(payload, ext.data.clone()).unwrap_or_default();
+            alpn.extend(
+                protocols
+                    .into_iter()
+                    .map(|range| String::from_utf8_lossy(&payload[range]).into_owned()),
+            );
+        }
+        if ext.kind == EXTENSION_SERVER_NAME {
+            sni_extension
+                .get_or_insert(records.stream_offset(ext.data.start - EXTENSION_HEADER_LEN));
+            for range in server_names(payload, ext.data)? {
+                hosts.push(Host {
+                    name: String::from_utf8_lossy(&payload[range.clone()]).into_owned(),
+                    range: records.stream_range(range),
+                });
+            }
+        }
+    }
+
+    Some(ClientHello {
+        len: records.end,
+        strict,
+        hosts,
+        sni_extension,
+        alpn_extension,
+        alpn,
+        ech,
+    })
+}
+
+/// Parses a ClientHello handshake message sent without records, as in QUIC CRYPTO frames, giving
+/// offsets into the message.
+pub fn parse_handshake_client_hello(message: &[u8]) -> Option<ClientHello> {
+    if message.len() > MAX_RECORD_LEN {
+        return None;
+    }
+    let mut hello = parse_client_hello(&record(CONTENT_TYPE_HANDSHAKE, message))?;
+    hello.len -= HEADER_LEN;
+    for host in &mut hello.hosts {
+        host.range = host.range.start - HEADER_LEN..host.range.end - HEADER_LEN;
+    }
+    hello.sni_extension = hello.sni_extension.map(|offset| offset - HEADER_LEN);
+    hello.alpn_extension = hello.alpn_extension.map(|offset| offset - HEADER_LEN);
+    Some(hello)
+}
+
+/// Rewrites the handshake records at the start of `data` so that a new record starts at each
+/// stream offset in `cuts`, which must be sorted and deduplicated.
+pub fn split_records(data: &[u8], cuts: &[usize]) -> Result<Vec<u8>, String> {
+    let records = Records::collect(data);
+    let Some(&(first_body, _)) = records.bodies.first() else {
+        return Err("no handshake records".to_owned());
+    };
+    if let Some(cut) = cuts
+        .iter()
+        .find(|cut| (first_body..first_body + HANDSHAKE_HEADER_LEN).contains(cut))
+    {
+        return Err(format!("{cut} is inside the handshake header"));
+    }
+
+    let mut out = Vec::with_capacity(data.len() + cuts.len() * HEADER_LEN);
+    let mut used_cuts = 0;
+    for &(body, _) in &records.bodies {
+        let header = &data[body - HEADER_LEN..body];
+        let end = body + u16::from_be_bytes([header[3], header[4]]) as usize;
+        let mut start = body;
+        for &cut in cuts.iter().filter(|cut| **cut > body && **cut < end) {
+            push_record(&mut out, header, record_body(data, start..cut)?);
+            start = cut;
+            used_cuts += 1;
+        }
+        push_record(&mut out, header, record_body(data, start..end)?);
+    }
+    if used_cuts != cuts.len() {
+        return Err("cuts must be inside record bodies".to_owned());
+    }
+    out.extend_from_slice(&data[records.end..]);
+    Ok(out)
+}
+
+/// Returns the `range` of `data` as a record body, unless it is too long for one.
+fn record_body(data: &[u8], range: Range<usize>) -> Result<&[u8], String> {
+    if range.len() > MAX_RECORD_LEN {
+        return Err(format!(
+            "the {} byte record at {} would exceed the {MAX_RECORD_LEN} byte limit",
+            range.len(),
+            range.start
+        ));
+    }
+    Ok(&data[range])
+}
+
+/// Returns a record of `content_type` carrying `body`, with the TLS 1.2 record version.
+pub fn record(content_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + body.len());
+    push_record(&mut out, &[content_type, 0x03, 0x03], body);
+    out
+}
+
+fn push_record(out: &mut Vec<u8>, header: &[u8], body: &[u8]) {
+    out.extend_from_slice(&header[..3]);
+    out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+struct Extension {
+    kind: u16,
+    /// Offsets of the extension data in the handshake payload.
+    data: Range<usize>,
+}
+
+fn client_hello_extensions(payload: &[u8]) -> Option<Vec<Extension>> {
+    let mut reader = Reader::new(payload);
+    if reader.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    let msg_len = reader.u24()?;
+    let mut reader = reader.sub(msg_len)?;
+
+    reader.skip(2 + 32)?; // legacy_version, random
+    let session_id_len = reader.u8()?;
+    reader.skip(session_id_len as usize)?;
+    let cipher_suites_len = reader.u16()?;
+    reader.skip(cipher_suites_len as usize)?;
+    let compression_methods_len = reader.u8()?;
+    reader.skip(compression_methods_len as usize)?;
+
+    let mut extensions = Vec::new();
+    if reader.is_empty() {
+        return Some(extensions);
+    }
+    let extensions_len = reader.u16()?;
+    let mut reader = reader.sub(extensions_len as usize)?;
+    while !reader.is_empty() {
+        let kind = reader.u16()?;
+        let len = reader.u16()? as usize;
+        let start = reader.pos;
+        reader.skip(len)?;
+        extensions.push(Extension {
+            kind,
+            data: start..start + len,
+        });
+    }
+
+    Some(extensions)
+}
+
+fn server_names(payload: &[u8], ext_data: Range<usize>) -> Option<Vec<Range<usize>>> {
+    let mut reader = Reader::new(&payload[..ext_data.end]);
+    reader.pos = ext_data.start;
+    let list_len = reader.u16()?;
+    let mut reader = reader.sub(list_len as usize)?;
+
+    let mut names = Vec::new();
+    while !reader.is_empty() {
+        let name_type = reader.u8()?;
+        let len = reader.u16()? as usize;
+        let start = reader.pos;
+        reader.skip(len)?;
+        if name_type == SERVER_NAME_HOST_NAME {
+            names.push(start..start + len);
+        }
+    }
+
+    Some(names)
+}
+
+fn protocol_names(payload: &[u8], ext_data: Range<usize>) -> Option<Vec<Range<usize>>> {
+    let mut reader = Reader::new(&payload[..ext_data.end]);
+    reader.pos = ext_data.start;
+    let list_len = reader.u16()?;
+    let mut reader = reader.sub(list_len as usize)?;
+
+    let mut names = Vec::new();
+    while !reader.is_empty() {
+        let len = reader.u8()? as usize;
+        let start = reader.pos;
+        reader.skip(len)?;
+        names.push(start..start + len);
+    }
+
+    Some(names)
+}
+
+/// Bounds-checked big-endian reader that keeps offsets relative to the start of `data`.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.take(3)?;
+        Some(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]) as usize)
+    }
+
+    /// Splits off a reader for the next `len` bytes, advancing this one past them.
+    fn sub(&mut self, len: usize) -> Option<Reader<'a>> {
+        let start = self.pos;
+        self.skip(len)?;
+        Some(Reader {
+            data: &self.data[..start + len],
+            pos: start,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+
+    /// Builds a ClientHello record with `extensions`, in order, after a 32 byte session id and
+    /// two cipher suites.
+    pub fn hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        hello_with(&[0x22; 32], 2, extensions)
+    }
+
+    /// Builds a ClientHello record with `session_id`, `suites` cipher suites and `extensions`.
+    fn hello_with(session_id: &[u8], suites: u16, extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0x11; 32]);
+        body.push(session_id.len() as u8);
+        body.extend(session_id);
+        body.extend((suites * 2).to_be_bytes());
+        body.extend((0..suites).flat_map(|suite| (0x1301 + suite).to_be_bytes()));
+        body.extend([1, 0]);
+        let len: usize = extensions.iter().map(|(_, data)| 4 + data.len()).sum();
+        body.extend((len as u16).to_be_bytes());
+        for (kind, data) in extensions {
+            body.extend(kind.to_be_bytes());
+            body.extend((data.len() as u16).to_be_bytes());
+            body.extend(data);
+        }
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO];
+        message.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend(body);
+        record(CONTENT_TYPE_HANDSHAKE, &message)
+    }
+
+    /// Returns server_name extension data listing `names` as host_name entries.
+    pub fn server_name(names: &[&str]) -> (u16, Vec<u8>) {
+        let mut list = Vec::new();
+        for name in names {
+            list.push(SERVER_NAME_HOST_NAME);
+            list.extend((name.len() as u16).to_be_bytes());
+            list.extend(name.as_bytes());
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend(list);
+        (EXTENSION_SERVER_NAME, data)
+    }
+
+    /// Returns application_layer_protocol_negotiation extension data offering `protocols`.
+    pub fn alpn(protocols: &[&str]) -> (u16, Vec<u8>) {
+        let mut list = Vec::new();
+        for protocol in protocols {
+            list.push(protocol.len() as u8);
+            list.extend(protocol.as_bytes());
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend(list);
+        (EXTENSION_ALPN, data)
+    }
+
+    /// Returns the stream offset of the first `needle` in `data`.
+    pub fn find(data: &[u8], needle: &[u8]) -> usize {
+        data.windows(needle.len())
+            .position(|window| window == needle)
+            .expect("needle is there")
+    }
+
+    #[test]
+    fn parses_hello_across_records() {
+        let original = hello(&[server_name(&["example.com"]), alpn(&["h2"])]);
+        let cuts = [60, find(&original, b"example") + 3, 108];
+        let stream = split_records(&original, &cuts).unwrap();
+
+        let parsed = parse_client_hello(&stream).unwrap();
+        assert!(parsed.strict);
+        assert_eq!(parsed.len, stream.len());
+        assert_eq!(parsed.hosts.len(), 1);
+        assert_eq!(parsed.hosts[0].name, "example.com");
+        let range = parsed.hosts[0].range.clone();
+        assert_eq!(range.len(), "example.com".len() + HEADER_LEN);
+        let mut name = stream[range.clone()].to_vec();
+        name.drain(cuts[1] + HEADER_LEN - range.start..cuts[1] + 2 * HEADER_LEN - range.start);
+        assert_eq!(name, b"example.com");
+        let sni_extension = parsed.sni_extension.unwrap();
+        assert_eq!(sni_extension, find(&original, &[0, 0, 0, 16]) + HEADER_LEN);
+        assert_eq!(&stream[sni_extension..sni_extension + 4], &[0, 0, 0, 16]);
+    }
+
+    #[test]
+    fn waits_for_every_record() {
+        let original = hello(&[server_name(&["example.com"])]);
+        let stream = split_records(&original, &[20, 40, 80]).unwrap();
+        for len in 0..stream.len() {
+            assert!(
+                pending_client_hello(&stream[..len]).is_some_and(|needed| needed > len),
+                "nothing pending after {len} bytes"
+            );
+        }
+        assert_eq!(pending_client_hello(&stream), None);
+    }
+
+    #[test]
+    fn finds_offsets_structurally() {
+        let padding = (0x0015, vec![0; 7]);
+        let sni = server_name(&["example.com"]);
+        for session_id in [&[][..], &[0x2a; 32]] {
+            for suites in [1, 17] {
+                let hello = hello_with(session_id, suites, &[padding.clone(), sni.clone()]);
+                let parsed = parse_client_hello(&hello).unwrap();
+                assert!(parsed.strict);
+                let host = find(&hello, b"example.com");
+                assert_eq!(parsed.hosts[0].range, host..host + 11);
+                assert_eq!(parsed.sni_extension, Some(host - 9));
+                assert_eq!(&hello[host - 9..host - 7], &[0, 0]);
+                assert_eq!(parsed.alpn_extension, None);
+
+                // Without records, as in QUIC CRYPTO frames.
+                let parsed = parse_handshake_client_hello(&hello[HEADER_LEN..]).unwrap();
+                let host = host - HEADER_LEN;
+                assert_eq!(parsed.hosts[0].range, host..host + 11);
+                assert_eq!(parsed.sni_extension, Some(host - 9));
+                assert_eq!(parsed.len, hello.len() - HEADER_LEN);
+            }
+        }
+    }
+
+    #[test]
+    fn ignores_host_names_in_other_extensions() {
+        // The name shows up in an unknown extension before the server_name one.
+        let decoy = (0x4a4a, b"\x00\x0e\x00\x00\x0bexample.org".to_vec());
+        let hello = hello(&[decoy, server_name(&["example.com"])]);
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert_eq!(parsed.hosts.len(), 1);
+        let host = find(&hello, b"example.com");
+        assert_eq!(parsed.hosts[0].range, host..host + 11);
+    }
+
+    /// ClientHello sent by OpenSSL 3.0 for example.com, offering h2 and http/1.1.
+    pub const OPENSSL_HELLO: &[u8] = include_bytes!("../testdata/openssl-hello.bin");
+
+    /// ClientHello laid out as Firefox 128 sends it to cloudflare-ech.com with ECH, extension
+    /// for extension, with random bytes for the key shares and the encrypted inner hello.
+    const FIREFOX_ECH_HELLO: &[u8] = include_bytes!("../testdata/firefox-ech-hello.bin");
+
+    /// Parses `data` every way the proxy does, checking that offsets stay within it.
+    fn parse_all_ways(data: &[u8]) {
+        if let Some(needed) = pending_client_hello(data) {
+            assert!(needed > data.len() && needed <= MAX_HELLO_LEN);
+        }
+        if let Some(hello) = parse_client_hello(data) {
+            assert!(hello.len <= data.len());
+            for host in &hello.hosts {
+                assert!(host.range.end <= data.len());
+            }
+            for offset in [hello.sni_extension, hello.alpn_extension]
+                .into_iter()
+                .flatten()
+            {
+                assert!(offset < data.len());
+            }
+        }
+        if data.len() > HEADER_LEN {
+            parse_handshake_client_hello(&data[HEADER_LEN..]);
+        }
+        RecordTracker::default().feed(data);
+    }
+
+    #[test]
+    fn parses_openssl_hello() {
+        let hello = parse_client_hello(OPENSSL_HELLO).unwrap();
+        assert!(hello.strict);
+        assert_eq!(hello.len, OPENSSL_HELLO.len());
+        assert_eq!(hello.hosts[0].name, "example.com");
+        assert_eq!(&OPENSSL_HELLO[hello.hosts[0].range.clone()], b"example.com");
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+        assert!(!hello.ech);
+    }
+
+    #[test]
+    fn parses_firefox_ech_hello() {
+        let hello = parse_client_hello(FIREFOX_ECH_HELLO).unwrap();
+        assert!(hello.strict);
+        assert!(hello.ech);
+        assert_eq!(hello.len, FIREFOX_ECH_HELLO.len());
+        assert_eq!(hello.hosts.len(), 1);
+        assert_eq!(hello.hosts[0].name, "cloudflare-ech.com");
+        let host = &FIREFOX_ECH_HELLO[hello.hosts[0].range.clone()];
+        assert_eq!(host, b"cloudflare-ech.com");
+        assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+    }
+
+    #[test]
+    fn survives_truncated_hellos() {
+        let cut = split_records(OPENSSL_HELLO, &[100, 300]).unwrap();
+        for hello in [OPENSSL_HELLO, &cut] {
+            for len in 0..=hello.len() {
+                parse_all_ways(&hello[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn survives_mutated_hellos() {
+        let mut hello = OPENSSL_HELLO.to_vec();
+        for i in 0..hello.len() {
+            let original = hello[i];
+            for value in [
+                0x00,
+                0x01,
+                0x7f,
+                0x80,
+                0xff,
+                original ^ 0x01,
+                original.wrapping_add(1),
+            ] {
+                hello[i] = value;
+                parse_all_ways(&hello);
+            }
+            hello[i] = original;
+        }
+    }
+
+    #[test]
+    fn survives_lenient_parse_of_mutated_hellos() {
+        // A session id over 32 bytes fails the strict parse but not the structure walk.
+        let mut hello = hello_with(&[0x2a; 40], 2, &[server_name(&["example.com"])]);
+        let parsed = parse_client_hello(&hello).unwrap();
+        assert!(!parsed.strict);
+        assert_eq!(parsed.hosts[0].name, "example.com");
+        for i in 0..hello.len() {
+            let original = hello[i];
+            for value in [0x00, 0xff, original ^ 0x01] {
+                hello[i] = value;
+                parse_all_ways(&hello);
+            }
+            hello[i] = original;
+        }
+    }
+
+    /// Returns the bodies of the records in `data`, checking their headers.
+    fn record_bodies(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut bodies = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(&data[..3], &[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01]);
+            let len = HEADER_LEN + u16::from_be_bytes([data[3], data[4]]) as usize;
+            bodies.push(&data[HEADER_LEN..len]);
+            data = &data[len..];
+        }
+        bodies
+    }
+
+    #[test]
+    fn splits_records_keeping_the_message() {
+        let original = crate::fake::client_hello("example.com");
+        let host = find(&original, b"example.com");
+        let cuts = [10, host + 1, host + 2, original.len() - 1];
+        let split = split_records(&original, &cuts).unwrap();
+        assert_eq!(split.len(), original.len() + cuts.len() * HEADER_LEN);
+
+        let bodies = record_bodies(&split);
+        let lens: Vec<_> = bodies.iter().map(|body| body.len()).collect();
+        assert_eq!(lens, [5, host - 9, 1, original.len() - 3 - host, 1]);
+        assert_eq!(bodies.concat(), &original[HEADER_LEN..]);
+
+        let parsed = parse_client_hello(&split).unwrap();
+        assert!(parsed.strict);
+        assert_eq!(parsed.len, split.len());
+        assert_eq!(parsed.hosts[0].name, "example.com");
+        assert_eq!(parsed.alpn, ["h2", "http/1.1"]);
+
+        // A record already cut is cut again at the right place.
+        let again = split_records(&split, &[host + 3 * HEADER_LEN + 4]).unwrap();
+        assert_eq!(record_bodies(&again).concat(), &original[HEADER_LEN..]);
+        assert_eq!(record_bodies(&again).len(), 6);
+    }
+
+    #[test]
+    fn keeps_data_after_split_records() {
+        let mut data = crate::fake::client_hello("example.com");
+        let len = data.len();
+        data.extend(record(CONTENT_TYPE_APPLICATION_DATA, b"early"));
+        let split = split_records(&data, &[50]).unwrap();
+        assert_eq!(&split[len + HEADER_LEN..], &data[len..]);
+        assert_eq!(parse_client_hello(&split).unwrap().len, len + HEADER_LEN);
+    }
+
+    #[test]
+    fn rejects_bad_record_cuts() {
+        let fake = crate::fake::client_hello("example.com");
+        assert_eq!(
+            split_records(&fake, &[HEADER_LEN + 2]).unwrap_err(),
+            "7 is inside the handshake header"
+        );
+        assert!(split_records(&fake, &[fake.len()]).is_err());
+        assert!(split_records(&fake, &[HEADER_LEN]).is_err());
+        assert!(split_records(b"GET / HTTP/1.1\r\n", &[3]).is_err());
+    }
+
+    #[test]
+    fn splits_hellos_larger_than_a_record() {
+        // A hello too large for one record, sent in a full record and the rest.
+        let padding = (0x0015, vec![0; MAX_RECORD_LEN + 1000]);
+        let message = hello(&[server_name(&["example.com"]), padding])[HEADER_LEN..].to_vec();
+        let (first, rest) = message.split_at(MAX_RECORD_LEN);
+        let original = [
+            record(CONTENT_TYPE_HANDSHAKE, first),
+            record(CONTENT_TYPE_HANDSHAKE, rest),
+        ]
+        .concat();
+        let host = find(&original, b"example.com");
+
+        let cuts: Vec<_> = (0..50).map(|i| host + 1 + i * 10).collect();
+        let split = split_records(&original, &cuts).unwrap();
+        assert_eq!(split.len(), original.len() + cuts.len() * HEADER_LEN);
+        let parsed = parse_client_hello(&split).unwrap();
+        assert!(parsed.strict);
+        assert_eq!(parsed.len, split.len());
+        assert_eq!(parsed.hosts[0].name, "example.com");
+    }
+}
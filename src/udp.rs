@@ -0,0 +1,501 @@
+use std::{
+    collections::HashMap,
+    future, io,
+    net::SocketAddr,
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use socket2::{Domain, Socket, Type};
+use tokio::{io::Interest, net::UdpSocket, sync::mpsc, time};
+
+use crate::{
+    autottl, dstlist, fake, is_listen_address, log_denied, quic, sockopt,
+    stats::STATS,
+    tls::{self, ClientHello},
+    unmap_ipv4, Args, Transparent,
+};
+
+/// Flows are dropped after this long without a datagram either way, longer than QUIC idle
+/// timeouts usually are.
+const IDLE_TIMEOUT: time::Duration = time::Duration::from_secs(60);
+const MAX_FLOWS: usize = 4096;
+const MAX_DATAGRAM_LEN: usize = 65535;
+/// Datagrams queued for a flow before more are dropped.
+const FLOW_QUEUE_LEN: usize = 64;
+/// How long to hold the first Initial datagrams of a flow waiting for the rest of the
+/// ClientHello.
+const HELLO_WAIT: time::Duration = time::Duration::from_millis(100);
+
+type Flows = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>>>>;
+
+pub fn make_relay_socket(addr: SocketAddr, transparent: Transparent) -> Result<UdpSocket> {
+    let domain = if addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, None)?;
+    socket.set_nonblocking(true)?;
+    socket.set_cloexec(true)?;
+    socket.set_reuse_address(true)?;
+    match transparent {
+        Transparent::Auto => {
+            if let Err(err) = socket.set_ip_transparent(true) {
+                eprintln!("relay {addr}: cannot set IP_TRANSPARENT, continuing without: {err}");
+            }
+        }
+        Transparent::Always => socket
+            .set_ip_transparent(true)
+            .context("cannot set IP_TRANSPARENT")?,
+        Transparent::Never => {}
+    }
+    sockopt::set_recv_original_dst(socket.as_raw_fd(), addr.is_ipv6())
+        .context("cannot ask for original destinations")?;
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Relays QUIC datagrams redirected to `socket`, with one flow for each client and destination.
+pub async fn relay_loop(socket: UdpSocket, args: Arc<Args>) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let local_addr = socket.local_addr()?;
+    let flows: Flows = Arc::default();
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+
+    loop {
+        let (len, client_addr, original_dst) = socket
+            .async_io(Interest::READABLE, || {
+                sockopt::recv_with_original_dst(socket.as_raw_fd(), &mut buf)
+            })
+            .await?;
+        if !dstlist::allows(unmap_ipv4(client_addr).ip()) {
+            STATS.clients_denied.incr();
+            log_denied(client_addr);
+            continue;
+        }
+        let datagram = buf[..len].to_vec();
+        let (dst, redirected) = match original_dst.map(unmap_ipv4) {
+            // Listeners on unspecified addresses get the address datagrams were sent to.
+            Some(original_dst) if !is_listen_address(original_dst, &[local_addr]) => {
+                (original_dst, true)
+            }
+            _ => match args.default_destination {
+                Some(default_destination) => (default_destination, false),
+                None => {
+                    STATS.not_redirected.incr();
+                    debug!("{client_addr}: datagram was not redirected");
+                    continue;
+                }
+            },
+        };
+
+        let key = (client_addr, dst);
+        let queue = flows.lock().unwrap().get(&key).cloned();
+        if let Some(queue) = queue {
+            // A full queue drops the datagram, which QUIC will retransmit.
+            let _ = queue.try_send(datagram);
+            continue;
+        }
+        if is_listen_address(dst, &args.quic_listen_address) {
+            STATS.loops_refused.incr();
+            continue;
+        }
+        let (queue, datagrams) = mpsc::channel(FLOW_QUEUE_LEN);
+        {
+            let mut flows = flows.lock().unwrap();
+            if flows.len() >= MAX_FLOWS {
+                debug!("{client_addr} -> {dst}: too many UDP flows, dropping");
+                continue;
+            }
+            flows.insert(key, queue);
+        }
+        STATS.quic_flows.incr();
+        eprintln!("{client_addr} -> {dst} (udp)");
+
+        let flow = Flow {
+            client_addr,
+            dst,
+            redirected,
+            listener: Arc::clone(&socket),
+            args: Arc::clone(&args),
+        };
+        let flows = Arc::clone(&flows);
+        tokio::spawn(async move {
+            if let Err(err) = flow.relay(datagram, datagrams).await {
+                eprintln!("{client_addr} -> {dst} (udp): {err:#}");
+            }
+            flows.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// Changes still to be made to the datagrams a flow sends to the destination.
+struct Desync {
+    /// TTL of the decoys, until they are sent before the first Initial.
+    decoy_ttl: Option<u32>,
+    /// Where to fragment client Initials, until that fails or the destination answers.
+    ipfrag: Option<usize>,
+    /// Whether fragments were sent and the destination hasn't answered since.
+    unanswered_fragments: bool,
+}
+
+struct Flow {
+    client_addr: SocketAddr,
+    dst: SocketAddr,
+    /// Whether the datagrams were redirected to us, so that replies must come from `dst`.
+    redirected: bool,
+    listener: Arc<UdpSocket>,
+    args: Arc<Args>,
+}
+
+impl Flow {
+    async fn relay(self, first: Vec<u8>, mut datagrams: mpsc::Receiver<Vec<u8>>) -> Result<()> {
+        let conn = format!("{} -> {}", self.client_addr, self.dst);
+        let upstream = self
+            .upstream_socket()
+            .context("cannot create upstream socket")?;
+        let reply = match self.redirected {
+            true => Some(
+                self.reply_socket()
+                    .context("cannot reply from the destination")?,
+            ),
+            false => None,
+        };
+
+        let mut desync = Desync {
+            decoy_ttl: match self.args.quic_fake {
+                true => Some(self.decoy_ttl(&conn).await),
+                false => None,
+            },
+            ipfrag: self.args.quic_ipfrag.filter(|_| self.dst.is_ipv4()),
+            unanswered_fragments: false,
+        };
+        let mut session = quic::Session::new();
+        let mut client_buf = vec![0; MAX_DATAGRAM_LEN];
+        let mut server_buf = vec![0; MAX_DATAGRAM_LEN];
+        let mut from_client = Some(first);
+        loop {
+            if let Some(datagram) = from_client.take() {
+                let split = |hello: Option<&ClientHello>| self.split_positions(hello, &conn);
+                let datagrams = session.client_datagram(datagram, split);
+                self.send_upstream(&upstream, datagrams, &mut desync, &conn)
+                    .await?;
+            }
+
+            let hello_deadline = session.held_since().map(|since| since + HELLO_WAIT);
+            let release = async {
+                match hello_deadline {
+                    Some(deadline) => time::sleep_until(deadline.into()).await,
+                    None => future::pending().await,
+                }
+            };
+            // Clients keep sending to the destination, which now reaches the reply socket.
+            let from_reply = async {
+                match &reply {
+                    Some(reply) => reply.recv(&mut client_buf).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                datagram = datagrams.recv() => match datagram {
+                    Some(datagram) => from_client = Some(datagram),
+                    None => return Ok(()),
+                },
+                len = from_reply => from_client = Some(client_buf[..len?].to_vec()),
+                len = upstream.recv(&mut server_buf) => {
+                    // Once the ClientHello got through, Initials only carry acknowledgments.
+                    desync.ipfrag = None;
+                    desync.unanswered_fragments = false;
+                    let datagram = session.server_datagram(server_buf[..len?].to_vec());
+                    match &reply {
+                        Some(reply) => reply.send(&datagram).await?,
+                        None => self.listener.send_to(&datagram, self.client_addr).await?,
+                    };
+                }
+                () = release => {
+                    debug!("{conn}: incomplete ClientHello, sending it as is");
+                    let datagrams = session.release();
+                    self.send_upstream(&upstream, datagrams, &mut desync, &conn).await?;
+                }
+                () = time::sleep(IDLE_TIMEOUT) => return Ok(()),
+            }
+        }
+    }
+
+    async fn decoy_ttl(&self, conn: &str) -> u32 {
+        let low_ttl = match self.args.autottl {
+            true => autottl::low_ttl(self.dst, &self.args, conn).await,
+            false => None,
+        };
+        low_ttl.unwrap_or(self.args.fake_ttl)
+    }
+
+    /// Sends `datagrams` to the destination, preceded by the decoys if they start with the first
+    /// Initial of the connection, and with client Initials fragmented with --quic-ipfrag.
+    async fn send_upstream(
+        &self,
+        upstream: &UdpSocket,
+        datagrams: Vec<Vec<u8>>,
+        desync: &mut Desync,
+        conn: &str,
+    ) -> io::Result<()> {
+        if let (Some(ttl), Some(first)) = (desync.decoy_ttl, datagrams.first()) {
+            desync.decoy_ttl = None;
+            if quic::is_initial(first) {
+                self.send_decoys(upstream, ttl, conn).await;
+            }
+        }
+        // A client sending again before any answer lost its datagrams, so the fragments may
+        // not get through.
+        if desync.unanswered_fragments && desync.ipfrag.take().is_some() {
+            STATS.ipfrag_fallbacks.incr();
+            debug!("{conn}: fragmented Initial unanswered, sending whole ones");
+        }
+        for datagram in datagrams {
+            if let Some(offset) = desync.ipfrag.filter(|_| quic::is_initial(&datagram)) {
+                match send_fragmented(upstream, &datagram, offset).await {
+                    Ok(()) => {
+                        desync.unanswered_fragments = true;
+                        STATS.ipfrag_initials.incr();
+                        continue;
+                    }
+                    Err(err) => {
+                        desync.ipfrag = None;
+                        STATS.ipfrag_fallbacks.incr();
+                        debug!("{conn}: cannot fragment Initial, sending it whole: {err:#}");
+                    }
+                }
+            }
+            upstream.send(&datagram).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_decoys(&self, upstream: &UdpSocket, ttl: u32, conn: &str) {
+        let hello = fake::client_hello(&self.args.fake_sni);
+        let decoy = quic::decoy_initial(&hello[tls::HEADER_LEN..]);
+        for _ in 0..self.args.fake_repeats {
+            let sent = upstream
+                .async_io(Interest::WRITABLE, || {
+                    sockopt::send_with_ttl(upstream.as_raw_fd(), &decoy, ttl, self.dst.is_ipv6())
+                })
+                .await;
+            if let Err(err) = sent {
+                debug!("{conn}: cannot send decoy Initial, skipping it: {err}");
+                return;
+            }
+        }
+        STATS.quic_fakes.incr();
+        debug!(
+            "{conn}: sent {} decoy Initial with TTL {ttl}",
+            self.args.fake_repeats
+        );
+    }
+
+    /// Resolves --quic-split-positions against the ClientHello.
+    fn split_positions(&self, hello: Option<&ClientHello>, conn: &str) -> Vec<usize> {
+        let Some(hello) = hello else {
+            STATS.non_tls.incr();
+            debug!("{conn}: no ClientHello in the Initial packets");
+            return Vec::new();
+        };
+        let sni = hello.hosts.first().map_or("none", |host| &host.name);
+        if hello.ech {
+            STATS.ech_hellos.incr();
+            if !self.args.desync_ech {
+                eprintln!("{conn}: quic sni {sni} (ech), not splitting");
+                return Vec::new();
+            }
+        }
+        let mut positions: Vec<_> = self
+            .args
+            .quic_split_positions
+            .iter()
+            .filter_map(|pos| pos.resolve(hello))
+            .filter(|pos| (1..hello.len).contains(pos))
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        let fake = match self.args.quic_fake {
+            true => " (fake Initial first)",
+            false => "",
+        };
+        eprintln!("{conn}: quic sni {sni}{fake}, split at {positions:?}");
+        if !positions.is_empty() {
+            STATS.quic_splits.incr();
+        }
+        positions
+    }
+
+    fn upstream_socket(&self) -> io::Result<UdpSocket> {
+        let domain = if self.dst.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        };
+        let socket = Socket::new(domain, Type::DGRAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.set_cloexec(true)?;
+        if self.args.fwmark != 0 {
+            socket.set_mark(self.args.fwmark)?;
+        }
+        socket.connect(&self.dst.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Creates a socket bound to the destination and connected to the client, which needs
+    /// IP_TRANSPARENT.
+    fn reply_socket(&self) -> io::Result<UdpSocket> {
+        let domain = if self.dst.is_ipv4() {
+            Domain::IPV4
+        } else {
+            Domain::IPV6
+        };
+        let socket = Socket::new(domain, Type::DGRAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.set_cloexec(true)?;
+        socket.set_reuse_address(true)?;
+        socket.set_ip_transparent(true)?;
+        if self.args.fwmark != 0 {
+            socket.set_mark(self.args.fwmark)?;
+        }
+        socket.bind(&self.dst.into())?;
+        socket.connect(&unmap_ipv4(self.client_addr).into())?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+/// Sends `datagram` as two IPv4 fragments, or fails if the raw socket isn't open.
+async fn send_fragmented(upstream: &UdpSocket, datagram: &[u8], offset: usize) -> Result<()> {
+    #[cfg(feature = "raw")]
+    return crate::raw::send_fragmented(upstream, datagram, offset).await;
+    #[cfg(not(feature = "raw"))]
+    {
+        let _ = (upstream, datagram, offset);
+        Err(anyhow::anyhow!("adpi was built without the raw feature"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::{
+        rustls::{
+            pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+            RootCertStore,
+        },
+        ClientConfig, Endpoint, ServerConfig,
+    };
+
+    /// Forwards datagrams between the relay and `server`, keeping those the relay sent.
+    async fn tap(server: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        upstream.connect(server).await.unwrap();
+        let sent = Arc::<Mutex<Vec<_>>>::default();
+        let kept = Arc::clone(&sent);
+        tokio::spawn(async move {
+            let mut relay = None;
+            let mut relay_buf = vec![0; MAX_DATAGRAM_LEN];
+            let mut server_buf = vec![0; MAX_DATAGRAM_LEN];
+            loop {
+                tokio::select! {
+                    received = socket.recv_from(&mut relay_buf) => {
+                        let (len, from) = received.unwrap();
+                        relay = Some(from);
+                        kept.lock().unwrap().push(relay_buf[..len].to_vec());
+                        upstream.send(&relay_buf[..len]).await.unwrap();
+                    }
+                    len = upstream.recv(&mut server_buf) => {
+                        if let Some(relay) = relay {
+                            socket.send_to(&server_buf[..len.unwrap()], relay).await.unwrap();
+                        }
+                    }
+                }
+            }
+        });
+        (addr, sent)
+    }
+
+    /// Echoes a stream from a quinn client through a relay started with `argv`, returning the
+    /// datagrams the relay sent the quinn server.
+    async fn round_trip(argv: &[&str]) -> Vec<Vec<u8>> {
+        let cert = CertificateDer::from(&include_bytes!("../testdata/example.com.crt.der")[..]);
+        let key = PrivatePkcs8KeyDer::from(&include_bytes!("../testdata/example.com.key.der")[..]);
+        let server_config =
+            ServerConfig::with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key)).unwrap();
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let (tap, sent) = tap(server.local_addr().unwrap()).await;
+        tokio::spawn(async move {
+            // Decoys start connections of their own, which fail.
+            let conn = loop {
+                if let Ok(conn) = server.accept().await.unwrap().await {
+                    break conn;
+                }
+            };
+            let (mut send, mut recv) = conn.accept_bi().await.unwrap();
+            let ping = recv.read_to_end(4).await.unwrap();
+            send.write_all(&ping).await.unwrap();
+            send.finish().unwrap();
+            conn.closed().await;
+        });
+
+        let tap = tap.to_string();
+        let mut all = vec!["--quic-listen-address", "127.0.0.1:0"];
+        all.extend(["--fwmark", "0", "--default-destination", &tap]);
+        all.extend(argv);
+        let args = crate::tests::args(&all);
+        let socket = make_relay_socket(args.quic_listen_address[0], Transparent::Never).unwrap();
+        let relay = socket.local_addr().unwrap();
+        tokio::spawn(relay_loop(socket, Arc::new(args)));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client
+            .set_default_client_config(ClientConfig::with_root_certificates(roots.into()).unwrap());
+        let pong = time::timeout(time::Duration::from_secs(5), async {
+            let conn = client.connect(relay, "example.com").unwrap().await.unwrap();
+            let (mut send, mut recv) = conn.open_bi().await.unwrap();
+            send.write_all(b"ping").await.unwrap();
+            send.finish().unwrap();
+            let pong = recv.read_to_end(4).await.unwrap();
+            conn.close(0u32.into(), b"");
+            pong
+        })
+        .await
+        .unwrap();
+        assert_eq!(pong, b"ping");
+        let sent = sent.lock().unwrap().clone();
+        sent
+    }
+
+    /// Counts the Initials sent to `dcid`, which the client only uses until the server answers.
+    fn initials_to(datagrams: &[Vec<u8>], dcid: &[u8]) -> usize {
+        datagrams
+            .iter()
+            .filter(|datagram| quic::initial_dcid(datagram) == Some(dcid))
+            .count()
+    }
+
+    #[tokio::test]
+    async fn splits_hello_across_initials() {
+        let sent = round_trip(&[]).await;
+        let dcid = quic::initial_dcid(&sent[0]).unwrap();
+        assert_eq!(initials_to(&sent, dcid), 2);
+    }
+
+    #[tokio::test]
+    async fn sends_decoy_before_split_initials() {
+        let sent = round_trip(&["--quic-fake", "--quic-split-positions", "sni+1"]).await;
+        let decoy = quic::initial_dcid(&sent[0]).unwrap();
+        let dcid = quic::initial_dcid(&sent[1]).unwrap();
+        assert_ne!(decoy, dcid);
+        assert_eq!(initials_to(&sent, decoy), 1);
+        assert_eq!(initials_to(&sent, dcid), 2);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    ffi::{CString, OsString},
+    io,
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+    ptr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use tokio::{io::unix::AsyncFd, time};
+
+use crate::{dstlist, hostlist, strategy_map, Args};
+
+/// Scripts and editors write files in steps, so wait for the rest before reloading.
+const SETTLE_DELAY: time::Duration = time::Duration::from_millis(200);
+const EVENT_HEADER_LEN: usize = size_of::<libc::inotify_event>();
+
+#[derive(Clone, Copy, PartialEq)]
+enum File {
+    Hostlist,
+    DstList,
+    StrategyMap,
+}
+
+/// A watched file: the watch on its directory, so that files replaced by renaming are seen too,
+/// and its name there.
+struct Watch {
+    wd: i32,
+    name: OsString,
+    file: File,
+}
+
+/// Reloads the --hostlist, --hostlist-exclude, --host-regex, --dst-include, --dst-exclude,
+/// --allow-from and --strategy-map files, and those of --listener-override, whenever they change,
+/// keeping the current ones if that fails.
+pub fn start(args: &Arc<Args>) -> Result<()> {
+    let files = [
+        (&args.hostlist, File::Hostlist),
+        (&args.hostlist_exclude, File::Hostlist),
+        (&args.host_regex, File::Hostlist),
+        (&args.strategy_map, File::StrategyMap),
+    ];
+    let dst_lists = [
+        (&args.dst_include, File::DstList),
+        (&args.dst_exclude, File::DstList),
+        (&args.allow_from, File::DstList),
+    ];
+    let overrides = args.listener_override.iter().flat_map(|entry| {
+        [&entry.hostlist, &entry.hostlist_exclude]
+            .into_iter()
+            .filter_map(|path| Some((path.as_ref()?.as_deref()?, File::Hostlist)))
+    });
+    let files: Vec<_> = files
+        .into_iter()
+        .filter_map(|(path, file)| Some((path.as_deref()?, file)))
+        .chain(overrides)
+        .chain(
+            dst_lists
+                .into_iter()
+                .filter_map(|(spec, file)| Some((dstlist::path(spec.as_deref()?)?, file))),
+        )
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("cannot start inotify");
+    }
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    let mut watches = Vec::new();
+    for (path, file) in files {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = path
+            .file_name()
+            .with_context(|| format!("cannot watch {}", path.display()))?;
+        let dir_name = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+        let wd = unsafe { libc::inotify_add_watch(fd.as_raw_fd(), dir_name.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("cannot watch {}", dir.display()));
+        }
+        watches.push(Watch {
+            wd,
+            name: name.to_owned(),
+            file,
+        });
+    }
+
+    let fd = AsyncFd::new(fd)?;
+    let args = Arc::clone(args);
+    tokio::spawn(async move {
+        if let Err(err) = watch(fd, &watches, &args).await {
+            eprintln!("stopped watching hostlists for changes: {err:#}");
+        }
+    });
+    Ok(())
+}
+
+async fn watch(fd: AsyncFd<OwnedFd>, watches: &[Watch], args: &Args) -> Result<()> {
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let mut changed = Vec::new();
+        let len = read_events(&fd, &mut buf).await?;
+        changed_files(&buf[..len], watches, &mut changed);
+        if changed.is_empty() {
+            continue;
+        }
+        time::sleep(SETTLE_DELAY).await;
+        while let Ok(Ok(len)) =
+            time::timeout(time::Duration::ZERO, read_events(&fd, &mut buf)).await
+        {
+            changed_files(&buf[..len], watches, &mut changed);
+        }
+
+        if changed.contains(&File::Hostlist) {
+            if let Err(err) = hostlist::reload(args) {
+                eprintln!("cannot reload hostlist: {err:#}");
+            }
+        }
+        if changed.contains(&File::DstList) {
+            if let Err(err) = dstlist::reload(args) {
+                eprintln!("cannot reload destination prefixes: {err:#}");
+            }
+        }
+        if changed.contains(&File::StrategyMap) {
+            if let Err(err) = strategy_map::reload(args) {
+                eprintln!("cannot reload strategy map: {err:#}");
+            }
+        }
+    }
+}
+
+async fn read_events(fd: &AsyncFd<OwnedFd>, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        let mut guard = fd.readable().await?;
+        let read = guard.try_io(|fd| {
+            match unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } {
+                -1 => Err(io::Error::last_os_error()),
+                len => Ok(len as usize),
+            }
+        });
+        if let Ok(read) = read {
+            return read;
+        }
+    }
+}
+
+/// Adds the watched files that `events` are about to `changed`.
+fn changed_files(events: &[u8], watches: &[Watch], changed: &mut Vec<File>) {
+    let mut pos = 0;
+    while pos + EVENT_HEADER_LEN <= events.len() {
+        let event: libc::inotify_event =
+            unsafe { ptr::read_unaligned(events[pos..].as_ptr().cast()) };
+        let name_start = pos + EVENT_HEADER_LEN;
+        let name_end = (name_start + event.len as usize).min(events.len());
+        let name = &events[name_start..name_end];
+        let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+        for watch in watches {
+            if watch.wd == event.wd && watch.name.as_bytes() == name {
+                changed.push(watch.file);
+            }
+        }
+        pos = name_end;
+    }
+}